@@ -0,0 +1,46 @@
+//! Settings of the JWT access tokens and the hook for adding application specific claims
+
+use std::time::Duration;
+
+use serde_json::{Map, Value};
+
+use crate::model::login_info::StoredLoginInfo;
+
+/// The claims set by the application, custom claims with these names are dropped
+pub const REGISTERED_CLAIMS: [&str; 9] = [
+    "sub", "roles", "sid", "act", "exp", "iat", "nbf", "iss", "aud",
+];
+
+pub struct AccessTokenConfig {
+    /// the access tokens are refreshed by the `AuthLayer` when they expire, a shorter lifetime
+    /// makes role changes and revocations effective sooner
+    pub lifetime: Duration,
+    /// the `iss` claim, tokens of other issuers are rejected
+    pub issuer: String,
+    /// the `aud` claim, tokens for other audiences are rejected
+    pub audience: String,
+    /// tolerated clock skew when checking `exp`, `nbf` and `iat`
+    pub leeway: Duration,
+}
+
+/// Lets the application put its own claims into the access tokens, e.g., a tenant id, and read
+/// them back when a token is verified. The claims end up in `LoginInfo::custom_claims`.
+pub trait ClaimsHook: Send + Sync {
+    /// Returns the claims added to the access tokens of the user
+    fn custom_claims(&self, login_info: &StoredLoginInfo) -> Map<String, Value>;
+
+    /// Checks the custom claims of a verified token, the token is rejected if an error is
+    /// returned. By default the claims are taken as they are.
+    fn read_custom_claims(&self, claims: Map<String, Value>) -> Result<Map<String, Value>, String> {
+        Ok(claims)
+    }
+}
+
+/// The default hook, the tokens carry only the registered claims
+pub struct NoCustomClaims;
+
+impl ClaimsHook for NoCustomClaims {
+    fn custom_claims(&self, _login_info: &StoredLoginInfo) -> Map<String, Value> {
+        Map::new()
+    }
+}
@@ -0,0 +1,42 @@
+//! Long-lived credentials for scripts and CI jobs, sent as `Authorization: ApiKey <key>`
+
+use axum::{
+    extract::Request,
+    http::{header::AUTHORIZATION, HeaderValue},
+};
+
+use crate::opaque_token;
+
+/// Tells API keys and JWTs apart once both are carried as bearer tokens
+pub const PREFIX: &str = "ak_";
+
+const AUTHORIZATION_SCHEME: &str = "ApiKey ";
+
+/// Generates a new key, it is shown to the user once and only its hash is stored
+pub fn generate() -> Result<String, ()> {
+    Ok(format!("{PREFIX}{}", opaque_token::generate()?))
+}
+
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(PREFIX)
+}
+
+/// Rewrites `Authorization: ApiKey <key>` to `Authorization: Bearer <key>`, so the key reaches
+/// `verify_access_token` through the same path as the access tokens. Has to run before the
+/// `AuthLayer`. Values that are not API keys are left untouched, so they are rejected as usual.
+pub async fn normalize_authorization_header(mut request: Request) -> Request {
+    let bearer = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(AUTHORIZATION_SCHEME))
+        .map(str::trim)
+        .filter(|key| is_api_key(key))
+        .and_then(|key| HeaderValue::from_str(&format!("Bearer {key}")).ok());
+
+    if let Some(bearer) = bearer {
+        request.headers_mut().insert(AUTHORIZATION, bearer);
+    }
+
+    request
+}
@@ -1,262 +1,3893 @@
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{collections::BTreeSet, net::IpAddr, sync::Arc, time::Duration};
 
 use axum::{
     async_trait,
     error_handling::HandleErrorLayer,
     extract::DefaultBodyLimit,
     http::StatusCode,
-    routing::{get, post},
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use axum_helpers::{
     app::AxumAppState,
     auth::{AccessToken, AccessTokenResponse, AuthHandler, AuthLayer, RefreshToken},
 };
+use jsonwebtoken::Validation;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 use tower::ServiceBuilder;
 use tower_http::services::ServeDir;
 use uuid::Uuid;
 
 use crate::{
-    model::login_info::{LoginInfo, StoredLoginInfo},
-    syn::{arc_rw_lock_new, ArcRwLock},
+    access_token::{self, AccessTokenConfig, ClaimsHook},
+    api_key,
+    audit::{AuditEvent, AuditEventKind, AuditLog, AuditQuery, AuditSink},
+    authorization_layer::AuthorizationLayer,
+    config::Config,
+    cors, csrf,
+    error::{BoxError, LoginError, OidcError, RefreshError, UserError},
+    health::{AuditWriterCheck, Health, MailerCheck, StoreCheck},
+    keyring::{self, Keyring, SigningKey, SigningKeyInfo},
+    login_throttle::LoginThrottle,
+    mailer::{Mail, Mailer},
+    model::{
+        api_key::StoredApiKey,
+        external_identity::StoredExternalIdentity,
+        invite::StoredInvite,
+        login_info::{validate_email, LoginInfo, LoginName, StoredLoginInfo},
+        refresh_token::StoredRefreshToken,
+        session::StoredSession,
+        totp::StoredTotp,
+    },
+    oidc::{self, AuthorizationRequest, OidcClient, OidcIdentity},
+    opaque_token, password,
+    permissions::{self, PermissionPolicy},
+    store::Store,
+    syn::{arc_mutex_new, arc_rw_lock_new, ArcMutex, ArcRwLock},
+    timestamp::{self, UnixTimestamp},
+    totp,
 };
 
-const ACCESS_TOKEN_EXPIRATION_TIME_DURATION: Duration = Duration::from_secs(60);
+const INVITE_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const API_KEY_NAME_MAX_LENGTH: usize = 64;
+/// The last use of an API key is stored at most this often, not on every request
+const API_KEY_USE_RECORDING_INTERVAL: Duration = Duration::from_secs(60);
+/// Impersonation sessions can not be refreshed, they end after this time at the latest
+const IMPERSONATION_LIFETIME: Duration = Duration::from_secs(30 * 60);
+/// Recorded as the `source` of the audit events of the user commands, they have no actor
+const COMMAND_LINE_SOURCE: &str = "cli";
+
+/// Who can create an account through `POST /api/users`
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum RegistrationMode {
+    /// anyone, the users registered without an invite get the default role
+    Open,
+    /// only the holders of an invite created by an admin
+    InviteOnly,
+    /// nobody, the users are created by the admins
+    Disabled,
+}
 
 #[derive(Clone)]
 pub struct AppState {
-    secret: Vec<u8>,
-    pub logins: ArcRwLock<BTreeMap<LoginName, StoredLoginInfo>>,
+    /// replaced as a whole when the configuration is reloaded
+    config: ArcRwLock<Arc<Config>>,
+    keyring: ArcRwLock<Keyring>,
+    claims_hook: Arc<dyn ClaimsHook>,
+    permission_policy: Arc<PermissionPolicy>,
+    login_throttle: ArcMutex<LoginThrottle>,
+    mailer: Arc<dyn Mailer>,
+    oidc: Option<Arc<OidcClient>>,
+    audit: AuditLog,
+    health: Arc<Health>,
+    pub store: Arc<dyn Store>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
-pub struct LoginName(pub String);
-
 #[derive(Debug, Serialize, Deserialize)]
 struct UserLoginClaims {
     sub: String,
-    role: String,
+    roles: BTreeSet<String>,
+    sid: Uuid,
+    /// the actor (RFC 8693) if an admin impersonates the user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    act: Option<ActorClaims>,
+    exp: usize,
+    iat: usize,
+    nbf: usize,
+    iss: String,
+    aud: String,
+    /// added by the `ClaimsHook`
+    #[serde(flatten)]
+    custom: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ActorClaims {
+    sub: String,
+}
+
+/// Single-purpose tokens signed with the internal keys of the keyring, which are never published,
+/// so the services verifying the access tokens against the JWKS can not accept them. The audience
+/// keeps them from being accepted as access tokens or for another purpose.
+#[derive(Debug, Serialize, Deserialize)]
+struct PurposeTokenClaims {
+    sub: String,
+    iss: String,
+    /// the audience of the purpose
+    aud: String,
+    /// ties the token to the state it was issued for (e.g., the current password hash), so it
+    /// stops working once it has been used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    binding: Option<String>,
     exp: usize,
 }
 
+#[derive(Debug, Clone, Copy)]
+enum TokenPurpose {
+    /// proves that the password was verified, exchanged for the tokens together with a second
+    /// factor code
+    SecondFactorChallenge,
+    PasswordReset,
+    EmailVerification,
+}
+
+impl TokenPurpose {
+    const ALL: [TokenPurpose; 3] = [
+        TokenPurpose::SecondFactorChallenge,
+        TokenPurpose::PasswordReset,
+        TokenPurpose::EmailVerification,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            TokenPurpose::SecondFactorChallenge => "second-factor",
+            TokenPurpose::PasswordReset => "password-reset",
+            TokenPurpose::EmailVerification => "email-verification",
+        }
+    }
+
+    /// Distinct from the audience of the access tokens
+    fn audience(self) -> String {
+        format!("urn:purpose:{}", self.name())
+    }
+
+    fn lifetime(self) -> Duration {
+        match self {
+            TokenPurpose::SecondFactorChallenge => Duration::from_secs(5 * 60),
+            TokenPurpose::PasswordReset => Duration::from_secs(60 * 60),
+            TokenPurpose::EmailVerification => Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// The tokens issued for a session, `access_token_response` puts them into the response
+struct SessionTokens {
+    access_token: String,
+    refresh_token: String,
+}
+
+pub enum LoginOutcome {
+    Authenticated(AccessTokenResponse),
+    SecondFactorRequired { challenge_token: String },
+}
+
+/// The components the state is built from, created from the configuration at startup
+pub struct AppStateDependencies {
+    /// the first one signs
+    pub signing_keys: Vec<SigningKey>,
+    /// adds the application specific claims to the access tokens
+    pub claims_hook: Arc<dyn ClaimsHook>,
+    pub store: Arc<dyn Store>,
+    pub permission_policy: PermissionPolicy,
+    pub mailer: Arc<dyn Mailer>,
+    pub oidc: Option<OidcClient>,
+    pub audit_sink: Arc<dyn AuditSink>,
+}
+
 impl AppState {
-    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+    pub fn new(config: &Config, dependencies: AppStateDependencies) -> Self {
+        let AppStateDependencies {
+            signing_keys,
+            claims_hook,
+            store,
+            permission_policy,
+            mailer,
+            oidc,
+            audit_sink,
+        } = dependencies;
+
+        let health = Arc::new(Health::default());
+        health.register("store", true, Arc::new(StoreCheck(store.clone())));
+        let audit = AuditLog::start(audit_sink);
+        health.register(
+            "audit_writer",
+            true,
+            Arc::new(AuditWriterCheck(audit.clone())),
+        );
+        health.register("mailer", false, Arc::new(MailerCheck(mailer.clone())));
+
         Self {
-            secret: secret.into(),
-            logins: arc_rw_lock_new(BTreeMap::new()),
+            config: arc_rw_lock_new(Arc::new(config.clone())),
+            keyring: arc_rw_lock_new(Keyring::new(
+                signing_keys,
+                signing_key_retention(&config.auth.access_token_config()),
+            )),
+            claims_hook,
+            permission_policy: Arc::new(permission_policy),
+            login_throttle: arc_mutex_new(LoginThrottle::new(
+                config.auth.login_max_failures,
+                config.auth.login_lockout(),
+            )),
+            mailer,
+            oidc: oidc.map(Arc::new),
+            audit,
+            health,
+            store,
+        }
+    }
+
+    /// Maps the roles to the permissions
+    pub fn permission_policy(&self) -> &PermissionPolicy {
+        &self.permission_policy
+    }
+
+    /// Records the audit events into the configured sink
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    /// The registered health checks and the readiness
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    /// The current configuration, it can be replaced by a reload any time
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().clone()
+    }
+
+    /// Replaces the configuration with the reloaded one and applies the changed settings, e.g.,
+    /// reads the changed signing keys. The reload is rejected if a setting only read at startup
+    /// changed or a signing key can not be read.
+    pub fn reload_config(&self, config: Config) -> Result<(), ()> {
+        let changes = self.config().changes(&config);
+        if changes.is_empty() {
+            log::info!("Configuration reloaded, nothing changed");
+            return Ok(());
+        }
+
+        let restart_required = changes
+            .iter()
+            .filter(|change| change.requires_restart())
+            .map(|change| change.field.as_str())
+            .collect::<Vec<_>>();
+        if !restart_required.is_empty() {
+            log::error!(
+                "Configuration reload rejected, the changed settings require a restart, fields = '{}'",
+                restart_required.join(", ")
+            );
+            return Err(());
+        }
+
+        // read before anything is applied, a key that can not be read rejects the reload
+        let signing_keys = if changes
+            .iter()
+            .any(|change| change.field == "auth.signing_keys")
+        {
+            Some(load_reloaded_signing_keys(&config)?)
+        } else {
+            None
+        };
+
+        // logged before the new log level applies
+        for change in &changes {
+            log::info!(
+                "Configuration changed, field = '{}', old = {}, new = {}",
+                change.field,
+                change.old,
+                change.new
+            );
+        }
+
+        log::set_max_level(config.log.level.into());
+        self.login_throttle
+            .lock()
+            .set_limits(config.auth.login_max_failures, config.auth.login_lockout());
+        {
+            let mut keyring = self.keyring.write();
+            keyring.set_token_lifetime(signing_key_retention(&config.auth.access_token_config()));
+            if let Some(signing_keys) = signing_keys {
+                let previous_kid = keyring.active_kid().to_string();
+                keyring.set_keys(signing_keys);
+                if keyring.active_kid() != previous_kid {
+                    log::info!("Signing key rotated, kid = '{}'", keyring.active_kid());
+                    self.audit.record(
+                        AuditEvent::new(AuditEventKind::SigningKeyRotated)
+                            .details(json!({ "kid": keyring.active_kid() })),
+                    );
+                }
+            }
         }
+        *self.config.write() = Arc::new(config);
+
+        self.audit.record(
+            AuditEvent::new(AuditEventKind::ConfigReloaded).details(json!({ "changes": changes })),
+        );
+
+        Ok(())
     }
 
-    pub fn login(
+    /// Creates the user, or sets the password and the roles of the existing one, e.g., of the
+    /// admin of `APP_ADMIN_PASSWORD` at every startup
+    pub async fn add_user(
         &mut self,
         loginname: impl Into<String>,
-        _password: impl Into<String>,
-    ) -> Result<AccessTokenResponse, ()> {
+        password: impl AsRef<[u8]>,
+        roles: BTreeSet<String>,
+    ) -> Result<(), ()> {
         let loginname = loginname.into();
-        let role = match loginname.as_str() {
-            "admin" => "admin",
-            _ => "regular",
-        }
-        .into();
+        let password_hash = password::hash_password(password)?;
 
-        let jwt = self.create_jwt_for_user(&loginname, &role)?;
-        let access_token_response = AccessTokenResponse::with_time_delta(
-            AccessToken::new(jwt),
-            ACCESS_TOKEN_EXPIRATION_TIME_DURATION,
-            None,
-        );
+        let existing_login_info = self
+            .store
+            .get_user(&LoginName(loginname.clone()))
+            .await
+            .inspect_err(|e| log::error!("add_user, get_user, error = {e}"))
+            .map_err(|_| ())?;
 
-        let login_info = StoredLoginInfo {
-            loginname,
-            role,
-            logged_in: true,
+        // only the credentials of an existing user are set, e.g., a disabled user stays disabled
+        let login_info = match existing_login_info {
+            Some(login_info) => StoredLoginInfo {
+                roles,
+                password_hash,
+                ..login_info
+            },
+            None => StoredLoginInfo {
+                loginname: loginname.clone(),
+                roles,
+                password_hash,
+                created_at: timestamp::now(),
+                last_login_at: None,
+                disabled: false,
+                email: None,
+                email_verified: false,
+            },
         };
 
-        self.logins
-            .write()
-            .entry(LoginName(login_info.loginname.clone()))
-            .and_modify(|login_info: &mut StoredLoginInfo| {
-                login_info.logged_in = true;
-            })
-            .or_insert_with(|| StoredLoginInfo {
-                loginname: login_info.loginname.clone(),
-                role: login_info.role.clone(),
-                logged_in: true,
-            });
+        self.store
+            .upsert_user(login_info)
+            .await
+            .inspect_err(|e| log::error!("add_user, upsert_user, error = {e}"))
+            .map_err(|_| ())?;
 
-        log::info!("User logged in, loginname = '{}'", login_info.loginname);
+        log::info!("User added, loginname = '{loginname}'");
 
-        Ok(access_token_response)
+        Ok(())
     }
 
-    pub fn logout(&mut self, login_info: &Arc<LoginInfo>) {
-        if let Some(login_info) = self
-            .logins
-            .write()
-            .get_mut(&LoginName(login_info.loginname.clone()))
-        {
-            log::info!("User logged out, loginname = '{}'", login_info.loginname);
-            login_info.logged_in = false;
+    /// Creates an account for the caller. Depending on the registration mode an invite is
+    /// required, if one is given it is consumed and its roles are assigned to the user.
+    pub async fn register_user(
+        &self,
+        loginname: impl Into<String>,
+        password: &str,
+        email: Option<String>,
+        invite_token: Option<&str>,
+    ) -> Result<StoredLoginInfo, UserError> {
+        let loginname = loginname.into();
+        Self::validate_credentials(&loginname, password)?;
+        if let Some(email) = &email {
+            validate_email(email).map_err(UserError::Invalid)?;
+        }
+
+        let registration_mode = self.config().auth.registration_mode;
+        if registration_mode == RegistrationMode::Disabled {
+            return Err(UserError::RegistrationNotAllowed);
         }
+
+        // checked before the invite is consumed, so a taken name does not burn the invite
+        if self.get_user(&loginname).await?.is_some() {
+            return Err(UserError::AlreadyExists);
+        }
+
+        let roles = match (registration_mode, invite_token) {
+            (_, Some(invite_token)) => self.use_invite(invite_token).await?.roles,
+            (RegistrationMode::Open, None) => BTreeSet::from([permissions::DEFAULT_ROLE.into()]),
+            _ => return Err(UserError::RegistrationNotAllowed),
+        };
+
+        let login_info = self.insert_user(loginname, password, roles, email).await?;
+
+        log::info!("User registered, loginname = '{}'", login_info.loginname);
+        self.audit.record(
+            AuditEvent::new(AuditEventKind::UserRegistered)
+                .loginname(&login_info.loginname)
+                .details(json!({ "roles": login_info.roles })),
+        );
+
+        self.send_email_verification(&login_info);
+
+        Ok(login_info)
     }
 
-    fn create_jwt_for_user(
+    /// Creates a user on behalf of the actor, an admin or the command line if there is none
+    pub async fn create_user(
         &self,
+        actor: Option<&str>,
         loginname: impl Into<String>,
-        role: impl Into<String>,
-    ) -> Result<String, ()> {
-        let expiration_time = std::time::SystemTime::now() + ACCESS_TOKEN_EXPIRATION_TIME_DURATION;
-        let exp = expiration_time
-            .duration_since(std::time::UNIX_EPOCH)
-            .inspect_err(|e| log::error!("create_jwt_for_user, exp calculation, error = {e}"))
-            .map_err(|_| ())?
-            .as_secs();
-
-        jsonwebtoken::encode(
-            &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS512),
-            &UserLoginClaims {
-                sub: loginname.into(),
-                role: role.into(),
-                exp: exp as usize,
-            },
-            &jsonwebtoken::EncodingKey::from_secret(&self.secret),
-        )
-        .inspect_err(|e| log::error!("create_jwt_for_user, encode, error = {e}"))
-        .map_err(|_| ())
-    }
+        password: &str,
+        roles: BTreeSet<String>,
+    ) -> Result<StoredLoginInfo, UserError> {
+        let loginname = loginname.into();
+        Self::validate_credentials(&loginname, password)?;
+        self.validate_roles(&roles)?;
 
-    fn decode_user_jwt(&self, token: &str) -> Result<UserLoginClaims, ()> {
-        jsonwebtoken::decode::<UserLoginClaims>(
-            token,
-            &jsonwebtoken::DecodingKey::from_secret(&self.secret),
-            &jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS512),
-        )
-        .map(|token_data| token_data.claims)
-        .inspect_err(|e| log::error!("decode_user_jwt, decode, error = {e}"))
-        .map_err(|_| ())
+        let login_info = self.insert_user(loginname, password, roles, None).await?;
+
+        log::info!("User created, loginname = '{}'", login_info.loginname);
+        self.record_admin_event(
+            AuditEventKind::UserCreated,
+            &login_info.loginname,
+            actor,
+            json!({ "roles": login_info.roles }),
+        );
+
+        Ok(login_info)
     }
-}
 
-#[async_trait]
-impl AuthHandler<LoginInfo> for AppState {
-    async fn verify_access_token(
-        &mut self,
-        access_token: &AccessToken,
-    ) -> Result<LoginInfo, StatusCode> {
-        let user_login_claims = self
-            .decode_user_jwt(access_token)
-            .map_err(|_| StatusCode::BAD_REQUEST)?;
+    /// Changes the given properties of the user on behalf of the actor, an admin or the command
+    /// line if there is none. Disabling the user or setting a new password ends every session of
+    /// the user.
+    pub async fn update_user(
+        &self,
+        actor: Option<&str>,
+        loginname: &str,
+        roles: Option<BTreeSet<String>>,
+        disabled: Option<bool>,
+        password: Option<&str>,
+    ) -> Result<StoredLoginInfo, UserError> {
+        // the password itself is not recorded, only that it was set
+        let details = json!({
+            "roles": roles,
+            "disabled": disabled,
+            "password_set": password.is_some(),
+        });
 
-        self.logins
-            .read()
-            .get(&LoginName(user_login_claims.sub.clone()))
-            .ok_or_else(|| StatusCode::BAD_REQUEST)
-            .and_then(|login_info| {
-                if login_info.logged_in {
-                    Ok(login_info.into())
-                } else {
-                    Err(StatusCode::BAD_REQUEST)
-                }
-            })
+        let mut login_info = self.get_user(loginname).await?.ok_or(UserError::NotFound)?;
+
+        if let Some(roles) = roles {
+            self.validate_roles(&roles)?;
+            login_info.roles = roles;
+        }
+
+        let mut end_sessions = false;
+        if let Some(disabled) = disabled {
+            end_sessions |= disabled && !login_info.disabled;
+            login_info.disabled = disabled;
+        }
+
+        if let Some(password) = password {
+            password::validate_password(password).map_err(UserError::Invalid)?;
+            login_info.password_hash =
+                password::hash_password(password).map_err(|_| UserError::Internal)?;
+            end_sessions = true;
+        }
+
+        let updated = self
+            .store
+            .update_user(login_info.clone())
+            .await
+            .inspect_err(|e| log::error!("update_user, update_user, error = {e}"))
+            .map_err(|_| UserError::Internal)?;
+        if !updated {
+            return Err(UserError::NotFound);
+        }
+
+        if end_sessions {
+            self.revoke_user_sessions(loginname, None)
+                .await
+                .map_err(|_| UserError::Internal)?;
+        }
+
+        log::info!("User updated, loginname = '{loginname}'");
+        self.record_admin_event(AuditEventKind::UserUpdated, loginname, actor, details);
+
+        Ok(login_info)
     }
 
-    async fn update_access_token(
-        &mut self,
-        _access_token: &AccessToken,
-        login_info: &Arc<LoginInfo>,
-    ) -> Option<(AccessToken, Duration)> {
-        self.logins
-            .read()
-            .get(&LoginName(login_info.loginname.clone()))
-            .and_then(|login_info| {
-                if login_info.logged_in {
-                    let access_token = self
-                        .create_jwt_for_user(&login_info.loginname, &login_info.role)
-                        .ok()?;
-                    Some((
-                        AccessToken::new(access_token),
-                        ACCESS_TOKEN_EXPIRATION_TIME_DURATION,
-                    ))
-                } else {
-                    None
-                }
-            })
+    /// Deletes the user together with its sessions and refresh tokens on behalf of the actor, an
+    /// admin or the command line if there is none. Returns false if the user does not exist.
+    pub async fn delete_user(&self, actor: Option<&str>, loginname: &str) -> Result<bool, ()> {
+        let deleted = self
+            .store
+            .delete_user(&LoginName(loginname.to_string()))
+            .await
+            .inspect_err(|e| log::error!("delete_user, delete_user, error = {e}"))
+            .map_err(|_| ())?;
+
+        if deleted {
+            log::info!("User deleted, loginname = '{loginname}'");
+            self.record_admin_event(AuditEventKind::UserDeleted, loginname, actor, Value::Null);
+        }
+
+        Ok(deleted)
     }
 
-    async fn revoke_access_token(
-        &mut self,
-        _access_token: &AccessToken,
-        login_info: &Arc<LoginInfo>,
+    /// Records a change made by an admin, or from the command line if there is no actor
+    fn record_admin_event(
+        &self,
+        kind: AuditEventKind,
+        loginname: &str,
+        actor: Option<&str>,
+        mut details: Value,
     ) {
-        self.logout(login_info);
+        let mut event = AuditEvent::new(kind).loginname(loginname);
+        match actor {
+            Some(actor) => event = event.actor(actor),
+            None => details["source"] = json!(COMMAND_LINE_SOURCE),
+        }
+
+        self.audit.record(event.details(details));
     }
 
-    async fn verify_refresh_token(
-        &mut self,
-        _refresh_token: &RefreshToken,
-    ) -> Result<(), StatusCode> {
-        Err(StatusCode::BAD_REQUEST)
+    /// Sets a new password if the current one is correct. The other sessions of the user are
+    /// ended, the session of the request stays active.
+    pub async fn change_password(
+        &self,
+        login_info: &LoginInfo,
+        current_password: &str,
+        new_password: &str,
+    ) -> Result<(), UserError> {
+        let mut stored_login_info = self
+            .get_user(&login_info.loginname)
+            .await?
+            .ok_or(UserError::NotFound)?;
+
+        if !password::verify_password(
+            current_password,
+            Some(stored_login_info.password_hash.as_str()),
+        ) {
+            log::info!(
+                "Failed password change attempt, loginname = '{}'",
+                login_info.loginname
+            );
+            return Err(UserError::WrongPassword);
+        }
+
+        password::validate_password(new_password).map_err(UserError::Invalid)?;
+        stored_login_info.password_hash =
+            password::hash_password(new_password).map_err(|_| UserError::Internal)?;
+
+        self.store
+            .update_user(stored_login_info)
+            .await
+            .inspect_err(|e| log::error!("change_password, update_user, error = {e}"))
+            .map_err(|_| UserError::Internal)?;
+
+        self.revoke_user_sessions(&login_info.loginname, Some(&login_info.session_id))
+            .await
+            .map_err(|_| UserError::Internal)?;
+
+        log::info!("Password changed, loginname = '{}'", login_info.loginname);
+        self.audit.record(
+            AuditEvent::new(AuditEventKind::PasswordChanged).loginname(&login_info.loginname),
+        );
+
+        Ok(())
     }
 
-    async fn revoke_refresh_token(&mut self, _refresh_token: &RefreshToken) {}
-}
+    /// Creates a single-use invite, returns the invite token and the invite
+    pub async fn create_invite(
+        &self,
+        created_by: impl Into<String>,
+        roles: BTreeSet<String>,
+    ) -> Result<(String, StoredInvite), UserError> {
+        self.validate_roles(&roles)?;
 
-impl AxumAppState for AppState {
-    fn routes(&self) -> Router {
-        Router::new()
-            .nest_service("/public", ServeDir::new("public"))
-            .route("/", get(crate::endpoints::index))
-            .route("/login", get(crate::endpoints::login))
-            .route("/api/login", post(crate::endpoints::api::login))
-            .route("/api/logout", post(crate::endpoints::api::logout))
-            .route(
-                "/api/seen-users",
-                get(crate::endpoints::api::get_seen_users),
-            )
-            .route(
-                "/api/seen-users/:index",
-                get(crate::endpoints::api::get_seen_user),
-            )
-            .route("/api/create-uuid-v4", get(create_uuid_v4))
-            .route(
-                "/api/echo/:this/and/:that",
-                get(crate::endpoints::api::echo_this_and_that),
-            )
-            .route("/api/echo-path", get(crate::endpoints::api::echo_path))
-            .route(
-                "/api/echo-query-params",
-                get(crate::endpoints::api::echo_query_params),
-            )
-            .route(
-                "/api/echo-parsed-query-params",
-                get(crate::endpoints::api::echo_parsed_query_params),
-            )
-            .route(
-                "/api/echo-uuid-in-path/:uuid",
-                get(crate::endpoints::api::echo_uuid_in_path),
-            )
-            .route_layer(AuthLayer::new(self.clone()))
-            // use this layer to change the body limit, the default is 2MB
-            // .layer(DefaultBodyLimit::disable())
-            .layer(DefaultBodyLimit::max(2 * 1024 * 1024))
-            .layer(
-                ServiceBuilder::new()
-                    .layer(HandleErrorLayer::new(handle_timeout_error))
-                    .timeout(Duration::from_secs(30)),
-            )
-            .with_state(self.clone())
+        let invite_token = opaque_token::generate().map_err(|_| UserError::Internal)?;
+        let now = timestamp::now();
+        let invite = StoredInvite {
+            token_hash: opaque_token::hash(&invite_token),
+            roles,
+            created_by: created_by.into(),
+            created_at: now,
+            expires_at: now + INVITE_LIFETIME.as_secs(),
+            used: false,
+        };
+
+        self.store
+            .insert_invite(invite.clone())
+            .await
+            .inspect_err(|e| log::error!("create_invite, insert_invite, error = {e}"))
+            .map_err(|_| UserError::Internal)?;
+
+        log::info!("Invite created, created_by = '{}'", invite.created_by);
+        self.audit.record(
+            AuditEvent::new(AuditEventKind::InviteCreated)
+                .actor(&invite.created_by)
+                .details(json!({ "roles": invite.roles, "expires_at": invite.expires_at })),
+        );
+
+        Ok((invite_token, invite))
     }
-}
 
-async fn handle_timeout_error(err: tower::BoxError) -> StatusCode {
-    if err.is::<tower::timeout::error::Elapsed>() {
-        StatusCode::REQUEST_TIMEOUT
-    } else {
-        StatusCode::INTERNAL_SERVER_ERROR
+    /// Creates an API key for the user. The key is scoped to the given permissions, or to every
+    /// permission of the user if none are given, and can not be scoped beyond them. Returns the
+    /// key, it is not stored and can not be shown again.
+    pub async fn create_api_key(
+        &self,
+        login_info: &LoginInfo,
+        name: String,
+        permissions: Option<BTreeSet<String>>,
+        lifetime: Option<Duration>,
+    ) -> Result<(String, StoredApiKey), UserError> {
+        let name = name.trim().to_string();
+        if name.is_empty() || name.chars().count() > API_KEY_NAME_MAX_LENGTH {
+            return Err(UserError::Invalid(format!(
+                "the name has to be 1 to {API_KEY_NAME_MAX_LENGTH} characters long"
+            )));
+        }
+
+        let permissions = permissions.unwrap_or_else(|| login_info.permissions.clone());
+        if let Some(permission) = permissions
+            .iter()
+            .find(|permission| !login_info.permissions.contains(*permission))
+        {
+            return Err(UserError::Invalid(format!(
+                "the permission '{permission}' is not granted to the user"
+            )));
+        }
+
+        let key = api_key::generate().map_err(|_| UserError::Internal)?;
+        let now = timestamp::now();
+        let stored_api_key = StoredApiKey {
+            id: Uuid::new_v4(),
+            key_hash: opaque_token::hash(&key),
+            loginname: login_info.loginname.clone(),
+            name,
+            permissions,
+            created_at: now,
+            expires_at: lifetime.map(|lifetime| now + lifetime.as_secs()),
+            last_used_at: None,
+        };
+
+        self.store
+            .insert_api_key(stored_api_key.clone())
+            .await
+            .inspect_err(|e| log::error!("create_api_key, insert_api_key, error = {e}"))
+            .map_err(|_| UserError::Internal)?;
+
+        log::info!(
+            "API key created, loginname = '{}', api_key_id = '{}'",
+            stored_api_key.loginname,
+            stored_api_key.id
+        );
+        self.audit.record(
+            AuditEvent::new(AuditEventKind::ApiKeyCreated)
+                .loginname(&stored_api_key.loginname)
+                .details(json!({
+                    "api_key_id": stored_api_key.id,
+                    "name": stored_api_key.name,
+                    "permissions": stored_api_key.permissions,
+                    "expires_at": stored_api_key.expires_at,
+                })),
+        );
+
+        Ok((key, stored_api_key))
     }
-}
 
-async fn create_uuid_v4() -> String {
-    Uuid::new_v4().as_hyphenated().to_string()
+    /// Returns false if the user has no such key
+    pub async fn revoke_api_key(&self, loginname: &str, api_key_id: &Uuid) -> Result<bool, ()> {
+        let revoked = self
+            .store
+            .delete_api_key(&LoginName(loginname.to_string()), api_key_id)
+            .await
+            .inspect_err(|e| log::error!("revoke_api_key, delete_api_key, error = {e}"))
+            .map_err(|_| ())?;
+
+        if revoked {
+            log::info!("API key revoked, loginname = '{loginname}', api_key_id = '{api_key_id}'");
+            self.audit.record(
+                AuditEvent::new(AuditEventKind::ApiKeyRevoked)
+                    .loginname(loginname)
+                    .details(json!({ "api_key_id": api_key_id })),
+            );
+        }
+
+        Ok(revoked)
+    }
+
+    /// Starts a session of the user on behalf of the admin. The access tokens of the session
+    /// carry the admin as actor, no refresh token is issued and the session ends after
+    /// `IMPERSONATION_LIFETIME` at the latest. Only users whose permissions the admin has too can
+    /// be impersonated. Returns the expiration time of the session and the access token.
+    pub async fn impersonate(
+        &self,
+        impersonator: &LoginInfo,
+        loginname: &str,
+        user_agent: Option<String>,
+    ) -> Result<(UnixTimestamp, AccessTokenResponse), UserError> {
+        let (expires_at, access_token) = self
+            .open_impersonation_session(impersonator, loginname, user_agent)
+            .await?;
+
+        Ok((
+            expires_at,
+            AccessTokenResponse::with_time_delta(
+                AccessToken::new(access_token),
+                self.access_token_config().lifetime,
+                None,
+            ),
+        ))
+    }
+
+    async fn open_impersonation_session(
+        &self,
+        impersonator: &LoginInfo,
+        loginname: &str,
+        user_agent: Option<String>,
+    ) -> Result<(UnixTimestamp, String), UserError> {
+        if impersonator.impersonator.is_some() || impersonator.api_key_id.is_some() {
+            return Err(UserError::NotAllowed(
+                "impersonation has to be started from an own session".into(),
+            ));
+        }
+        if impersonator.loginname == loginname {
+            return Err(UserError::Invalid(
+                "users can not impersonate themselves".into(),
+            ));
+        }
+
+        let login_info = self.get_user(loginname).await?.ok_or(UserError::NotFound)?;
+        if login_info.disabled {
+            return Err(UserError::NotAllowed("the user is disabled".into()));
+        }
+        // e.g., a support role with the impersonation permission must not act as a full admin
+        if !self
+            .permission_policy
+            .resolve_permissions(&login_info.roles)
+            .is_subset(&impersonator.permissions)
+        {
+            log::warn!(
+                "Impersonation rejected, the user has permissions the impersonator does not have, impersonator = '{}', loginname = '{loginname}'",
+                impersonator.loginname
+            );
+            return Err(UserError::NotAllowed(
+                "the user has permissions the impersonator does not have".into(),
+            ));
+        }
+
+        let now = timestamp::now();
+        let session = StoredSession {
+            id: Uuid::new_v4(),
+            loginname: login_info.loginname.clone(),
+            user_agent,
+            created_at: now,
+            last_refreshed_at: now,
+            expires_at: now + IMPERSONATION_LIFETIME.as_secs(),
+            revoked: false,
+            impersonator: Some(impersonator.loginname.clone()),
+        };
+
+        self.store
+            .insert_session(session.clone())
+            .await
+            .inspect_err(|e| log::error!("impersonate, insert_session, error = {e}"))
+            .map_err(|_| UserError::Internal)?;
+
+        let jwt = self
+            .create_jwt_for_user(&login_info, session.id, Some(&impersonator.loginname))
+            .map_err(|_| UserError::Internal)?;
+
+        log::warn!(
+            "Impersonation started, impersonator = '{}', loginname = '{}', session_id = '{}'",
+            impersonator.loginname,
+            login_info.loginname,
+            session.id
+        );
+        self.audit.record(
+            AuditEvent::new(AuditEventKind::ImpersonationStarted)
+                .loginname(&login_info.loginname)
+                .actor(&impersonator.loginname)
+                .details(json!({ "session_id": session.id })),
+        );
+
+        Ok((session.expires_at, jwt))
+    }
+
+    /// Creates an access token of the user for debugging, in a session that ends with the
+    /// token. Returns the token and its expiration time.
+    pub async fn mint_access_token(
+        &self,
+        loginname: &str,
+    ) -> Result<(String, UnixTimestamp), UserError> {
+        let login_info = self.get_user(loginname).await?.ok_or(UserError::NotFound)?;
+        if login_info.disabled {
+            return Err(UserError::NotAllowed("the user is disabled".into()));
+        }
+
+        let now = timestamp::now();
+        let session = StoredSession {
+            id: Uuid::new_v4(),
+            loginname: login_info.loginname.clone(),
+            user_agent: None,
+            created_at: now,
+            last_refreshed_at: now,
+            expires_at: now + self.access_token_config().lifetime.as_secs(),
+            revoked: false,
+            impersonator: None,
+        };
+
+        self.store
+            .insert_session(session.clone())
+            .await
+            .inspect_err(|e| log::error!("mint_access_token, insert_session, error = {e}"))
+            .map_err(|_| UserError::Internal)?;
+
+        let jwt = self
+            .create_jwt_for_user(&login_info, session.id, None)
+            .map_err(|_| UserError::Internal)?;
+
+        log::warn!(
+            "Access token minted, loginname = '{}', session_id = '{}'",
+            login_info.loginname,
+            session.id
+        );
+        self.audit.record(
+            AuditEvent::new(AuditEventKind::TokenMinted)
+                .loginname(&login_info.loginname)
+                .details(json!({ "session_id": session.id })),
+        );
+
+        Ok((jwt, session.expires_at))
+    }
+
+    /// Revokes the impersonation session of the request. Returns false if the request is not
+    /// impersonated.
+    pub async fn end_impersonation(&self, login_info: &LoginInfo) -> Result<bool, ()> {
+        let Some(impersonator) = &login_info.impersonator else {
+            return Ok(false);
+        };
+
+        self.revoke_session(&login_info.session_id).await?;
+
+        log::warn!(
+            "Impersonation ended, impersonator = '{impersonator}', loginname = '{}', session_id = '{}'",
+            login_info.loginname,
+            login_info.session_id
+        );
+        self.audit.record(
+            AuditEvent::new(AuditEventKind::ImpersonationEnded)
+                .loginname(&login_info.loginname)
+                .actor(impersonator)
+                .details(json!({ "session_id": login_info.session_id })),
+        );
+
+        Ok(true)
+    }
+
+    /// Returns the login info of the key if it is valid and its user is not disabled
+    async fn verify_api_key(&self, key: &str) -> Result<Option<LoginInfo>, BoxError> {
+        let now = timestamp::now();
+        let Some(stored_api_key) = self
+            .store
+            .get_api_key(&opaque_token::hash(key))
+            .await?
+            .filter(|stored_api_key| !stored_api_key.is_expired(now))
+        else {
+            return Ok(None);
+        };
+
+        let Some(stored_login_info) = self
+            .store
+            .get_user(&LoginName(stored_api_key.loginname.clone()))
+            .await?
+            .filter(|login_info| !login_info.disabled)
+        else {
+            return Ok(None);
+        };
+
+        if stored_api_key.last_used_at.is_none_or(|last_used_at| {
+            last_used_at + API_KEY_USE_RECORDING_INTERVAL.as_secs() <= now
+        }) {
+            self.store
+                .record_api_key_use(&stored_api_key.id, now)
+                .await?;
+        }
+
+        Ok(Some(LoginInfo::for_api_key(
+            &stored_login_info,
+            &stored_api_key,
+            &self.permission_policy,
+        )))
+    }
+
+    /// Sends a password reset mail if the user exists and has a verified email address. Whether
+    /// a mail was sent is not revealed to the caller.
+    pub async fn request_password_reset(&self, loginname: &str) -> Result<(), ()> {
+        let Some(login_info) = self.get_user(loginname).await.map_err(|_| ())? else {
+            log::info!("Password reset requested for unknown user, loginname = '{loginname}'");
+            return Ok(());
+        };
+
+        let email = match &login_info.email {
+            Some(email) if login_info.email_verified && !login_info.disabled => email.clone(),
+            _ => {
+                log::info!(
+                    "Password reset requested for user without verified email, loginname = '{loginname}'"
+                );
+                return Ok(());
+            }
+        };
+
+        let token = self.create_purpose_token(
+            TokenPurpose::PasswordReset,
+            loginname,
+            Some(opaque_token::hash(&login_info.password_hash)),
+        )?;
+
+        self.send_mail_in_background(Mail {
+            to: email,
+            subject: "Password reset".into(),
+            body: format!(
+                "Hello {loginname},\n\n\
+                a password reset was requested for your account. To set a new password, send the \
+                following token to POST /api/password-reset/confirm within {} minutes:\n\n{token}\n\n\
+                If you did not request it, you can ignore this mail.",
+                TokenPurpose::PasswordReset.lifetime().as_secs() / 60
+            ),
+        });
+
+        log::info!("Password reset requested, loginname = '{loginname}'");
+
+        Ok(())
+    }
+
+    /// Sets the new password if the reset token is valid. The token is bound to the password it
+    /// was issued for, so it can be used only once. Every session of the user is ended.
+    pub async fn reset_password(&self, token: &str, new_password: &str) -> Result<(), UserError> {
+        let (loginname, binding) = self
+            .decode_purpose_token(TokenPurpose::PasswordReset, token)
+            .map_err(|_| UserError::InvalidToken)?;
+
+        let mut login_info = self
+            .get_user(&loginname)
+            .await?
+            .filter(|login_info| {
+                binding.as_deref() == Some(opaque_token::hash(&login_info.password_hash).as_str())
+            })
+            .ok_or(UserError::InvalidToken)?;
+
+        password::validate_password(new_password).map_err(UserError::Invalid)?;
+        login_info.password_hash =
+            password::hash_password(new_password).map_err(|_| UserError::Internal)?;
+
+        self.store
+            .update_user(login_info)
+            .await
+            .inspect_err(|e| log::error!("reset_password, update_user, error = {e}"))
+            .map_err(|_| UserError::Internal)?;
+
+        self.revoke_user_sessions(&loginname, None)
+            .await
+            .map_err(|_| UserError::Internal)?;
+        self.login_throttle.lock().unlock(&loginname);
+
+        log::info!("Password reset, loginname = '{loginname}'");
+        self.audit
+            .record(AuditEvent::new(AuditEventKind::PasswordReset).loginname(&loginname));
+
+        Ok(())
+    }
+
+    /// Replaces the email address of the user and sends a verification mail to the new address.
+    /// The password reset mails go to this address, so the current password is required, a
+    /// stolen access token alone must not be enough to take over the account. The previous
+    /// address is notified if it was verified.
+    pub async fn set_email(
+        &self,
+        loginname: &str,
+        current_password: &str,
+        email: String,
+    ) -> Result<(), UserError> {
+        validate_email(&email).map_err(UserError::Invalid)?;
+
+        let mut login_info = self.get_user(loginname).await?.ok_or(UserError::NotFound)?;
+
+        if !password::verify_password(current_password, Some(login_info.password_hash.as_str())) {
+            log::info!("Failed email address change attempt, loginname = '{loginname}'");
+            return Err(UserError::WrongPassword);
+        }
+
+        let previous_email = login_info
+            .email
+            .take()
+            .filter(|_| login_info.email_verified);
+        login_info.email = Some(email);
+        login_info.email_verified = false;
+
+        self.store
+            .update_user(login_info.clone())
+            .await
+            .inspect_err(|e| log::error!("set_email, update_user, error = {e}"))
+            .map_err(|_| UserError::Internal)?;
+
+        log::info!("Email address changed, loginname = '{loginname}'");
+        self.audit
+            .record(AuditEvent::new(AuditEventKind::EmailChanged).loginname(loginname));
+
+        if let Some(previous_email) = previous_email {
+            self.send_mail_in_background(Mail {
+                to: previous_email,
+                subject: "Your email address was changed".into(),
+                body: format!(
+                    "Hello {loginname},\n\n\
+                    the email address of your account was changed, the password reset mails are \
+                    sent to the new address once it is verified. If you did not change it, reset \
+                    your password and contact the administrators."
+                ),
+            });
+        }
+
+        self.send_email_verification(&login_info);
+
+        Ok(())
+    }
+
+    /// Sends the verification mail again, e.g., if the previous token expired
+    pub async fn resend_email_verification(&self, loginname: &str) -> Result<(), UserError> {
+        let login_info = self.get_user(loginname).await?.ok_or(UserError::NotFound)?;
+
+        if login_info.email.is_none() || login_info.email_verified {
+            return Err(UserError::Invalid(
+                "there is no unverified email address".into(),
+            ));
+        }
+
+        self.send_email_verification(&login_info);
+
+        Ok(())
+    }
+
+    /// Marks the email address of the token as verified. The token is bound to the address, it
+    /// is rejected if the address was changed or is already verified.
+    pub async fn verify_email(&self, token: &str) -> Result<(), UserError> {
+        let (loginname, binding) = self
+            .decode_purpose_token(TokenPurpose::EmailVerification, token)
+            .map_err(|_| UserError::InvalidToken)?;
+
+        let mut login_info = self
+            .get_user(&loginname)
+            .await?
+            .filter(|login_info| !login_info.email_verified && login_info.email == binding)
+            .ok_or(UserError::InvalidToken)?;
+        login_info.email_verified = true;
+
+        self.store
+            .update_user(login_info)
+            .await
+            .inspect_err(|e| log::error!("verify_email, update_user, error = {e}"))
+            .map_err(|_| UserError::Internal)?;
+
+        log::info!("Email address verified, loginname = '{loginname}'");
+
+        Ok(())
+    }
+
+    fn send_email_verification(&self, login_info: &StoredLoginInfo) {
+        let Some(email) = &login_info.email else {
+            return;
+        };
+
+        let Ok(token) = self.create_purpose_token(
+            TokenPurpose::EmailVerification,
+            &login_info.loginname,
+            Some(email.clone()),
+        ) else {
+            return;
+        };
+
+        self.send_mail_in_background(Mail {
+            to: email.clone(),
+            subject: "Verify your email address".into(),
+            body: format!(
+                "Hello {},\n\n\
+                to verify your email address, send the following token to \
+                POST /api/email-verification/confirm within {} hours:\n\n{token}",
+                login_info.loginname,
+                TokenPurpose::EmailVerification.lifetime().as_secs() / 60 / 60
+            ),
+        });
+    }
+
+    /// The mail is sent in a separate task, so the response time does not reveal whether a mail
+    /// was sent
+    fn send_mail_in_background(&self, mail: Mail) {
+        let mailer = self.mailer.clone();
+        tokio::spawn(async move {
+            let to = mail.to.clone();
+            let _ = mailer
+                .send(mail)
+                .await
+                .inspect_err(|e| log::error!("send_mail, to = '{to}', error = {e}"));
+        });
+    }
+
+    fn validate_credentials(loginname: &str, password: &str) -> Result<(), UserError> {
+        LoginName::validate(loginname).map_err(UserError::Invalid)?;
+        password::validate_password(password).map_err(UserError::Invalid)
+    }
+
+    fn validate_roles(&self, roles: &BTreeSet<String>) -> Result<(), UserError> {
+        match roles
+            .iter()
+            .find(|role| !self.permission_policy.contains_role(role))
+        {
+            Some(unknown_role) => Err(UserError::Invalid(format!("unknown role '{unknown_role}'"))),
+            None => Ok(()),
+        }
+    }
+
+    async fn get_user(&self, loginname: &str) -> Result<Option<StoredLoginInfo>, UserError> {
+        self.store
+            .get_user(&LoginName(loginname.to_string()))
+            .await
+            .inspect_err(|e| log::error!("get_user, get_user, error = {e}"))
+            .map_err(|_| UserError::Internal)
+    }
+
+    async fn insert_user(
+        &self,
+        loginname: String,
+        password: &str,
+        roles: BTreeSet<String>,
+        email: Option<String>,
+    ) -> Result<StoredLoginInfo, UserError> {
+        let login_info = StoredLoginInfo {
+            loginname,
+            roles,
+            password_hash: password::hash_password(password).map_err(|_| UserError::Internal)?,
+            created_at: timestamp::now(),
+            last_login_at: None,
+            disabled: false,
+            email,
+            email_verified: false,
+        };
+
+        let inserted = self
+            .store
+            .insert_user(login_info.clone())
+            .await
+            .inspect_err(|e| log::error!("insert_user, insert_user, error = {e}"))
+            .map_err(|_| UserError::Internal)?;
+
+        if inserted {
+            Ok(login_info)
+        } else {
+            Err(UserError::AlreadyExists)
+        }
+    }
+
+    async fn use_invite(&self, invite_token: &str) -> Result<StoredInvite, UserError> {
+        self.store
+            .use_invite(&opaque_token::hash(invite_token))
+            .await
+            .inspect_err(|e| log::error!("use_invite, use_invite, error = {e}"))
+            .map_err(|_| UserError::Internal)?
+            .filter(|invite| !invite.used && invite.expires_at > timestamp::now())
+            .ok_or(UserError::RegistrationNotAllowed)
+    }
+
+    /// Revokes every active session of the user except the given one
+    async fn revoke_user_sessions(&self, loginname: &str, except: Option<&Uuid>) -> Result<(), ()> {
+        let sessions = self
+            .store
+            .list_sessions(&LoginName(loginname.to_string()))
+            .await
+            .inspect_err(|e| log::error!("revoke_user_sessions, list_sessions, error = {e}"))
+            .map_err(|_| ())?;
+
+        for session in sessions
+            .iter()
+            .filter(|session| session.is_active() && Some(&session.id) != except)
+        {
+            self.revoke_session(&session.id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the password. If the user has a second factor, the login has to be completed with
+    /// `complete_second_factor` using the returned challenge token.
+    pub async fn login(
+        &mut self,
+        loginname: impl Into<String>,
+        password: impl AsRef<[u8]>,
+        user_agent: Option<String>,
+        ip: Option<IpAddr>,
+    ) -> Result<LoginOutcome, LoginError> {
+        let loginname = loginname.into();
+
+        self.check_login_throttle(&loginname, ip)?;
+
+        let stored_login_info = self
+            .store
+            .get_user(&LoginName(loginname.clone()))
+            .await
+            .inspect_err(|e| log::error!("login, get_user, error = {e}"))
+            .map_err(|_| LoginError::Internal)?;
+
+        if !password::verify_password(
+            password,
+            stored_login_info
+                .as_ref()
+                .map(|login_info| login_info.password_hash.as_str()),
+        ) {
+            log::info!("Failed login attempt, loginname = '{loginname}'");
+            self.record_login_failure(&loginname, ip, "invalid_credentials");
+            self.login_throttle.lock().record_failure(&loginname, ip);
+            return Err(LoginError::InvalidCredentials);
+        }
+
+        let stored_login_info = stored_login_info.ok_or(LoginError::InvalidCredentials)?;
+
+        if stored_login_info.disabled {
+            log::info!("Login attempt of disabled user, loginname = '{loginname}'");
+            self.record_login_failure(&loginname, ip, "disabled");
+            return Err(LoginError::Disabled);
+        }
+
+        let totp = self
+            .store
+            .get_totp(&LoginName(loginname.clone()))
+            .await
+            .inspect_err(|e| log::error!("login, get_totp, error = {e}"))
+            .map_err(|_| LoginError::Internal)?;
+
+        if totp.is_some_and(|totp| totp.confirmed) {
+            // the failure counter is only reset when the second factor is verified too,
+            // otherwise the password would allow unlimited guesses of the code
+            let challenge_token = self
+                .create_purpose_token(TokenPurpose::SecondFactorChallenge, &loginname, None)
+                .map_err(|_| LoginError::Internal)?;
+
+            log::info!("Second factor required, loginname = '{loginname}'");
+
+            return Ok(LoginOutcome::SecondFactorRequired { challenge_token });
+        }
+
+        self.login_throttle.lock().record_success(&loginname);
+
+        self.start_session(&stored_login_info, user_agent, ip)
+            .await
+            .map(LoginOutcome::Authenticated)
+    }
+
+    /// Second step of the login of users with TOTP. The code can be a TOTP code or a recovery
+    /// code, recovery codes can be used only once. Returns the loginname and the tokens.
+    pub async fn complete_second_factor(
+        &mut self,
+        challenge_token: &str,
+        code: &str,
+        user_agent: Option<String>,
+        ip: Option<IpAddr>,
+    ) -> Result<(String, AccessTokenResponse), LoginError> {
+        let (loginname, _) = self
+            .decode_purpose_token(TokenPurpose::SecondFactorChallenge, challenge_token)
+            .map_err(|_| LoginError::InvalidCredentials)?;
+
+        self.check_login_throttle(&loginname, ip)?;
+
+        let stored_login_info = self
+            .store
+            .get_user(&LoginName(loginname.clone()))
+            .await
+            .inspect_err(|e| log::error!("complete_second_factor, get_user, error = {e}"))
+            .map_err(|_| LoginError::Internal)?
+            .ok_or(LoginError::InvalidCredentials)?;
+
+        if stored_login_info.disabled {
+            log::info!("Login attempt of disabled user, loginname = '{loginname}'");
+            self.record_login_failure(&loginname, ip, "disabled");
+            return Err(LoginError::Disabled);
+        }
+
+        if !self
+            .verify_second_factor(&loginname, code)
+            .await
+            .map_err(|_| LoginError::Internal)?
+        {
+            log::info!("Failed second factor attempt, loginname = '{loginname}'");
+            self.record_login_failure(&loginname, ip, "invalid_second_factor");
+            self.login_throttle.lock().record_failure(&loginname, ip);
+            return Err(LoginError::InvalidCredentials);
+        }
+
+        self.login_throttle.lock().record_success(&loginname);
+
+        let access_token_response = self
+            .start_session(&stored_login_info, user_agent, ip)
+            .await?;
+
+        Ok((loginname, access_token_response))
+    }
+
+    fn check_login_throttle(&self, loginname: &str, ip: Option<IpAddr>) -> Result<(), LoginError> {
+        self.login_throttle
+            .lock()
+            .check(loginname, ip)
+            .inspect_err(|retry_after| {
+                self.audit.record(
+                    AuditEvent::new(AuditEventKind::LoginThrottled)
+                        .loginname(loginname)
+                        .ip(ip)
+                        .details(json!({ "retry_after_ms": retry_after.as_millis() as u64 })),
+                )
+            })
+            .map_err(LoginError::Throttled)
+    }
+
+    async fn start_session(
+        &self,
+        stored_login_info: &StoredLoginInfo,
+        user_agent: Option<String>,
+        ip: Option<IpAddr>,
+    ) -> Result<AccessTokenResponse, LoginError> {
+        self.open_session(stored_login_info, user_agent, ip)
+            .await
+            .map(|session_tokens| self.access_token_response(session_tokens))
+    }
+
+    async fn open_session(
+        &self,
+        stored_login_info: &StoredLoginInfo,
+        user_agent: Option<String>,
+        ip: Option<IpAddr>,
+    ) -> Result<SessionTokens, LoginError> {
+        let loginname = &stored_login_info.loginname;
+
+        let now = timestamp::now();
+        let session = StoredSession {
+            id: Uuid::new_v4(),
+            loginname: loginname.clone(),
+            user_agent,
+            created_at: now,
+            last_refreshed_at: now,
+            expires_at: now + self.refresh_token_lifetime().as_secs(),
+            revoked: false,
+            impersonator: None,
+        };
+
+        self.store
+            .insert_session(session.clone())
+            .await
+            .inspect_err(|e| log::error!("start_session, insert_session, error = {e}"))
+            .map_err(|_| LoginError::Internal)?;
+
+        let session_tokens = self
+            .issue_session_tokens(stored_login_info, session.id)
+            .await
+            .map_err(|_| LoginError::Internal)?;
+
+        self.store
+            .record_login(&LoginName(loginname.clone()), timestamp::now())
+            .await
+            .inspect_err(|e| log::error!("start_session, record_login, error = {e}"))
+            .map_err(|_| LoginError::Internal)?;
+
+        log::info!(
+            "User logged in, loginname = '{loginname}', session_id = '{}'",
+            session.id
+        );
+        self.audit.record(
+            AuditEvent::new(AuditEventKind::LoginSucceeded)
+                .loginname(loginname)
+                .ip(ip)
+                .details(json!({ "session_id": session.id })),
+        );
+
+        Ok(session_tokens)
+    }
+
+    /// Returns the url of the OpenID Connect provider the user has to be redirected to and the
+    /// state the callback has to come back with
+    pub async fn oidc_authorization_request(&self) -> Result<AuthorizationRequest, OidcError> {
+        self.oidc
+            .as_ref()
+            .ok_or(OidcError::NotConfigured)?
+            .authorization_request()
+            .await
+    }
+
+    /// Completes the login through the OpenID Connect provider. At the first login of an
+    /// identity a user is created for it, the roles (if managed by the provider) and the verified
+    /// email address are synchronized at every login. The second factor is left to the provider.
+    /// Returns the loginname and the tokens.
+    pub async fn oidc_login(
+        &self,
+        code: &str,
+        state: &str,
+        user_agent: Option<String>,
+        ip: Option<IpAddr>,
+    ) -> Result<(String, AccessTokenResponse), OidcError> {
+        let identity = self
+            .oidc
+            .as_ref()
+            .ok_or(OidcError::NotConfigured)?
+            .complete_authorization(code, state)
+            .await?;
+
+        let external_identity = self
+            .store
+            .get_external_identity(&identity.issuer, &identity.subject)
+            .await
+            .inspect_err(|e| log::error!("oidc_login, get_external_identity, error = {e}"))
+            .map_err(|_| OidcError::Internal)?;
+
+        let mut stored_login_info = match external_identity {
+            Some(external_identity) => self
+                .get_user(&external_identity.loginname)
+                .await
+                .map_err(|_| OidcError::Internal)?
+                .ok_or(OidcError::Internal)?,
+            None => self.create_oidc_user(&identity).await?,
+        };
+        let loginname = stored_login_info.loginname.clone();
+
+        if stored_login_info.disabled {
+            log::info!("Login attempt of disabled user, loginname = '{loginname}'");
+            self.record_login_failure(&loginname, ip, "disabled");
+            return Err(OidcError::Disabled);
+        }
+
+        let mut changed = false;
+        if let Some(roles) = &identity.roles {
+            if *roles != stored_login_info.roles {
+                log::info!(
+                    "Roles synchronized from the OIDC provider, loginname = '{loginname}', roles = {roles:?}"
+                );
+                stored_login_info.roles = roles.clone();
+                changed = true;
+            }
+        }
+        if let Some(email) = verified_email(&identity) {
+            if stored_login_info.email.as_ref() != Some(&email) || !stored_login_info.email_verified
+            {
+                stored_login_info.email = Some(email);
+                stored_login_info.email_verified = true;
+                changed = true;
+            }
+        }
+        if changed {
+            self.store
+                .update_user(stored_login_info.clone())
+                .await
+                .inspect_err(|e| log::error!("oidc_login, update_user, error = {e}"))
+                .map_err(|_| OidcError::Internal)?;
+        }
+
+        let access_token_response = self
+            .start_session(&stored_login_info, user_agent, ip)
+            .await
+            .map_err(|_| OidcError::Internal)?;
+
+        Ok((loginname, access_token_response))
+    }
+
+    pub fn remove_expired_oidc_authorizations(&self) {
+        if let Some(oidc) = &self.oidc {
+            oidc.remove_expired_authorizations();
+        }
+    }
+
+    /// Creates the user of an identity that logs in the first time and links the identity to it.
+    /// The user gets an unusable random password, a password can be set with a password reset.
+    async fn create_oidc_user(
+        &self,
+        identity: &OidcIdentity,
+    ) -> Result<StoredLoginInfo, OidcError> {
+        let loginname = identity
+            .username
+            .as_deref()
+            .and_then(oidc::loginname_from_username)
+            .ok_or_else(|| {
+                OidcError::UserConflict("the provider did not assert a valid username".to_string())
+            })?;
+
+        let roles = identity
+            .roles
+            .clone()
+            .unwrap_or_else(|| BTreeSet::from([permissions::DEFAULT_ROLE.to_string()]));
+        let password = opaque_token::generate().map_err(|_| OidcError::Internal)?;
+
+        let login_info = self
+            .insert_user(loginname.clone(), &password, roles, None)
+            .await
+            .map_err(|e| match e {
+                // linking an existing local user would let the provider take over its account
+                UserError::AlreadyExists => {
+                    log::info!(
+                        "OIDC login rejected, the username is taken by a local user, loginname = '{loginname}', subject = '{}'",
+                        identity.subject
+                    );
+                    OidcError::UserConflict(format!("the user '{loginname}' already exists"))
+                }
+                _ => OidcError::Internal,
+            })?;
+
+        let inserted = self
+            .store
+            .insert_external_identity(StoredExternalIdentity {
+                issuer: identity.issuer.clone(),
+                subject: identity.subject.clone(),
+                loginname: loginname.clone(),
+                created_at: timestamp::now(),
+            })
+            .await
+            .inspect_err(|e| log::error!("create_oidc_user, insert_external_identity, error = {e}"))
+            .map_err(|_| OidcError::Internal)?;
+        if !inserted {
+            // a concurrent login of the same identity was faster
+            self.store
+                .delete_user(&LoginName(loginname.clone()))
+                .await
+                .inspect_err(|e| log::error!("create_oidc_user, delete_user, error = {e}"))
+                .map_err(|_| OidcError::Internal)?;
+            return Err(OidcError::Internal);
+        }
+
+        log::info!(
+            "User created for OIDC identity, loginname = '{loginname}', issuer = '{}', subject = '{}'",
+            identity.issuer,
+            identity.subject
+        );
+
+        Ok(login_info)
+    }
+
+    /// Returns true if the code is a valid TOTP code of an unused time step or an unused recovery
+    /// code of the user
+    async fn verify_second_factor(&self, loginname: &str, code: &str) -> Result<bool, ()> {
+        let loginname = LoginName(loginname.to_string());
+
+        let Some(totp) = self
+            .store
+            .get_totp(&loginname)
+            .await
+            .inspect_err(|e| log::error!("verify_second_factor, get_totp, error = {e}"))
+            .map_err(|_| ())?
+        else {
+            return Ok(false);
+        };
+
+        if let Some(step) = totp::verify(&totp.secret, code, timestamp::now()) {
+            return self
+                .store
+                .use_totp_step(&loginname, step)
+                .await
+                .inspect_err(|e| log::error!("verify_second_factor, use_totp_step, error = {e}"))
+                .map_err(|_| ());
+        }
+
+        if !totp.confirmed {
+            return Ok(false);
+        }
+
+        let used_recovery_code = self
+            .store
+            .use_recovery_code(&loginname, &totp::hash_recovery_code(code))
+            .await
+            .inspect_err(|e| log::error!("verify_second_factor, use_recovery_code, error = {e}"))
+            .map_err(|_| ())?;
+        if used_recovery_code {
+            log::warn!("Recovery code used, loginname = '{}'", loginname.0);
+        }
+
+        Ok(used_recovery_code)
+    }
+
+    /// Generates a new TOTP secret for the user, it has to be confirmed with a valid code before
+    /// it is required at login. Returns the secret and the otpauth URI.
+    pub async fn enroll_totp(&self, loginname: &str) -> Result<(String, String), UserError> {
+        let existing_totp = self
+            .store
+            .get_totp(&LoginName(loginname.to_string()))
+            .await
+            .inspect_err(|e| log::error!("enroll_totp, get_totp, error = {e}"))
+            .map_err(|_| UserError::Internal)?;
+        if existing_totp.is_some_and(|totp| totp.confirmed) {
+            return Err(UserError::AlreadyExists);
+        }
+
+        let secret = totp::generate_secret().map_err(|_| UserError::Internal)?;
+        self.store
+            .upsert_totp(StoredTotp {
+                loginname: loginname.to_string(),
+                secret: secret.clone(),
+                confirmed: false,
+                last_used_step: None,
+                created_at: timestamp::now(),
+            })
+            .await
+            .inspect_err(|e| log::error!("enroll_totp, upsert_totp, error = {e}"))
+            .map_err(|_| UserError::Internal)?;
+
+        log::info!("TOTP enrollment started, loginname = '{loginname}'");
+
+        let otpauth_uri = totp::otpauth_uri(loginname, &secret);
+
+        Ok((secret, otpauth_uri))
+    }
+
+    /// Activates the enrolled TOTP if the code is valid. Returns the recovery codes, they are not
+    /// retrievable later.
+    pub async fn confirm_totp(
+        &self,
+        loginname: &str,
+        code: &str,
+    ) -> Result<Vec<String>, UserError> {
+        let totp = self
+            .store
+            .get_totp(&LoginName(loginname.to_string()))
+            .await
+            .inspect_err(|e| log::error!("confirm_totp, get_totp, error = {e}"))
+            .map_err(|_| UserError::Internal)?
+            .ok_or(UserError::NotFound)?;
+        if totp.confirmed {
+            return Err(UserError::AlreadyExists);
+        }
+
+        if !self
+            .verify_second_factor(loginname, code)
+            .await
+            .map_err(|_| UserError::Internal)?
+        {
+            return Err(UserError::Invalid("invalid code".into()));
+        }
+
+        let recovery_codes = totp::generate_recovery_codes().map_err(|_| UserError::Internal)?;
+        self.store
+            .confirm_totp(
+                &LoginName(loginname.to_string()),
+                recovery_codes
+                    .iter()
+                    .map(|code| totp::hash_recovery_code(code))
+                    .collect(),
+            )
+            .await
+            .inspect_err(|e| log::error!("confirm_totp, confirm_totp, error = {e}"))
+            .map_err(|_| UserError::Internal)?;
+
+        log::info!("TOTP enabled, loginname = '{loginname}'");
+        self.audit
+            .record(AuditEvent::new(AuditEventKind::TotpEnabled).loginname(loginname));
+
+        Ok(recovery_codes)
+    }
+
+    /// Removes the second factor of the user. Returns false if the user had none.
+    async fn delete_totp(&self, loginname: &str) -> Result<bool, ()> {
+        let deleted = self
+            .store
+            .delete_totp(&LoginName(loginname.to_string()))
+            .await
+            .inspect_err(|e| log::error!("delete_totp, delete_totp, error = {e}"))
+            .map_err(|_| ())?;
+
+        if deleted {
+            log::info!("TOTP disabled, loginname = '{loginname}'");
+        }
+
+        Ok(deleted)
+    }
+
+    /// Removes the second factor of the user on behalf of an admin, e.g., when the user lost the
+    /// device. Returns false if the user has no second factor.
+    pub async fn reset_totp(&self, actor: &str, loginname: &str) -> Result<bool, ()> {
+        let deleted = self.delete_totp(loginname).await?;
+        if deleted {
+            self.record_admin_event(
+                AuditEventKind::TotpReset,
+                loginname,
+                Some(actor),
+                Value::Null,
+            );
+        }
+
+        Ok(deleted)
+    }
+
+    /// Removes the second factor of the user if the password is correct
+    pub async fn disable_totp(&self, loginname: &str, password: &str) -> Result<(), UserError> {
+        let stored_login_info = self.get_user(loginname).await?.ok_or(UserError::NotFound)?;
+
+        if !password::verify_password(password, Some(stored_login_info.password_hash.as_str())) {
+            log::info!("Failed TOTP disable attempt, loginname = '{loginname}'");
+            return Err(UserError::WrongPassword);
+        }
+
+        match self.delete_totp(loginname).await {
+            Ok(true) => {
+                self.audit
+                    .record(AuditEvent::new(AuditEventKind::TotpDisabled).loginname(loginname));
+                Ok(())
+            }
+            Ok(false) => Err(UserError::NotFound),
+            Err(_) => Err(UserError::Internal),
+        }
+    }
+
+    pub async fn logout(&mut self, login_info: &Arc<LoginInfo>) {
+        if let Ok(true) = self.revoke_session(&login_info.session_id).await {
+            log::info!(
+                "User logged out, loginname = '{}', session_id = '{}'",
+                login_info.loginname,
+                login_info.session_id
+            );
+            self.audit.record(
+                AuditEvent::new(AuditEventKind::Logout)
+                    .loginname(&login_info.loginname)
+                    .details(json!({ "session_id": login_info.session_id })),
+            );
+        }
+    }
+
+    /// Lifts the lockout and the backoff of the account. Returns false if the account was not
+    /// throttled.
+    /// Lifts the login throttling of the user, returns false if the user is not throttled
+    pub fn unlock_login(&self, actor: &str, loginname: &str) -> bool {
+        let unlocked = self.login_throttle.lock().unlock(loginname);
+        if unlocked {
+            log::info!("Account unlocked, loginname = '{loginname}', actor = '{actor}'");
+            self.record_admin_event(
+                AuditEventKind::LoginUnlocked,
+                loginname,
+                Some(actor),
+                Value::Null,
+            );
+        }
+
+        unlocked
+    }
+
+    pub fn remove_stale_login_throttle_counters(&self) {
+        self.login_throttle.lock().remove_stale_counters();
+    }
+
+    /// Ends the session, its access tokens and refresh tokens are not accepted anymore. Returns
+    /// false if the session does not exist.
+    pub async fn revoke_session(&self, session_id: &Uuid) -> Result<bool, ()> {
+        let revoked = self
+            .store
+            .revoke_session(session_id)
+            .await
+            .inspect_err(|e| log::error!("revoke_session, revoke_session, error = {e}"))
+            .map_err(|_| ())?;
+
+        self.store
+            .delete_refresh_token_family(session_id)
+            .await
+            .inspect_err(|e| {
+                log::error!("revoke_session, delete_refresh_token_family, error = {e}")
+            })
+            .map_err(|_| ())?;
+
+        Ok(revoked)
+    }
+
+    /// Returns the session and its user if the session is active and belongs to the user, and
+    /// the user is not disabled
+    async fn get_session_user(
+        &self,
+        session_id: &Uuid,
+        loginname: &str,
+    ) -> Result<Option<(StoredSession, StoredLoginInfo)>, BoxError> {
+        let session = self
+            .store
+            .get_session(session_id)
+            .await?
+            .filter(|session| session.is_active() && session.loginname == loginname);
+
+        match session {
+            Some(session) => Ok(self
+                .store
+                .get_user(&LoginName(session.loginname.clone()))
+                .await?
+                .filter(|login_info| !login_info.disabled)
+                .map(|login_info| (session, login_info))),
+            None => Ok(None),
+        }
+    }
+
+    fn record_login_failure(&self, loginname: &str, ip: Option<IpAddr>, reason: &str) {
+        self.audit.record(
+            AuditEvent::new(AuditEventKind::LoginFailed)
+                .loginname(loginname)
+                .ip(ip)
+                .details(json!({ "reason": reason })),
+        );
+    }
+
+    /// Returns the matching audit events, the newest first
+    pub async fn audit_events(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>, ()> {
+        self.audit
+            .query(query)
+            .await
+            .inspect_err(|e| log::error!("audit_events, query, error = {e}"))
+            .map_err(|_| ())
+    }
+
+    pub fn signing_keys(&self) -> Vec<SigningKeyInfo> {
+        self.keyring.read().key_infos()
+    }
+
+    pub fn public_jwks(&self) -> Vec<serde_json::Value> {
+        self.keyring.read().public_jwks()
+    }
+
+    /// Removes the retired keys that are not needed for verification anymore
+    pub fn remove_expired_signing_keys(&self) {
+        self.keyring.write().remove_expired_keys();
+    }
+
+    /// Rotates the refresh token: the given token is consumed and a new access token and refresh
+    /// token are issued. Presenting an already used token revokes its whole family and its
+    /// session, since that means the token was stolen.
+    pub async fn refresh(
+        &mut self,
+        refresh_token: &str,
+    ) -> Result<(String, AccessTokenResponse), RefreshError> {
+        self.rotate_refresh_token(refresh_token)
+            .await
+            .map(|(loginname, session_tokens)| {
+                (loginname, self.access_token_response(session_tokens))
+            })
+    }
+
+    async fn rotate_refresh_token(
+        &mut self,
+        refresh_token: &str,
+    ) -> Result<(String, SessionTokens), RefreshError> {
+        let refresh_token = self
+            .store
+            .use_refresh_token(&opaque_token::hash(refresh_token))
+            .await
+            .inspect_err(|e| log::error!("refresh, use_refresh_token, error = {e}"))
+            .map_err(|_| RefreshError::Internal)?
+            .ok_or(RefreshError::InvalidToken)?;
+
+        if refresh_token.used {
+            log::warn!(
+                "Refresh token reused, revoking its session, loginname = '{}', family_id = '{}'",
+                refresh_token.loginname,
+                refresh_token.family_id
+            );
+
+            // the thief or the user holds a token of the session, so the session is revoked too
+            self.revoke_session(&refresh_token.family_id)
+                .await
+                .map_err(|_| RefreshError::Internal)?;
+            self.audit.record(
+                AuditEvent::new(AuditEventKind::RefreshTokenReused)
+                    .loginname(&refresh_token.loginname)
+                    .details(json!({ "session_id": refresh_token.family_id })),
+            );
+
+            return Err(RefreshError::InvalidToken);
+        }
+
+        if refresh_token.expires_at <= timestamp::now() {
+            return Err(RefreshError::InvalidToken);
+        }
+
+        let (_, login_info) = self
+            .get_session_user(&refresh_token.family_id, &refresh_token.loginname)
+            .await
+            .inspect_err(|e| log::error!("refresh, get_session_user, error = {e}"))
+            .map_err(|_| RefreshError::Internal)?
+            .ok_or(RefreshError::InvalidToken)?;
+
+        let now = timestamp::now();
+        self.store
+            .extend_session(
+                &refresh_token.family_id,
+                now,
+                now + self.refresh_token_lifetime().as_secs(),
+            )
+            .await
+            .inspect_err(|e| log::error!("refresh, extend_session, error = {e}"))
+            .map_err(|_| RefreshError::Internal)?;
+
+        let session_tokens = self
+            .issue_session_tokens(&login_info, refresh_token.family_id)
+            .await
+            .map_err(|_| RefreshError::Internal)?;
+
+        log::info!("Tokens refreshed, loginname = '{}'", login_info.loginname);
+
+        Ok((login_info.loginname, session_tokens))
+    }
+
+    async fn issue_session_tokens(
+        &self,
+        login_info: &StoredLoginInfo,
+        session_id: Uuid,
+    ) -> Result<SessionTokens, ()> {
+        Ok(SessionTokens {
+            access_token: self.create_jwt_for_user(login_info, session_id, None)?,
+            refresh_token: self
+                .create_refresh_token(&login_info.loginname, session_id)
+                .await?,
+        })
+    }
+
+    fn access_token_response(&self, session_tokens: SessionTokens) -> AccessTokenResponse {
+        AccessTokenResponse::with_time_delta(
+            AccessToken::new(session_tokens.access_token),
+            self.access_token_config().lifetime,
+            Some((
+                RefreshToken::new(session_tokens.refresh_token),
+                self.refresh_token_lifetime(),
+            )),
+        )
+    }
+
+    fn access_token_config(&self) -> AccessTokenConfig {
+        self.config().auth.access_token_config()
+    }
+
+    fn refresh_token_lifetime(&self) -> Duration {
+        self.config().auth.refresh_token_lifetime()
+    }
+
+    async fn create_refresh_token(
+        &self,
+        loginname: impl Into<String>,
+        family_id: Uuid,
+    ) -> Result<String, ()> {
+        let refresh_token = opaque_token::generate()?;
+
+        self.store
+            .insert_refresh_token(StoredRefreshToken {
+                token_hash: opaque_token::hash(&refresh_token),
+                family_id,
+                loginname: loginname.into(),
+                expires_at: timestamp::now() + self.refresh_token_lifetime().as_secs(),
+                used: false,
+            })
+            .await
+            .inspect_err(|e| log::error!("create_refresh_token, insert_refresh_token, error = {e}"))
+            .map_err(|_| ())?;
+
+        Ok(refresh_token)
+    }
+
+    fn create_jwt_for_user(
+        &self,
+        login_info: &StoredLoginInfo,
+        session_id: Uuid,
+        impersonator: Option<&str>,
+    ) -> Result<String, ()> {
+        let now = timestamp::now();
+        let config = self.access_token_config();
+
+        let mut custom = self.claims_hook.custom_claims(login_info);
+        custom.retain(|name, _| {
+            let registered = access_token::REGISTERED_CLAIMS.contains(&name.as_str());
+            if registered {
+                log::error!("create_jwt_for_user, the custom claim '{name}' is dropped, it is a registered claim");
+            }
+            !registered
+        });
+
+        self.keyring.read().encode(&UserLoginClaims {
+            sub: login_info.loginname.clone(),
+            roles: login_info.roles.clone(),
+            sid: session_id,
+            act: impersonator.map(|impersonator| ActorClaims {
+                sub: impersonator.to_string(),
+            }),
+            exp: (now + config.lifetime.as_secs()) as usize,
+            iat: now as usize,
+            nbf: now as usize,
+            iss: config.issuer,
+            aud: config.audience,
+            custom,
+        })
+    }
+
+    /// Checks the signature, `exp`, `nbf`, `iat`, `iss` and `aud`, the time claims with the
+    /// configured leeway
+    fn decode_user_jwt(&self, token: &str) -> Result<UserLoginClaims, ()> {
+        let config = self.access_token_config();
+
+        let mut validation = Validation::default();
+        validation.leeway = config.leeway.as_secs();
+        validation.validate_nbf = true;
+        validation.set_issuer(&[&config.issuer]);
+        validation.set_audience(&[&config.audience]);
+        validation.set_required_spec_claims(&["exp", "nbf", "iss", "aud", "sub"]);
+
+        let claims: UserLoginClaims = self.keyring.read().decode(token, &validation)?;
+
+        // jsonwebtoken does not check iat, a token issued in the future is as suspicious as one
+        // that is not valid yet
+        if claims.iat as u64 > timestamp::now() + config.leeway.as_secs() {
+            log::error!(
+                "decode_user_jwt, the token is issued in the future, iat = {}",
+                claims.iat
+            );
+            return Err(());
+        }
+
+        Ok(claims)
+    }
+
+    fn create_purpose_token(
+        &self,
+        purpose: TokenPurpose,
+        loginname: impl Into<String>,
+        binding: Option<String>,
+    ) -> Result<String, ()> {
+        let exp = (timestamp::now() + purpose.lifetime().as_secs()) as usize;
+
+        self.keyring.read().encode_internal(&PurposeTokenClaims {
+            sub: loginname.into(),
+            iss: self.access_token_config().issuer,
+            aud: purpose.audience(),
+            binding,
+            exp,
+        })
+    }
+
+    /// Returns the loginname and the binding of the token
+    fn decode_purpose_token(
+        &self,
+        purpose: TokenPurpose,
+        token: &str,
+    ) -> Result<(String, Option<String>), ()> {
+        let config = self.access_token_config();
+
+        let mut validation = Validation::default();
+        validation.leeway = config.leeway.as_secs();
+        validation.set_issuer(&[&config.issuer]);
+        validation.set_audience(&[purpose.audience()]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims: PurposeTokenClaims = self.keyring.read().decode_internal(token, &validation)?;
+
+        Ok((claims.sub, claims.binding))
+    }
+}
+
+#[async_trait]
+impl AuthHandler<LoginInfo> for AppState {
+    async fn verify_access_token(
+        &mut self,
+        access_token: &AccessToken,
+    ) -> Result<LoginInfo, StatusCode> {
+        if api_key::is_api_key(access_token) {
+            return self
+                .verify_api_key(access_token)
+                .await
+                .inspect_err(|e| log::error!("verify_access_token, verify_api_key, error = {e}"))
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .ok_or(StatusCode::BAD_REQUEST);
+        }
+
+        let user_login_claims = self
+            .decode_user_jwt(access_token)
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        let (session, login_info) = self
+            .get_session_user(&user_login_claims.sid, &user_login_claims.sub)
+            .await
+            .inspect_err(|e| log::error!("verify_access_token, get_session_user, error = {e}"))
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::BAD_REQUEST)?;
+
+        // the actor of the token has to be the one the session was started by
+        if user_login_claims.act.map(|actor| actor.sub) != session.impersonator {
+            log::warn!(
+                "Access token actor does not match its session, loginname = '{}', session_id = '{}'",
+                session.loginname,
+                session.id
+            );
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let custom_claims = self
+            .claims_hook
+            .read_custom_claims(user_login_claims.custom)
+            .inspect_err(|e| {
+                log::warn!(
+                    "Access token with invalid custom claims, loginname = '{}', error = {e}",
+                    session.loginname
+                )
+            })
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        let mut login_info = LoginInfo::new(
+            &login_info,
+            session.id,
+            session.impersonator,
+            &self.permission_policy,
+        );
+        login_info.custom_claims = custom_claims;
+
+        Ok(login_info)
+    }
+
+    async fn update_access_token(
+        &mut self,
+        _access_token: &AccessToken,
+        login_info: &Arc<LoginInfo>,
+    ) -> Option<(AccessToken, Duration)> {
+        // API keys are sent with every request, they are not exchanged for access tokens
+        if login_info.api_key_id.is_some() {
+            return None;
+        }
+
+        let (session, stored_login_info) = self
+            .get_session_user(&login_info.session_id, &login_info.loginname)
+            .await
+            .inspect_err(|e| log::error!("update_access_token, get_session_user, error = {e}"))
+            .ok()??;
+
+        let access_token = self
+            .create_jwt_for_user(
+                &stored_login_info,
+                session.id,
+                session.impersonator.as_deref(),
+            )
+            .ok()?;
+        Some((
+            AccessToken::new(access_token),
+            self.access_token_config().lifetime,
+        ))
+    }
+
+    async fn revoke_access_token(
+        &mut self,
+        _access_token: &AccessToken,
+        login_info: &Arc<LoginInfo>,
+    ) {
+        self.logout(login_info).await;
+    }
+
+    async fn verify_refresh_token(
+        &mut self,
+        refresh_token: &RefreshToken,
+    ) -> Result<(), StatusCode> {
+        let refresh_token = self
+            .store
+            .get_refresh_token(&opaque_token::hash(refresh_token))
+            .await
+            .inspect_err(|e| log::error!("verify_refresh_token, get_refresh_token, error = {e}"))
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::BAD_REQUEST)?;
+
+        if !refresh_token.used && refresh_token.expires_at > timestamp::now() {
+            Ok(())
+        } else {
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+
+    async fn revoke_refresh_token(&mut self, refresh_token: &RefreshToken) {
+        let refresh_token = match self
+            .store
+            .get_refresh_token(&opaque_token::hash(refresh_token))
+            .await
+        {
+            Ok(Some(refresh_token)) => refresh_token,
+            Ok(None) => return,
+            Err(e) => {
+                log::error!("revoke_refresh_token, get_refresh_token, error = {e}");
+                return;
+            }
+        };
+
+        let _ = self
+            .store
+            .delete_refresh_token_family(&refresh_token.family_id)
+            .await
+            .inspect_err(|e| {
+                log::error!("revoke_refresh_token, delete_refresh_token_family, error = {e}")
+            });
+    }
+}
+
+impl AxumAppState for AppState {
+    /// Every route is listed in `endpoints::route_table` too, a test checks that the listed ones
+    /// are routed. The server settings are read once, they are restart-only
+    fn routes(&self) -> Router {
+        let server_config = self.config().server.clone();
+
+        Router::new()
+            .nest_service("/public", ServeDir::new(&server_config.static_dir))
+            .route("/", get(crate::endpoints::index))
+            .route(
+                "/.well-known/jwks.json",
+                get(crate::endpoints::well_known::jwks),
+            )
+            .route("/login", get(crate::endpoints::login))
+            .route("/api/login", post(crate::endpoints::api::login))
+            .route(
+                "/api/login/second-factor",
+                post(crate::endpoints::api::login_second_factor),
+            )
+            .route("/api/oidc/login", get(crate::endpoints::api::oidc::login))
+            .route(
+                "/api/oidc/callback",
+                get(crate::endpoints::api::oidc::callback),
+            )
+            .route("/api/logout", post(crate::endpoints::api::logout))
+            .route("/api/refresh", post(crate::endpoints::api::refresh))
+            .route(
+                "/api/impersonation/end",
+                post(crate::endpoints::api::end_impersonation),
+            )
+            .route(
+                "/api/password-reset",
+                post(crate::endpoints::api::users::request_password_reset),
+            )
+            .route(
+                "/api/password-reset/confirm",
+                post(crate::endpoints::api::users::confirm_password_reset),
+            )
+            .route(
+                "/api/email-verification/confirm",
+                post(crate::endpoints::api::users::confirm_email_verification),
+            )
+            .route("/api/users", post(crate::endpoints::api::users::register))
+            .route(
+                "/api/users/me",
+                get(crate::endpoints::api::users::get_current_user),
+            )
+            .route(
+                "/api/users/me/password",
+                post(crate::endpoints::api::users::change_password),
+            )
+            .route(
+                "/api/users/me/email",
+                put(crate::endpoints::api::users::set_email),
+            )
+            .route(
+                "/api/users/me/email/verification",
+                post(crate::endpoints::api::users::resend_email_verification),
+            )
+            .route(
+                "/api/users/me/totp",
+                post(crate::endpoints::api::users::enroll_totp)
+                    .delete(crate::endpoints::api::users::disable_totp),
+            )
+            .route(
+                "/api/users/me/totp/confirm",
+                post(crate::endpoints::api::users::confirm_totp),
+            )
+            .route(
+                "/api/users/me/api-keys",
+                get(crate::endpoints::api::users::list_api_keys)
+                    .post(crate::endpoints::api::users::create_api_key),
+            )
+            .route(
+                "/api/users/me/api-keys/:api_key_id",
+                delete(crate::endpoints::api::users::revoke_api_key),
+            )
+            .route("/api/sessions", get(crate::endpoints::api::get_sessions))
+            .route(
+                "/api/sessions/:session_id",
+                delete(crate::endpoints::api::revoke_session),
+            )
+            .route(
+                "/api/seen-users",
+                get(crate::endpoints::api::get_seen_users),
+            )
+            .route(
+                "/api/seen-users/:index",
+                get(crate::endpoints::api::get_seen_user),
+            )
+            .nest("/api/admin", admin_routes(self))
+            .route("/api/create-uuid-v4", get(create_uuid_v4))
+            .route(
+                "/api/echo/:this/and/:that",
+                get(crate::endpoints::api::echo_this_and_that),
+            )
+            .route("/api/echo-path", get(crate::endpoints::api::echo_path))
+            .route(
+                "/api/echo-query-params",
+                get(crate::endpoints::api::echo_query_params),
+            )
+            .route(
+                "/api/echo-parsed-query-params",
+                get(crate::endpoints::api::echo_parsed_query_params),
+            )
+            .route(
+                "/api/echo-uuid-in-path/:uuid",
+                get(crate::endpoints::api::echo_uuid_in_path),
+            )
+            .route_layer(AuthLayer::new(self.clone()))
+            .layer(axum::middleware::map_request(
+                api_key::normalize_authorization_header,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                self.clone(),
+                csrf::protect,
+            ))
+            .layer(DefaultBodyLimit::max(server_config.body_limit_bytes))
+            .layer(
+                ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(handle_timeout_error))
+                    .timeout(server_config.request_timeout()),
+            )
+            // merged after the auth and the timeout layers, the probes need neither
+            .merge(health_routes())
+            // outermost, so the preflight requests are answered before the other layers
+            .layer(axum::middleware::from_fn_with_state(
+                self.clone(),
+                cors::handle,
+            ))
+            .with_state(self.clone())
+    }
+}
+
+fn load_reloaded_signing_keys(config: &Config) -> Result<Vec<SigningKey>, ()> {
+    if config.auth.signing_keys.is_empty() {
+        log::error!(
+            "Configuration reload rejected, auth.signing_keys can not be emptied, the secret and the private key file are only read at startup"
+        );
+        return Err(());
+    }
+
+    keyring::load_keys(config.auth.jwt_algorithm, &config.auth.signing_keys)
+        .inspect_err(|e| {
+            log::error!(
+                "Configuration reload rejected, the signing keys can not be loaded, error = {e}"
+            )
+        })
+        .map_err(|_| ())
+}
+
+/// The retired signing keys are kept until every token signed by them expired
+fn signing_key_retention(access_token_config: &AccessTokenConfig) -> Duration {
+    TokenPurpose::ALL
+        .iter()
+        .map(|purpose| purpose.lifetime())
+        .fold(access_token_config.lifetime, Duration::max)
+        + access_token_config.leeway
+}
+
+fn health_routes() -> Router<AppState> {
+    Router::new()
+        .route("/healthz", get(crate::endpoints::health::healthz))
+        .route("/readyz", get(crate::endpoints::health::readyz))
+}
+
+/// Everything under `/api/admin`. Each route requires the permission it needs, whichever role
+/// grants it.
+fn admin_routes(state: &AppState) -> Router<AppState> {
+    Router::new()
+        .route(
+            "/signing-keys",
+            get(crate::endpoints::api::admin::get_signing_keys).route_layer(
+                AuthorizationLayer::require_permission(state, permissions::SIGNING_KEYS_READ),
+            ),
+        )
+        .route(
+            "/audit-events",
+            get(crate::endpoints::api::admin::get_audit_events).route_layer(
+                AuthorizationLayer::require_permission(state, permissions::AUDIT_READ),
+            ),
+        )
+        .route(
+            "/users",
+            get(crate::endpoints::api::admin::get_users).route_layer(
+                AuthorizationLayer::require_permission(state, permissions::USERS_READ),
+            ),
+        )
+        .route(
+            "/users",
+            post(crate::endpoints::api::admin::create_user).route_layer(
+                AuthorizationLayer::require_permission(state, permissions::USERS_WRITE),
+            ),
+        )
+        .route(
+            "/users/:loginname",
+            get(crate::endpoints::api::admin::get_user).route_layer(
+                AuthorizationLayer::require_permission(state, permissions::USERS_READ),
+            ),
+        )
+        .route(
+            "/users/:loginname",
+            patch(crate::endpoints::api::admin::update_user)
+                .delete(crate::endpoints::api::admin::delete_user)
+                .route_layer(AuthorizationLayer::require_permission(
+                    state,
+                    permissions::USERS_WRITE,
+                )),
+        )
+        .route(
+            "/users/:loginname/unlock",
+            post(crate::endpoints::api::admin::unlock_user).route_layer(
+                AuthorizationLayer::require_permission(state, permissions::USERS_WRITE),
+            ),
+        )
+        .route(
+            "/users/:loginname/impersonate",
+            post(crate::endpoints::api::admin::impersonate_user).route_layer(
+                AuthorizationLayer::require_permission(state, permissions::USERS_IMPERSONATE),
+            ),
+        )
+        .route(
+            "/users/:loginname/totp",
+            delete(crate::endpoints::api::admin::reset_user_totp).route_layer(
+                AuthorizationLayer::require_permission(state, permissions::USERS_WRITE),
+            ),
+        )
+        .route(
+            "/invites",
+            post(crate::endpoints::api::admin::create_invite).route_layer(
+                AuthorizationLayer::require_permission(state, permissions::USERS_WRITE),
+            ),
+        )
+}
+
+async fn handle_timeout_error(err: tower::BoxError) -> StatusCode {
+    if err.is::<tower::timeout::error::Elapsed>() {
+        StatusCode::REQUEST_TIMEOUT
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+async fn create_uuid_v4() -> String {
+    Uuid::new_v4().as_hyphenated().to_string()
+}
+
+/// The email address of the identity if the provider verified it and it is valid
+fn verified_email(identity: &OidcIdentity) -> Option<String> {
+    identity
+        .email
+        .clone()
+        .filter(|email| identity.email_verified && validate_email(email).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{
+        header::{
+            ACCESS_CONTROL_ALLOW_ORIGIN, AUTHORIZATION, COOKIE, LOCATION, ORIGIN, SET_COOKIE,
+        },
+        HeaderValue, Method,
+    };
+    use axum_test::TestServer;
+
+    use crate::{
+        access_token::NoCustomClaims,
+        config::SigningKeyConfig,
+        endpoints::route_table::ROUTES,
+        mailer::{LogMailer, MemoryMailer},
+        oidc::tests::MockProvider,
+        store::{InMemoryStore, SqliteStore},
+    };
+
+    fn state() -> AppState {
+        state_with_mailer(Arc::new(LogMailer))
+    }
+
+    fn state_with_mailer(mailer: Arc<dyn Mailer>) -> AppState {
+        state_with(&Config::default(), mailer)
+    }
+
+    fn state_with(config: &Config, mailer: Arc<dyn Mailer>) -> AppState {
+        AppState::new(config, dependencies(config, mailer))
+    }
+
+    fn state_with_oidc(oidc: OidcClient) -> AppState {
+        let config = Config::default();
+        AppState::new(
+            &config,
+            AppStateDependencies {
+                oidc: Some(oidc),
+                ..dependencies(&config, Arc::new(LogMailer))
+            },
+        )
+    }
+
+    fn dependencies(config: &Config, mailer: Arc<dyn Mailer>) -> AppStateDependencies {
+        let store = Arc::new(InMemoryStore::new());
+        AppStateDependencies {
+            signing_keys: if config.auth.signing_keys.is_empty() {
+                vec![SigningKey::hmac(b"a secret long enough for the tests")]
+            } else {
+                keyring::load_keys(config.auth.jwt_algorithm, &config.auth.signing_keys).unwrap()
+            },
+            claims_hook: Arc::new(NoCustomClaims),
+            store: store.clone(),
+            permission_policy: PermissionPolicy::default(),
+            mailer,
+            oidc: None,
+            audit_sink: store,
+        }
+    }
+
+    fn roles(roles: &[&str]) -> BTreeSet<String> {
+        roles.iter().map(|role| role.to_string()).collect()
+    }
+
+    async fn stored_user(state: &AppState, loginname: &str) -> StoredLoginInfo {
+        state
+            .store
+            .get_user(&LoginName(loginname.to_string()))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    /// Adds the user and logs it in
+    async fn open_session(state: &mut AppState, loginname: &str) -> SessionTokens {
+        state
+            .add_user(loginname, "a long password", roles(&["regular"]))
+            .await
+            .unwrap();
+        state
+            .open_session(&stored_user(state, loginname).await, None, None)
+            .await
+            .unwrap()
+    }
+
+    async fn verify_access_token(
+        state: &mut AppState,
+        session_tokens: &SessionTokens,
+    ) -> Result<LoginInfo, StatusCode> {
+        state
+            .verify_access_token(&AccessToken::new(session_tokens.access_token.clone()))
+            .await
+    }
+
+    /// The mails are sent in the background
+    async fn wait_for_mails(mailer: &MemoryMailer) -> Vec<Mail> {
+        for _ in 0..100 {
+            let mails = mailer.take_sent_mails();
+            if !mails.is_empty() {
+                return mails;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        Vec::new()
+    }
+
+    /// The token is the only paragraph of the mail without spaces
+    fn token_of_mail(mail: &Mail) -> String {
+        mail.body
+            .split("\n\n")
+            .find(|paragraph| !paragraph.is_empty() && !paragraph.contains(' '))
+            .unwrap()
+            .to_string()
+    }
+
+    /// Adds the user with an email address verified through the mailed token
+    async fn add_user_with_verified_email(
+        state: &mut AppState,
+        mailer: &MemoryMailer,
+        loginname: &str,
+    ) {
+        state
+            .add_user(loginname, "a long password", roles(&["regular"]))
+            .await
+            .unwrap();
+        state
+            .set_email(
+                loginname,
+                "a long password",
+                format!("{loginname}@example.com"),
+            )
+            .await
+            .unwrap();
+        let mails = wait_for_mails(mailer).await;
+        state.verify_email(&token_of_mail(&mails[0])).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn email_verification_mail_verifies_the_address() {
+        let mailer = MemoryMailer::new();
+        let mut state = state_with_mailer(Arc::new(mailer.clone()));
+        state
+            .add_user("user", "a long password", roles(&["regular"]))
+            .await
+            .unwrap();
+
+        state
+            .set_email("user", "a long password", "user@example.com".to_string())
+            .await
+            .unwrap();
+
+        let mails = wait_for_mails(&mailer).await;
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].to, "user@example.com");
+        assert_eq!(mails[0].subject, "Verify your email address");
+        assert!(!stored_user(&state, "user").await.email_verified);
+
+        let token = token_of_mail(&mails[0]);
+        state.verify_email(&token).await.unwrap();
+        assert!(stored_user(&state, "user").await.email_verified);
+        assert!(matches!(
+            state.verify_email(&token).await,
+            Err(UserError::InvalidToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn changing_the_email_address_invalidates_the_verification_token() {
+        let mailer = MemoryMailer::new();
+        let mut state = state_with_mailer(Arc::new(mailer.clone()));
+        state
+            .add_user("user", "a long password", roles(&["regular"]))
+            .await
+            .unwrap();
+        state
+            .set_email("user", "a long password", "old@example.com".to_string())
+            .await
+            .unwrap();
+        let old_token = token_of_mail(&wait_for_mails(&mailer).await[0]);
+
+        state
+            .set_email("user", "a long password", "new@example.com".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(wait_for_mails(&mailer).await[0].to, "new@example.com");
+        assert!(matches!(
+            state.verify_email(&old_token).await,
+            Err(UserError::InvalidToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn the_email_address_is_only_changed_with_the_current_password() {
+        let mailer = MemoryMailer::new();
+        let mut state = state_with_mailer(Arc::new(mailer.clone()));
+        add_user_with_verified_email(&mut state, &mailer, "user").await;
+
+        assert!(matches!(
+            state
+                .set_email(
+                    "user",
+                    "not the password",
+                    "attacker@example.com".to_string()
+                )
+                .await,
+            Err(UserError::WrongPassword)
+        ));
+
+        let stored_login_info = stored_user(&state, "user").await;
+        assert_eq!(stored_login_info.email.as_deref(), Some("user@example.com"));
+        assert!(stored_login_info.email_verified);
+    }
+
+    #[tokio::test]
+    async fn the_previous_verified_address_is_notified_of_the_change() {
+        let mailer = MemoryMailer::new();
+        let mut state = state_with_mailer(Arc::new(mailer.clone()));
+        add_user_with_verified_email(&mut state, &mailer, "user").await;
+
+        state
+            .set_email("user", "a long password", "new@example.com".to_string())
+            .await
+            .unwrap();
+
+        let mut mails = Vec::new();
+        while mails.len() < 2 {
+            let sent_mails = wait_for_mails(&mailer).await;
+            assert!(!sent_mails.is_empty());
+            mails.extend(sent_mails);
+        }
+        let notification = mails
+            .iter()
+            .find(|mail| mail.to == "user@example.com")
+            .unwrap();
+        assert_eq!(notification.subject, "Your email address was changed");
+        assert!(mails.iter().any(|mail| mail.to == "new@example.com"));
+    }
+
+    #[tokio::test]
+    async fn password_reset_mail_resets_the_password_once() {
+        let mailer = MemoryMailer::new();
+        let mut state = state_with_mailer(Arc::new(mailer.clone()));
+        add_user_with_verified_email(&mut state, &mailer, "user").await;
+
+        state.request_password_reset("user").await.unwrap();
+
+        let mails = wait_for_mails(&mailer).await;
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].to, "user@example.com");
+        assert_eq!(mails[0].subject, "Password reset");
+
+        let token = token_of_mail(&mails[0]);
+        state
+            .reset_password(&token, "the new password")
+            .await
+            .unwrap();
+        assert!(password::verify_password(
+            "the new password",
+            Some(&stored_user(&state, "user").await.password_hash)
+        ));
+        assert!(matches!(
+            state.reset_password(&token, "another password").await,
+            Err(UserError::InvalidToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn password_reset_mail_is_not_sent_to_unverified_addresses() {
+        let mailer = MemoryMailer::new();
+        let mut state = state_with_mailer(Arc::new(mailer.clone()));
+        state
+            .add_user("user", "a long password", roles(&["regular"]))
+            .await
+            .unwrap();
+        state
+            .set_email("user", "a long password", "user@example.com".to_string())
+            .await
+            .unwrap();
+        // the verification mail
+        wait_for_mails(&mailer).await;
+
+        state.request_password_reset("user").await.unwrap();
+        state.request_password_reset("nobody").await.unwrap();
+
+        assert!(wait_for_mails(&mailer).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn logins_after_a_failed_one_are_throttled() {
+        let mut state = state();
+        state
+            .add_user("user", "a long password", roles(&["regular"]))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            state.login("user", "a wrong password", None, None).await,
+            Err(LoginError::InvalidCredentials)
+        ));
+
+        // even with the right password
+        match state.login("user", "a long password", None, None).await {
+            Err(LoginError::Throttled(retry_after)) => {
+                assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1))
+            }
+            _ => panic!("the login is not throttled"),
+        }
+
+        assert!(state.unlock_login("admin", "user"));
+        assert!(matches!(
+            state.login("user", "a long password", None, None).await,
+            Ok(LoginOutcome::Authenticated(_))
+        ));
+    }
+
+    /// Answers the requests no route matched, the handlers never return it
+    const UNROUTED: StatusCode = StatusCode::IM_A_TEAPOT;
+
+    async fn status_of(server: &TestServer, method: &str, path: &str) -> StatusCode {
+        server
+            .method(Method::from_bytes(method.as_bytes()).unwrap(), path)
+            .await
+            .status_code()
+    }
+
+    /// The path with an example value in place of every parameter
+    fn example_path(path: &str) -> String {
+        path.split('/')
+            .map(|segment| {
+                if segment.starts_with(':') || segment.starts_with('*') {
+                    Uuid::nil().to_string()
+                } else {
+                    segment.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[tokio::test]
+    async fn every_listed_route_is_routed() {
+        let server = TestServer::new(state().routes().fallback(|| async { UNROUTED })).unwrap();
+        // the check itself
+        assert_eq!(status_of(&server, "GET", "/api/unknown").await, UNROUTED);
+        assert_eq!(
+            status_of(&server, "GET", "/api/admin/unknown").await,
+            UNROUTED
+        );
+        assert_eq!(
+            status_of(&server, "DELETE", "/api/refresh").await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+
+        for route in ROUTES {
+            let status = status_of(&server, route.method, &example_path(route.path)).await;
+            assert!(
+                status != UNROUTED && status != StatusCode::METHOD_NOT_ALLOWED,
+                "{} {} is listed, but not routed",
+                route.method,
+                route.path
+            );
+        }
+    }
+
+    /// Writes a new secret into a temporary file
+    fn signing_key_config(kid: &str) -> SigningKeyConfig {
+        let file = std::env::temp_dir().join(format!("signing-key-{}.secret", Uuid::new_v4()));
+        std::fs::write(&file, crate::secret::generate().unwrap()).unwrap();
+        SigningKeyConfig {
+            kid: kid.to_string(),
+            file,
+        }
+    }
+
+    fn kid_of(session_tokens: &SessionTokens) -> String {
+        jsonwebtoken::decode_header(&session_tokens.access_token)
+            .unwrap()
+            .kid
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reloading_the_signing_keys_rotates_the_signing_key() {
+        let first_key = signing_key_config("first");
+        let mut config = Config::default();
+        config.auth.signing_keys = vec![first_key.clone()];
+        let mut state = state_with(&config, Arc::new(LogMailer));
+        let first_tokens = open_session(&mut state, "user").await;
+
+        config.auth.signing_keys = vec![signing_key_config("second"), first_key];
+        state.reload_config(config.clone()).unwrap();
+        let (_, second_tokens) = state
+            .rotate_refresh_token(&first_tokens.refresh_token)
+            .await
+            .unwrap();
+
+        assert_eq!(kid_of(&second_tokens), "second");
+        assert!(verify_access_token(&mut state, &first_tokens).await.is_ok());
+        assert!(verify_access_token(&mut state, &second_tokens)
+            .await
+            .is_ok());
+
+        state.audit().flush().await;
+        let events = state
+            .audit()
+            .query(&AuditQuery {
+                kind: Some(AuditEventKind::SigningKeyRotated),
+                limit: 10,
+                ..AuditQuery::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].details["kid"], "second");
+    }
+
+    #[tokio::test]
+    async fn a_reload_with_an_unreadable_signing_key_is_rejected() {
+        let mut config = Config::default();
+        config.auth.signing_keys = vec![signing_key_config("first")];
+        let mut state = state_with(&config, Arc::new(LogMailer));
+
+        let mut reloaded = config.clone();
+        reloaded.auth.signing_keys = vec![SigningKeyConfig {
+            kid: "second".to_string(),
+            file: std::env::temp_dir().join(format!("missing-{}.secret", Uuid::new_v4())),
+        }];
+        reloaded.log.level = crate::config::LogLevel::Debug;
+
+        assert!(state.reload_config(reloaded).is_err());
+        assert_eq!(state.config().log.level, config.log.level);
+        assert_eq!(kid_of(&open_session(&mut state, "user").await), "first");
+    }
+
+    #[tokio::test]
+    async fn adding_an_existing_user_only_sets_its_credentials() {
+        let mut state = state();
+        state
+            .add_user("admin", "first password", roles(&["admin"]))
+            .await
+            .unwrap();
+        state
+            .update_user(None, "admin", None, Some(true), None)
+            .await
+            .unwrap();
+        let created_at = stored_user(&state, "admin").await.created_at;
+
+        state
+            .add_user("admin", "second password", roles(&["admin", "regular"]))
+            .await
+            .unwrap();
+
+        let login_info = stored_user(&state, "admin").await;
+        assert!(login_info.disabled);
+        assert_eq!(login_info.created_at, created_at);
+        assert_eq!(login_info.roles, roles(&["admin", "regular"]));
+        assert!(password::verify_password(
+            "second password",
+            Some(&login_info.password_hash)
+        ));
+    }
+
+    #[tokio::test]
+    async fn refreshing_rotates_the_refresh_token() {
+        let mut state = state();
+        let session_tokens = open_session(&mut state, "user").await;
+
+        let (loginname, rotated) = state
+            .rotate_refresh_token(&session_tokens.refresh_token)
+            .await
+            .unwrap();
+
+        assert_eq!(loginname, "user");
+        assert_ne!(rotated.refresh_token, session_tokens.refresh_token);
+        let login_info = verify_access_token(&mut state, &rotated).await.unwrap();
+        assert_eq!(login_info.loginname, "user");
+        assert!(state
+            .rotate_refresh_token(&rotated.refresh_token)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn replayed_refresh_tokens_are_rejected() {
+        let mut state = state();
+        let session_tokens = open_session(&mut state, "user").await;
+        state
+            .rotate_refresh_token(&session_tokens.refresh_token)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            state
+                .rotate_refresh_token(&session_tokens.refresh_token)
+                .await,
+            Err(RefreshError::InvalidToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn replaying_a_rotated_refresh_token_revokes_the_session() {
+        let mut state = state();
+        let session_tokens = open_session(&mut state, "user").await;
+        let (_, rotated) = state
+            .rotate_refresh_token(&session_tokens.refresh_token)
+            .await
+            .unwrap();
+
+        assert!(state
+            .rotate_refresh_token(&session_tokens.refresh_token)
+            .await
+            .is_err());
+
+        // the tokens of the rightful holder are not accepted either
+        assert!(state
+            .rotate_refresh_token(&rotated.refresh_token)
+            .await
+            .is_err());
+        assert!(verify_access_token(&mut state, &rotated).await.is_err());
+        assert!(verify_access_token(&mut state, &session_tokens)
+            .await
+            .is_err());
+
+        let events = audit_events(&state, AuditEventKind::RefreshTokenReused).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].loginname.as_deref(), Some("user"));
+    }
+
+    #[tokio::test]
+    async fn expired_refresh_tokens_are_rejected() {
+        let mut config = Config::default();
+        config.auth.refresh_token_lifetime_secs = 0;
+        let mut state = state_with(&config, Arc::new(LogMailer));
+        let session_tokens = open_session(&mut state, "user").await;
+
+        assert!(matches!(
+            state
+                .rotate_refresh_token(&session_tokens.refresh_token)
+                .await,
+            Err(RefreshError::InvalidToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_are_rejected_after_logout() {
+        let mut state = state();
+        let session_tokens = open_session(&mut state, "user").await;
+        let login_info = verify_access_token(&mut state, &session_tokens)
+            .await
+            .unwrap();
+
+        state.logout(&Arc::new(login_info)).await;
+
+        assert!(matches!(
+            state
+                .rotate_refresh_token(&session_tokens.refresh_token)
+                .await,
+            Err(RefreshError::InvalidToken)
+        ));
+        assert!(verify_access_token(&mut state, &session_tokens)
+            .await
+            .is_err());
+    }
+
+    /// Starts a login at the provider and returns the state cookie set for the browser and the
+    /// callback url the provider redirects back to
+    async fn start_oidc_login(server: &TestServer, provider: &MockProvider) -> (String, String) {
+        let response = server.get("/api/oidc/login").await;
+        response.assert_status(StatusCode::SEE_OTHER);
+
+        let state_cookie = response
+            .headers()
+            .get(SET_COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(state_cookie.contains("HttpOnly"));
+        assert!(state_cookie.contains("SameSite=Lax"));
+        let state_cookie = state_cookie.split(';').next().unwrap().to_string();
+
+        let authorization_url = response.header(LOCATION).to_str().unwrap().to_string();
+        let code = provider.authorize(
+            &authorization_url,
+            serde_json::json!({ "preferred_username": "oidc-user", "groups": ["administrators"] }),
+        );
+        let state = url::Url::parse(&authorization_url)
+            .unwrap()
+            .query_pairs()
+            .find(|(key, _)| key == "state")
+            .map(|(_, value)| value.into_owned())
+            .unwrap();
+
+        (
+            state_cookie,
+            format!("/api/oidc/callback?code={code}&state={state}"),
+        )
+    }
+
+    #[tokio::test]
+    async fn the_oidc_callback_is_accepted_from_the_browser_that_started_the_login() {
+        let provider = MockProvider::start().await;
+        let state = state_with_oidc(OidcClient::new(provider.client_config()).unwrap());
+        let server = TestServer::new(state.routes()).unwrap();
+
+        let (state_cookie, callback_url) = start_oidc_login(&server, &provider).await;
+        let response = server
+            .get(&callback_url)
+            .add_header(COOKIE, HeaderValue::from_str(&state_cookie).unwrap())
+            .await;
+
+        assert!(response.status_code().is_redirection());
+        assert_eq!(
+            stored_user(&state, "oidc-user").await.roles,
+            roles(&["admin"])
+        );
+    }
+
+    #[tokio::test]
+    async fn the_oidc_callback_is_rejected_without_the_state_cookie_of_the_login() {
+        let provider = MockProvider::start().await;
+        let state = state_with_oidc(OidcClient::new(provider.client_config()).unwrap());
+        let server = TestServer::new(state.routes()).unwrap();
+
+        // e.g., the callback url of the attacker's login sent to the victim
+        let (_, callback_url) = start_oidc_login(&server, &provider).await;
+        server
+            .get(&callback_url)
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        // the state cookie of another login of the same browser
+        let (state_cookie, _) = start_oidc_login(&server, &provider).await;
+        server
+            .get(&callback_url)
+            .add_header(COOKIE, HeaderValue::from_str(&state_cookie).unwrap())
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        assert!(state.get_user("oidc-user").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn credentials_email_and_second_factor_can_not_be_changed_with_an_api_key() {
+        let mut state = state();
+        let session_tokens = open_session(&mut state, "user").await;
+        let login_info = verify_access_token(&mut state, &session_tokens)
+            .await
+            .unwrap();
+        let (key, _) = state
+            .create_api_key(&login_info, "ci".to_string(), None, None)
+            .await
+            .unwrap();
+        let server = TestServer::new(state.routes()).unwrap();
+        let authorization = HeaderValue::from_str(&format!("ApiKey {key}")).unwrap();
+
+        // the key itself is valid
+        server
+            .get("/api/users/me")
+            .add_header(AUTHORIZATION, authorization.clone())
+            .await
+            .assert_status_ok();
+
+        for (method, path, body) in [
+            (
+                Method::POST,
+                "/api/users/me/password",
+                json!({ "current_password": "a long password", "new_password": "a new long password" }),
+            ),
+            (
+                Method::PUT,
+                "/api/users/me/email",
+                json!({ "email": "attacker@example.com", "current_password": "a long password" }),
+            ),
+            (Method::POST, "/api/users/me/totp", json!({})),
+            (
+                Method::POST,
+                "/api/users/me/totp/confirm",
+                json!({ "code": "000000" }),
+            ),
+            (
+                Method::DELETE,
+                "/api/users/me/totp",
+                json!({ "current_password": "a long password" }),
+            ),
+            (
+                Method::POST,
+                "/api/users/me/api-keys",
+                json!({ "name": "another key" }),
+            ),
+        ] {
+            let response = server
+                .method(method.clone(), path)
+                .add_header(AUTHORIZATION, authorization.clone())
+                .json(&body)
+                .await;
+            assert_eq!(
+                response.status_code(),
+                StatusCode::FORBIDDEN,
+                "{method} {path}"
+            );
+        }
+
+        let loginname = LoginName("user".to_string());
+        assert_eq!(stored_user(&state, "user").await.email, None);
+        assert!(state.store.get_totp(&loginname).await.unwrap().is_none());
+        assert_eq!(
+            state.store.list_api_keys(&loginname).await.unwrap().len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn purpose_tokens_are_only_accepted_for_their_purpose() {
+        let config = Config::default();
+        let state = AppState::new(
+            &config,
+            AppStateDependencies {
+                signing_keys: vec![SigningKey::generate(keyring::SigningAlgorithm::Es256).unwrap()],
+                ..dependencies(&config, Arc::new(LogMailer))
+            },
+        );
+
+        let token = state
+            .create_purpose_token(TokenPurpose::SecondFactorChallenge, "user", None)
+            .unwrap();
+
+        assert_eq!(
+            state
+                .decode_purpose_token(TokenPurpose::SecondFactorChallenge, &token)
+                .unwrap()
+                .0,
+            "user"
+        );
+        assert!(state
+            .decode_purpose_token(TokenPurpose::PasswordReset, &token)
+            .is_err());
+        assert!(state.decode_user_jwt(&token).is_err());
+
+        // the services verifying the access tokens against the JWKS do not accept it either
+        let public_jwks = state.keyring.read().public_jwks();
+        assert_eq!(public_jwks.len(), 1);
+        for public_jwk in public_jwks {
+            let jwk: jsonwebtoken::jwk::Jwk = serde_json::from_value(public_jwk).unwrap();
+            let mut validation = Validation::new(jsonwebtoken::Algorithm::ES256);
+            validation.validate_aud = false;
+            assert!(jsonwebtoken::decode::<Value>(
+                &token,
+                &jsonwebtoken::DecodingKey::from_jwk(&jwk).unwrap(),
+                &validation
+            )
+            .is_err());
+        }
+    }
+
+    /// A support role that can impersonate but has fewer permissions than the admins
+    fn state_with_support_role() -> AppState {
+        let config = Config::default();
+        let permission_policy = toml::from_str(
+            r#"
+            [regular]
+
+            [support]
+            permissions = ["users:read", "users:impersonate"]
+            inherits = ["regular"]
+
+            [admin]
+            permissions = ["users:read", "users:write", "users:impersonate", "audit:read"]
+            inherits = ["regular"]
+            "#,
+        )
+        .unwrap();
+
+        AppState::new(
+            &config,
+            AppStateDependencies {
+                permission_policy,
+                ..dependencies(&config, Arc::new(LogMailer))
+            },
+        )
+    }
+
+    /// Adds the user with the roles and returns the login info of a session of the user
+    async fn logged_in_user(
+        state: &mut AppState,
+        loginname: &str,
+        user_roles: &[&str],
+    ) -> LoginInfo {
+        state
+            .add_user(loginname, "a long password", roles(user_roles))
+            .await
+            .unwrap();
+        let session_tokens = state
+            .open_session(&stored_user(state, loginname).await, None, None)
+            .await
+            .unwrap();
+
+        verify_access_token(state, &session_tokens).await.unwrap()
+    }
+
+    async fn audit_events(state: &AppState, kind: AuditEventKind) -> Vec<AuditEvent> {
+        state.audit().flush().await;
+        state
+            .audit()
+            .query(&AuditQuery {
+                kind: Some(kind),
+                limit: 10,
+                ..AuditQuery::default()
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn impersonation_session_carries_the_impersonator_until_it_ends() {
+        let mut state = state_with_support_role();
+        let support = logged_in_user(&mut state, "support", &["support"]).await;
+        state
+            .add_user("user", "a long password", roles(&["regular"]))
+            .await
+            .unwrap();
+
+        let (_, access_token) = state
+            .open_impersonation_session(&support, "user", None)
+            .await
+            .unwrap();
+
+        let claims = state.decode_user_jwt(&access_token).unwrap();
+        assert_eq!(claims.sub, "user");
+        assert_eq!(
+            claims.act.map(|actor| actor.sub).as_deref(),
+            Some("support")
+        );
+        let access_token = AccessToken::new(access_token);
+        let login_info = state.verify_access_token(&access_token).await.unwrap();
+        assert_eq!(login_info.loginname, "user");
+        assert_eq!(login_info.impersonator.as_deref(), Some("support"));
+
+        assert!(state.end_impersonation(&login_info).await.unwrap());
+        assert!(state.verify_access_token(&access_token).await.is_err());
+
+        for kind in [
+            AuditEventKind::ImpersonationStarted,
+            AuditEventKind::ImpersonationEnded,
+        ] {
+            let events = audit_events(&state, kind).await;
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].loginname.as_deref(), Some("user"));
+            assert_eq!(events[0].actor.as_deref(), Some("support"));
+        }
+    }
+
+    #[tokio::test]
+    async fn users_with_permissions_the_impersonator_lacks_can_not_be_impersonated() {
+        let mut state = state_with_support_role();
+        let support = logged_in_user(&mut state, "support", &["support"]).await;
+        let admin = logged_in_user(&mut state, "admin", &["admin"]).await;
+
+        assert!(matches!(
+            state
+                .open_impersonation_session(&support, "admin", None)
+                .await,
+            Err(UserError::NotAllowed(_))
+        ));
+        assert!(state
+            .open_impersonation_session(&admin, "support", None)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn impersonation_is_only_started_from_an_own_session() {
+        let mut state = state_with_support_role();
+        let admin = logged_in_user(&mut state, "admin", &["admin"]).await;
+        logged_in_user(&mut state, "support", &["support"]).await;
+        state
+            .add_user("user", "a long password", roles(&["regular"]))
+            .await
+            .unwrap();
+
+        // nested
+        let (_, access_token) = state
+            .open_impersonation_session(&admin, "support", None)
+            .await
+            .unwrap();
+        let impersonated = state
+            .verify_access_token(&AccessToken::new(access_token))
+            .await
+            .unwrap();
+        assert!(matches!(
+            state
+                .open_impersonation_session(&impersonated, "user", None)
+                .await,
+            Err(UserError::NotAllowed(_))
+        ));
+
+        // with an API key of the admin
+        let (key, _) = state
+            .create_api_key(&admin, "ci".to_string(), None, None)
+            .await
+            .unwrap();
+        let api_key_login_info = state
+            .verify_access_token(&AccessToken::new(key))
+            .await
+            .unwrap();
+        assert!(matches!(
+            state
+                .open_impersonation_session(&api_key_login_info, "user", None)
+                .await,
+            Err(UserError::NotAllowed(_))
+        ));
+
+        assert_eq!(
+            audit_events(&state, AuditEventKind::ImpersonationStarted)
+                .await
+                .len(),
+            1
+        );
+    }
+
+    /// The claims of a valid access token of the session
+    fn access_token_claims(state: &AppState, loginname: &str, session_id: Uuid) -> Value {
+        let config = state.access_token_config();
+        let now = timestamp::now();
+
+        json!({
+            "sub": loginname,
+            "roles": ["regular"],
+            "sid": session_id,
+            "exp": now + 60,
+            "iat": now,
+            "nbf": now,
+            "iss": config.issuer,
+            "aud": config.audience,
+        })
+    }
+
+    #[tokio::test]
+    async fn access_tokens_are_validated_against_the_configuration() {
+        let mut state = state();
+        let session_tokens = open_session(&mut state, "user").await;
+        let session_id = verify_access_token(&mut state, &session_tokens)
+            .await
+            .unwrap()
+            .session_id;
+        let beyond_leeway = state.access_token_config().leeway.as_secs() + 60;
+        let with = |name: &str, value: Value| {
+            let mut claims = access_token_claims(&state, "user", session_id);
+            claims[name] = value;
+            state.keyring.read().encode(&claims).unwrap()
+        };
+
+        assert!(state.decode_user_jwt(&with("sub", json!("user"))).is_ok());
+        for (name, value) in [
+            ("iss", json!("another issuer")),
+            ("aud", json!("another audience")),
+            ("nbf", json!(timestamp::now() + beyond_leeway)),
+            ("iat", json!(timestamp::now() + beyond_leeway)),
+            ("exp", json!(timestamp::now() - beyond_leeway)),
+        ] {
+            assert!(state.decode_user_jwt(&with(name, value)).is_err(), "{name}");
+        }
+    }
+
+    /// Adds the tenant of the users to their tokens and requires it when they are verified
+    struct TenantClaims;
+
+    impl ClaimsHook for TenantClaims {
+        fn custom_claims(&self, login_info: &StoredLoginInfo) -> Map<String, Value> {
+            Map::from_iter([
+                (
+                    "tenant".to_string(),
+                    json!(format!("tenant of {}", login_info.loginname)),
+                ),
+                // registered claims can not be overridden
+                ("sub".to_string(), json!("admin")),
+            ])
+        }
+
+        fn read_custom_claims(
+            &self,
+            claims: Map<String, Value>,
+        ) -> Result<Map<String, Value>, String> {
+            match claims.get("tenant") {
+                Some(Value::String(_)) => Ok(claims),
+                _ => Err("the tenant is missing".to_string()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_claims_of_the_hook_reach_the_login_info() {
+        let config = Config::default();
+        let mut state = AppState::new(
+            &config,
+            AppStateDependencies {
+                claims_hook: Arc::new(TenantClaims),
+                ..dependencies(&config, Arc::new(LogMailer))
+            },
+        );
+        let session_tokens = open_session(&mut state, "user").await;
+
+        let login_info = verify_access_token(&mut state, &session_tokens)
+            .await
+            .unwrap();
+
+        assert_eq!(login_info.loginname, "user");
+        assert_eq!(
+            login_info.custom_claims.get("tenant"),
+            Some(&json!("tenant of user"))
+        );
+
+        // a token without the tenant is rejected by the hook
+        let token = state
+            .keyring
+            .read()
+            .encode(&access_token_claims(&state, "user", login_info.session_id))
+            .unwrap();
+        assert!(state.decode_user_jwt(&token).is_ok());
+        assert_eq!(
+            state
+                .verify_access_token(&AccessToken::new(token))
+                .await
+                .err(),
+            Some(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    fn state_with_registration_mode(registration_mode: RegistrationMode) -> AppState {
+        let mut config = Config::default();
+        config.auth.registration_mode = registration_mode;
+        state_with(&config, Arc::new(LogMailer))
+    }
+
+    #[tokio::test]
+    async fn registration_follows_the_registration_mode() {
+        let state = state_with_registration_mode(RegistrationMode::Open);
+        let login_info = state
+            .register_user("user", "a long password", None, None)
+            .await
+            .unwrap();
+        assert_eq!(login_info.roles, roles(&["regular"]));
+
+        let state = state_with_registration_mode(RegistrationMode::InviteOnly);
+        assert!(matches!(
+            state
+                .register_user("user", "a long password", None, None)
+                .await,
+            Err(UserError::RegistrationNotAllowed)
+        ));
+        let (invite_token, _) = state
+            .create_invite("admin", roles(&["admin"]))
+            .await
+            .unwrap();
+        let login_info = state
+            .register_user("user", "a long password", None, Some(&invite_token))
+            .await
+            .unwrap();
+        assert_eq!(login_info.roles, roles(&["admin"]));
+
+        let state = state_with_registration_mode(RegistrationMode::Disabled);
+        let (invite_token, _) = state
+            .create_invite("admin", roles(&["regular"]))
+            .await
+            .unwrap();
+        assert!(matches!(
+            state
+                .register_user("user", "a long password", None, Some(&invite_token))
+                .await,
+            Err(UserError::RegistrationNotAllowed)
+        ));
+        assert!(state.get_user("user").await.unwrap().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn an_invite_registers_a_single_user_even_concurrently() {
+        let state = state_with_registration_mode(RegistrationMode::InviteOnly);
+        let (invite_token, _) = state
+            .create_invite("admin", roles(&["regular"]))
+            .await
+            .unwrap();
+
+        let registrations = (0..8)
+            .map(|index| {
+                let state = state.clone();
+                let invite_token = invite_token.clone();
+                tokio::spawn(async move {
+                    state
+                        .register_user(
+                            format!("user{index}"),
+                            "a long password",
+                            None,
+                            Some(&invite_token),
+                        )
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut registered_count = 0;
+        for registration in registrations {
+            match registration.await.unwrap() {
+                Ok(_) => registered_count += 1,
+                Err(e) => assert!(matches!(e, UserError::RegistrationNotAllowed)),
+            }
+        }
+
+        assert_eq!(registered_count, 1);
+    }
+
+    #[tokio::test]
+    async fn expired_invites_are_rejected() {
+        let state = state_with_registration_mode(RegistrationMode::InviteOnly);
+        let invite_token = opaque_token::generate().unwrap();
+        let now = timestamp::now();
+        state
+            .store
+            .insert_invite(StoredInvite {
+                token_hash: opaque_token::hash(&invite_token),
+                roles: roles(&["regular"]),
+                created_by: "admin".to_string(),
+                created_at: now - INVITE_LIFETIME.as_secs() - 1,
+                expires_at: now - 1,
+                used: false,
+            })
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            state
+                .register_user("user", "a long password", None, Some(&invite_token))
+                .await,
+            Err(UserError::RegistrationNotAllowed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn user_changes_are_recorded_with_the_admin_as_actor() {
+        let state = state();
+
+        state
+            .create_user(
+                Some("admin"),
+                "user",
+                "a long password",
+                roles(&["regular"]),
+            )
+            .await
+            .unwrap();
+        state
+            .update_user(Some("admin"), "user", Some(roles(&["admin"])), None, None)
+            .await
+            .unwrap();
+        state
+            .update_user(None, "user", None, Some(true), None)
+            .await
+            .unwrap();
+        assert!(state.delete_user(Some("admin"), "user").await.unwrap());
+
+        let created = audit_events(&state, AuditEventKind::UserCreated).await;
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0].loginname.as_deref(), Some("user"));
+        assert_eq!(created[0].actor.as_deref(), Some("admin"));
+        assert_eq!(created[0].details["roles"], json!(["regular"]));
+
+        // the latest first
+        let updated = audit_events(&state, AuditEventKind::UserUpdated).await;
+        assert_eq!(updated.len(), 2);
+        assert_eq!(updated[0].actor, None);
+        assert_eq!(updated[0].details["source"], "cli");
+        assert_eq!(updated[0].details["disabled"], true);
+        assert_eq!(updated[1].actor.as_deref(), Some("admin"));
+        assert_eq!(updated[1].details["roles"], json!(["admin"]));
+        assert_eq!(updated[1].details["password_set"], false);
+
+        let deleted = audit_events(&state, AuditEventKind::UserDeleted).await;
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].actor.as_deref(), Some("admin"));
+    }
+
+    #[tokio::test]
+    async fn admin_actions_are_recorded_with_the_admin_as_actor() {
+        let mut state = state();
+        state
+            .add_user("admin", "a long password", roles(&["admin"]))
+            .await
+            .unwrap();
+        state
+            .add_user("user", "a long password", roles(&["regular"]))
+            .await
+            .unwrap();
+        state.enroll_totp("user").await.unwrap();
+        assert!(state
+            .login("user", "a wrong password", None, None)
+            .await
+            .is_err());
+        let session_tokens = state
+            .open_session(&stored_user(&state, "admin").await, None, None)
+            .await
+            .unwrap();
+        let server = TestServer::new(state.routes()).unwrap();
+        let authorization =
+            HeaderValue::from_str(&format!("Bearer {}", session_tokens.access_token)).unwrap();
+
+        server
+            .patch("/api/admin/users/user")
+            .add_header(AUTHORIZATION, authorization.clone())
+            .json(&json!({ "roles": ["admin"] }))
+            .await
+            .assert_status_ok();
+        server
+            .post("/api/admin/users/user/unlock")
+            .add_header(AUTHORIZATION, authorization.clone())
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+        server
+            .delete("/api/admin/users/user/totp")
+            .add_header(AUTHORIZATION, authorization)
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+
+        let updated = audit_events(&state, AuditEventKind::UserUpdated).await;
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].loginname.as_deref(), Some("user"));
+        assert_eq!(updated[0].actor.as_deref(), Some("admin"));
+        assert_eq!(updated[0].details["roles"], json!(["admin"]));
+
+        for kind in [AuditEventKind::LoginUnlocked, AuditEventKind::TotpReset] {
+            let events = audit_events(&state, kind).await;
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].loginname.as_deref(), Some("user"));
+            assert_eq!(events[0].actor.as_deref(), Some("admin"));
+        }
+    }
+
+    #[tokio::test]
+    async fn readiness_fails_while_the_store_is_unreachable() {
+        let database = std::env::temp_dir().join(format!("health-{}.sqlite", Uuid::new_v4()));
+        let store = Arc::new(SqliteStore::open(&database).unwrap());
+        let config = Config::default();
+        let state = AppState::new(
+            &config,
+            AppStateDependencies {
+                store: store.clone(),
+                audit_sink: store,
+                ..dependencies(&config, Arc::new(LogMailer))
+            },
+        );
+        state.health().set_ready(true);
+        let server = TestServer::new(state.routes()).unwrap();
+        server.get("/readyz").await.assert_status_ok();
+
+        // the store check reads the users
+        let connection = rusqlite::Connection::open(&database).unwrap();
+        connection
+            .execute("ALTER TABLE users RENAME TO unreachable_users", [])
+            .unwrap();
+        for path in ["/readyz", "/healthz"] {
+            let response = server.get(path).await;
+            response.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(
+                response.json::<Value>()["checks"]["store"]["status"],
+                "fail"
+            );
+        }
+
+        connection
+            .execute("ALTER TABLE unreachable_users RENAME TO users", [])
+            .unwrap();
+        server.get("/readyz").await.assert_status_ok();
+
+        drop(connection);
+        std::fs::remove_file(database).unwrap();
+    }
+
+    #[tokio::test]
+    async fn readiness_fails_once_the_shutdown_has_begun() {
+        let state = state();
+        let server = TestServer::new(state.routes()).unwrap();
+        // during the startup
+        server
+            .get("/readyz")
+            .await
+            .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+
+        state.health().set_ready(true);
+        server.get("/readyz").await.assert_status_ok();
+
+        // what serve does when the shutdown starts
+        state.health().set_ready(false);
+        let response = server.get("/readyz").await;
+        response.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.json::<Value>()["ready"], false);
+        // the application still works
+        server.get("/healthz").await.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn the_probes_need_neither_authentication_nor_a_csrf_token() {
+        let state = state();
+        state.health().set_ready(true);
+        let server = TestServer::new(state.routes()).unwrap();
+
+        for path in ["/healthz", "/readyz"] {
+            let response = server
+                .get(path)
+                .add_header(AUTHORIZATION, HeaderValue::from_static("Bearer invalid"))
+                .await;
+            response.assert_status_ok();
+            // the CSRF layer issues a token to every client without one
+            assert!(response.headers().get(SET_COOKIE).is_none());
+
+            // a forged request would be rejected with 403 by the CSRF layer
+            server
+                .post(path)
+                .add_header(COOKIE, HeaderValue::from_static("session=1"))
+                .await
+                .assert_status(StatusCode::METHOD_NOT_ALLOWED);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_reload_changing_a_restart_required_setting_is_rejected() {
+        let state = state();
+        let mut reloaded = Config::default();
+        reloaded.server.cors_allowed_origins = vec!["https://app.example.com".into()];
+        reloaded.database.path = Some("other.sqlite".into());
+
+        assert!(state.reload_config(reloaded).is_err());
+
+        // nothing is applied, not even the reloadable setting
+        assert!(state.config().server.cors_allowed_origins.is_empty());
+        assert_eq!(state.config().database.path, None);
+        assert!(audit_events(&state, AuditEventKind::ConfigReloaded)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_reload_applies_the_reloadable_settings() {
+        let state = state();
+        let server = TestServer::new(state.routes()).unwrap();
+        let origin = HeaderValue::from_static("https://app.example.com");
+        let mut reloaded = Config::default();
+        reloaded.server.cors_allowed_origins = vec!["https://app.example.com".into()];
+
+        state.reload_config(reloaded).unwrap();
+
+        // the next request sees the new setting
+        let response = server
+            .get("/healthz")
+            .add_header(ORIGIN, origin.clone())
+            .await;
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+
+        let reloads = audit_events(&state, AuditEventKind::ConfigReloaded).await;
+        assert_eq!(reloads.len(), 1);
+        assert_eq!(
+            reloads[0].details["changes"][0]["field"],
+            "server.cors_allowed_origins"
+        );
+    }
+
+    fn bearer(session_tokens: &SessionTokens) -> HeaderValue {
+        HeaderValue::from_str(&format!("Bearer {}", session_tokens.access_token)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_sessions_of_other_users_can_not_be_revoked() {
+        let mut state = state();
+        let own_tokens = open_session(&mut state, "user").await;
+        let other_tokens = open_session(&mut state, "other").await;
+        let other_session_id = verify_access_token(&mut state, &other_tokens)
+            .await
+            .unwrap()
+            .session_id;
+        let server = TestServer::new(state.routes()).unwrap();
+
+        // as if it did not exist
+        server
+            .delete(&format!("/api/sessions/{other_session_id}"))
+            .add_header(AUTHORIZATION, bearer(&own_tokens))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        server
+            .get("/api/users/me")
+            .add_header(AUTHORIZATION, bearer(&other_tokens))
+            .await
+            .assert_status_ok();
+        server
+            .post("/api/refresh")
+            .json(&json!({ "refresh_token": other_tokens.refresh_token }))
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn revoking_the_current_session_invalidates_its_tokens() {
+        let mut state = state();
+        let session_tokens = open_session(&mut state, "user").await;
+        let session_id = verify_access_token(&mut state, &session_tokens)
+            .await
+            .unwrap()
+            .session_id;
+        let server = TestServer::new(state.routes()).unwrap();
+
+        let sessions = server
+            .get("/api/sessions")
+            .add_header(AUTHORIZATION, bearer(&session_tokens))
+            .await
+            .json::<Value>();
+        assert_eq!(sessions[0]["id"], session_id.to_string());
+        assert_eq!(sessions[0]["current"], true);
+
+        server
+            .delete(&format!("/api/sessions/{session_id}"))
+            .add_header(AUTHORIZATION, bearer(&session_tokens))
+            .await
+            .assert_status(StatusCode::NO_CONTENT);
+
+        server
+            .post("/api/refresh")
+            .json(&json!({ "refresh_token": session_tokens.refresh_token }))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        // the access tokens of unknown sessions are rejected like the malformed ones
+        server
+            .get("/api/users/me")
+            .add_header(AUTHORIZATION, bearer(&session_tokens))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+    }
 }
@@ -0,0 +1,90 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+};
+
+use axum::async_trait;
+
+use crate::{
+    error::BoxError,
+    syn::{arc_mutex_new, ArcMutex},
+};
+
+use super::{AuditEvent, AuditQuery, AuditSink};
+
+/// Appends the events to a JSON Lines file, one event per line. The file is only ever appended
+/// to, so it can be shipped or rotated by external tools.
+pub struct FileAuditSink {
+    path: PathBuf,
+    // keeps the lines of concurrent appends from interleaving
+    lock: ArcMutex<()>,
+}
+
+impl FileAuditSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: arc_mutex_new(()),
+        }
+    }
+}
+
+#[async_trait]
+impl AuditSink for FileAuditSink {
+    async fn append(&self, events: Vec<AuditEvent>) -> Result<(), BoxError> {
+        let mut content = String::new();
+        for event in &events {
+            content.push_str(&serde_json::to_string(event)?);
+            content.push('\n');
+        }
+
+        let path = self.path.clone();
+        let lock = self.lock.clone();
+        tokio::task::spawn_blocking(move || {
+            let _guard = lock.lock();
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(content.as_bytes()))
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    /// Scans the whole file, meant for occasional queries of moderately sized files
+    async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>, BoxError> {
+        let path = self.path.clone();
+        let lock = self.lock.clone();
+        let query = query.clone();
+        tokio::task::spawn_blocking(move || -> Result<Vec<AuditEvent>, BoxError> {
+            let _guard = lock.lock();
+            let file = match std::fs::File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut events = Vec::new();
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let event: AuditEvent = serde_json::from_str(&line)?;
+                if query.matches(&event) {
+                    events.push(event);
+                }
+            }
+
+            Ok(events
+                .into_iter()
+                .rev()
+                .skip(query.offset)
+                .take(query.limit)
+                .collect())
+        })
+        .await?
+    }
+}
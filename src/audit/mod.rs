@@ -0,0 +1,299 @@
+//! Structured, append-only trail of the security-relevant events. Events are recorded with the
+//! [`AuditLog`] of the `AppState` and written into its [`AuditSink`] by a background task.
+
+mod file;
+
+pub use file::FileAuditSink;
+
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::async_trait;
+use serde_json::Value;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot,
+};
+use uuid::Uuid;
+
+use crate::{
+    error::BoxError,
+    timestamp::{self, UnixTimestamp},
+};
+
+/// Where the audit events are written
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditSinkKind {
+    /// the store of the application, i.e., the database
+    Store,
+    /// an append-only JSON Lines file
+    File,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventKind {
+    LoginSucceeded,
+    LoginFailed,
+    LoginThrottled,
+    Logout,
+    /// a used refresh token was presented again, its session was revoked
+    RefreshTokenReused,
+    /// a request was rejected because the user lacks a role or a permission
+    AccessDenied,
+    SeenUsersRead,
+    UserRegistered,
+    UserCreated,
+    UserUpdated,
+    UserDeleted,
+    /// an admin lifted the login throttling of the user
+    LoginUnlocked,
+    PasswordChanged,
+    PasswordReset,
+    EmailChanged,
+    TotpEnabled,
+    TotpDisabled,
+    /// an admin removed the second factor of the user
+    TotpReset,
+    InviteCreated,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    ImpersonationStarted,
+    ImpersonationEnded,
+    SigningKeyRotated,
+    ConfigReloaded,
+    /// an access token was created with the `token mint` command
+    TokenMinted,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AuditEvent {
+    pub id: Uuid,
+    pub timestamp: UnixTimestamp,
+    pub kind: AuditEventKind,
+    /// the user the event is about
+    pub loginname: Option<String>,
+    /// who caused the event if it is not the user, e.g., an admin
+    pub actor: Option<String>,
+    pub ip: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub details: Value,
+}
+
+impl AuditEvent {
+    pub fn new(kind: AuditEventKind) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            timestamp: timestamp::now(),
+            kind,
+            loginname: None,
+            actor: None,
+            ip: None,
+            details: Value::Null,
+        }
+    }
+
+    pub fn loginname(mut self, loginname: impl Into<String>) -> Self {
+        self.loginname = Some(loginname.into());
+        self
+    }
+
+    pub fn actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    pub fn ip(mut self, ip: Option<IpAddr>) -> Self {
+        self.ip = ip;
+        self
+    }
+
+    pub fn details(mut self, details: Value) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+/// Every set field has to match
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    /// matches the events about the user and the ones caused by the user
+    pub loginname: Option<String>,
+    pub kind: Option<AuditEventKind>,
+    /// inclusive
+    pub since: Option<UnixTimestamp>,
+    /// exclusive
+    pub until: Option<UnixTimestamp>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        self.loginname.as_ref().is_none_or(|loginname| {
+            event.loginname.as_ref() == Some(loginname) || event.actor.as_ref() == Some(loginname)
+        }) && self.kind.is_none_or(|kind| event.kind == kind)
+            && self.since.is_none_or(|since| event.timestamp >= since)
+            && self.until.is_none_or(|until| event.timestamp < until)
+    }
+}
+
+#[async_trait]
+pub trait AuditSink: Send + Sync {
+    /// Appends the events in the given order. The recorded events are never modified or deleted.
+    async fn append(&self, events: Vec<AuditEvent>) -> Result<(), BoxError>;
+
+    /// Returns the matching events, the newest first
+    async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>, BoxError>;
+}
+
+/// How many events can wait for the writer, the events recorded while the queue is full are
+/// dropped, so a slow sink can not make the requests wait or exhaust the memory
+const QUEUE_CAPACITY: usize = 4096;
+
+enum WriterMessage {
+    Event(AuditEvent),
+    /// answered when the events recorded before are written
+    Flush(oneshot::Sender<()>),
+}
+
+/// Records the events into the sink it was started with. The events are written by a background
+/// task, so recording never blocks or fails a request.
+#[derive(Clone)]
+pub struct AuditLog {
+    sink: Arc<dyn AuditSink>,
+    sender: mpsc::Sender<WriterMessage>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AuditLog {
+    /// Starts the task that writes the recorded events into the sink. The events waiting in the
+    /// queue are written in one batch.
+    pub fn start(sink: Arc<dyn AuditSink>) -> Self {
+        let (sender, mut receiver) = mpsc::channel(QUEUE_CAPACITY);
+
+        let writer_sink = sink.clone();
+        tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                let mut events = Vec::new();
+                let mut flushes = Vec::new();
+                for message in
+                    std::iter::once(message).chain(std::iter::from_fn(|| receiver.try_recv().ok()))
+                {
+                    match message {
+                        WriterMessage::Event(event) => events.push(event),
+                        WriterMessage::Flush(flushed) => flushes.push(flushed),
+                    }
+                }
+
+                if !events.is_empty() {
+                    if let Err(e) = writer_sink.append(events.clone()).await {
+                        // the events are logged, so they are not lost entirely
+                        log::error!(
+                            "audit writer, append, error = {e}, events = {}",
+                            serde_json::to_string(&events).unwrap_or_default()
+                        );
+                    }
+                }
+
+                for flushed in flushes {
+                    let _ = flushed.send(());
+                }
+            }
+        });
+
+        Self {
+            sink,
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Queues the event for the writer, it is dropped and counted if the queue is full
+    pub fn record(&self, event: AuditEvent) {
+        match self.sender.try_send(WriterMessage::Event(event)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(WriterMessage::Event(event))) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                // the events are logged, so they are not lost entirely
+                log::error!(
+                    "Audit event dropped, the queue is full, dropped = {dropped}, event = {}",
+                    serde_json::to_string(&event).unwrap_or_default()
+                );
+            }
+            Err(_) => log::error!("AuditLog::record, the writer is stopped"),
+        }
+    }
+
+    /// How many events were dropped because the queue was full
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn is_writer_running(&self) -> bool {
+        !self.sender.is_closed()
+    }
+
+    /// Waits until the events recorded so far are written, e.g., before the application exits
+    pub async fn flush(&self) {
+        let (flushed, flushed_receiver) = oneshot::channel();
+        if self
+            .sender
+            .send(WriterMessage::Flush(flushed))
+            .await
+            .is_ok()
+        {
+            let _ = flushed_receiver.await;
+        }
+    }
+
+    /// Returns the matching events, the newest first
+    pub async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>, BoxError> {
+        self.sink.query(query).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Never finishes an append, so the queue fills up
+    struct StuckSink;
+
+    #[async_trait]
+    impl AuditSink for StuckSink {
+        async fn append(&self, _events: Vec<AuditEvent>) -> Result<(), BoxError> {
+            std::future::pending().await
+        }
+
+        async fn query(&self, _query: &AuditQuery) -> Result<Vec<AuditEvent>, BoxError> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[tokio::test]
+    async fn events_are_dropped_and_counted_when_the_queue_is_full() {
+        let audit_log = AuditLog::start(Arc::new(StuckSink));
+
+        // the writer takes the first event and gets stuck appending it
+        audit_log.record(AuditEvent::new(AuditEventKind::LoginSucceeded));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        for _ in 0..QUEUE_CAPACITY + 3 {
+            audit_log.record(AuditEvent::new(AuditEventKind::LoginSucceeded));
+        }
+
+        assert_eq!(audit_log.dropped_count(), 3);
+        assert!(audit_log.is_writer_running());
+    }
+}
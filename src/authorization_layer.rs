@@ -0,0 +1,210 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, OriginalUri, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use axum_helpers::auth::LoginInfoExtractor;
+use serde_json::json;
+use tower::{Layer, Service};
+
+use crate::{
+    app_state::AppState,
+    audit::{AuditEvent, AuditEventKind},
+    model::login_info::LoginInfo,
+    permissions::PermissionPolicy,
+};
+
+/// What a request has to carry to get through an [`AuthorizationLayer`].
+#[derive(Debug, Clone)]
+pub enum AccessPolicy {
+    /// the role itself or a role inheriting from it. The routes of the template require
+    /// permissions only, the role policy is for the applications built on it.
+    #[allow(dead_code)]
+    Role(String),
+    Permission(String),
+}
+
+impl AccessPolicy {
+    fn allows(&self, login_info: &LoginInfo, permission_policy: &PermissionPolicy) -> bool {
+        match self {
+            AccessPolicy::Role(role) => permission_policy
+                .resolve_roles(&login_info.roles)
+                .contains(role),
+            AccessPolicy::Permission(permission) => login_info.permissions.contains(permission),
+        }
+    }
+}
+
+/// Enforces an [`AccessPolicy`] on every route it is attached to. Anonymous requests are
+/// rejected with 401, authenticated ones without the required rights with 403.
+///
+/// It relies on the login info put into the request by `AuthLayer`, so it has to be added with
+/// `route_layer` *inside* the `AuthLayer`, e.g. on a nested router or a single method router.
+/// The denied requests are recorded into the audit log of the state.
+#[derive(Clone)]
+pub struct AuthorizationLayer {
+    state: AppState,
+    policy: Arc<AccessPolicy>,
+}
+
+impl AuthorizationLayer {
+    pub fn new(state: &AppState, policy: AccessPolicy) -> Self {
+        Self {
+            state: state.clone(),
+            policy: Arc::new(policy),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn require_role(state: &AppState, role: impl Into<String>) -> Self {
+        Self::new(state, AccessPolicy::Role(role.into()))
+    }
+
+    pub fn require_permission(state: &AppState, permission: impl Into<String>) -> Self {
+        Self::new(state, AccessPolicy::Permission(permission.into()))
+    }
+}
+
+impl<S> Layer<S> for AuthorizationLayer {
+    type Service = Authorization<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Authorization {
+            inner,
+            state: self.state.clone(),
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Authorization<S> {
+    inner: S,
+    state: AppState,
+    policy: Arc<AccessPolicy>,
+}
+
+impl<S> Service<Request> for Authorization<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // the clone is not necessarily ready, so keep the service that was polled and leave the
+        // clone in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let state = self.state.clone();
+        let policy = self.policy.clone();
+
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+
+            let login_info =
+                match LoginInfoExtractor::<LoginInfo>::from_request_parts(&mut parts, &()).await {
+                    Ok(LoginInfoExtractor(login_info)) => login_info,
+                    Err(_) => return Ok(StatusCode::UNAUTHORIZED.into_response()),
+                };
+
+            if !policy.allows(&login_info, state.permission_policy()) {
+                // nested routers strip their prefix from the uri
+                let path = parts
+                    .extensions
+                    .get::<OriginalUri>()
+                    .map_or_else(|| parts.uri.path(), |uri| uri.path());
+                log::info!(
+                    "Access denied, loginname = '{}', impersonator = '{}', path = '{}', policy = {:?}",
+                    login_info.loginname,
+                    login_info.impersonator.as_deref().unwrap_or_default(),
+                    path,
+                    policy,
+                );
+
+                let mut event = AuditEvent::new(AuditEventKind::AccessDenied)
+                    .loginname(&login_info.loginname)
+                    .ip(parts
+                        .extensions
+                        .get::<ConnectInfo<SocketAddr>>()
+                        .map(|ConnectInfo(addr)| addr.ip()))
+                    .details(json!({ "path": path, "policy": format!("{policy:?}") }));
+                if let Some(impersonator) = &login_info.impersonator {
+                    event = event.actor(impersonator);
+                }
+                state.audit().record(event);
+
+                return Ok(StatusCode::FORBIDDEN.into_response());
+            }
+
+            inner.call(Request::from_parts(parts, body)).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use serde_json::Map;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::permissions;
+
+    fn login_info(role: &str, permission_policy: &PermissionPolicy) -> LoginInfo {
+        let roles = BTreeSet::from([role.to_string()]);
+        LoginInfo {
+            loginname: "user".to_string(),
+            permissions: permission_policy.resolve_permissions(&roles),
+            roles,
+            session_id: Uuid::new_v4(),
+            api_key_id: None,
+            impersonator: None,
+            custom_claims: Map::new(),
+        }
+    }
+
+    #[test]
+    fn role_policy_accepts_the_roles_inheriting_from_the_role() {
+        let permission_policy = PermissionPolicy::default();
+        let policy = AccessPolicy::Role(permissions::DEFAULT_ROLE.to_string());
+
+        assert!(policy.allows(
+            &login_info(permissions::DEFAULT_ROLE, &permission_policy),
+            &permission_policy
+        ));
+        // admin inherits from the default role
+        assert!(policy.allows(&login_info("admin", &permission_policy), &permission_policy));
+        assert!(!AccessPolicy::Role("admin".to_string()).allows(
+            &login_info(permissions::DEFAULT_ROLE, &permission_policy),
+            &permission_policy
+        ));
+    }
+
+    #[test]
+    fn permission_policy_accepts_any_role_granting_the_permission() {
+        let permission_policy = PermissionPolicy::default();
+        let policy = AccessPolicy::Permission(permissions::USERS_READ.to_string());
+
+        assert!(policy.allows(&login_info("admin", &permission_policy), &permission_policy));
+        assert!(!policy.allows(
+            &login_info(permissions::DEFAULT_ROLE, &permission_policy),
+            &permission_policy
+        ));
+    }
+}
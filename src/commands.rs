@@ -0,0 +1,163 @@
+//! The operations commands besides `serve`, they work on the same `AppState` as the server
+
+use std::io::BufRead;
+
+use crate::{
+    app_state::AppState,
+    cli::{Cli, TokenCommand, UserCommand},
+    config::Config,
+    endpoints::route_table::ROUTES,
+    error::{BoxError, UserError},
+};
+
+pub fn print_routes() {
+    let method_width = ROUTES
+        .iter()
+        .map(|route| route.method.len())
+        .max()
+        .unwrap_or_default();
+    let path_width = ROUTES
+        .iter()
+        .map(|route| route.path.len())
+        .max()
+        .unwrap_or_default();
+
+    for route in ROUTES {
+        println!(
+            "{:method_width$}  {:path_width$}  {}",
+            route.method, route.path, route.description
+        );
+    }
+}
+
+/// Checks what the server would check at startup without starting it, and prints the effective
+/// settings as TOML. The database is not opened, it would be created if it did not exist.
+pub fn check_config(config: &Config, cli: &Cli) -> Result<(), BoxError> {
+    if config.server.listener_address.is_none() {
+        return Err(
+            "server.listener_address is not set (--listener-address, APP_LISTENER_ADDRESS or the configuration file)"
+                .into(),
+        );
+    }
+
+    crate::load_signing_keys(config, cli)?;
+    crate::create_mailer(config, cli)?;
+    crate::create_oidc_client(config, cli, &config.permissions)?;
+
+    print!("{}", toml::to_string(config)?);
+    eprintln!("The configuration is valid");
+
+    Ok(())
+}
+
+pub async fn run_user_command(state: &AppState, user_command: UserCommand) -> Result<(), BoxError> {
+    match user_command {
+        UserCommand::Add { loginname, roles } => {
+            let password = read_password()?;
+
+            let login_info = state
+                .create_user(None, &loginname, &password, roles.into_iter().collect())
+                .await
+                .map_err(user_error)?;
+
+            println!("User '{}' created", login_info.loginname);
+        }
+        UserCommand::List { offset, limit } => {
+            let users = state.store.list_users(offset, limit).await?;
+
+            let loginname_width = users
+                .iter()
+                .map(|user| user.loginname.len())
+                .chain(["LOGINNAME".len()])
+                .max()
+                .unwrap_or_default();
+            println!("{:loginname_width$}  DISABLED  ROLES", "LOGINNAME");
+            for user in users {
+                println!(
+                    "{:loginname_width$}  {:8}  {}",
+                    user.loginname,
+                    if user.disabled { "yes" } else { "no" },
+                    user.roles.into_iter().collect::<Vec<_>>().join(",")
+                );
+            }
+        }
+        UserCommand::SetRole { loginname, roles } => {
+            let login_info = state
+                .update_user(
+                    None,
+                    &loginname,
+                    Some(roles.into_iter().collect()),
+                    None,
+                    None,
+                )
+                .await
+                .map_err(user_error)?;
+
+            println!("Roles of user '{}' set", login_info.loginname);
+        }
+        UserCommand::Disable { loginname } => {
+            set_disabled(state, &loginname, true).await?;
+            println!("User '{loginname}' disabled");
+        }
+        UserCommand::Enable { loginname } => {
+            set_disabled(state, &loginname, false).await?;
+            println!("User '{loginname}' enabled");
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn run_token_command(
+    state: &AppState,
+    token_command: TokenCommand,
+) -> Result<(), BoxError> {
+    match token_command {
+        TokenCommand::Mint { loginname } => {
+            let (access_token, expires_at) = state
+                .mint_access_token(&loginname)
+                .await
+                .map_err(user_error)?;
+
+            println!("{access_token}");
+            eprintln!("The token expires at {expires_at} (unix time)");
+        }
+    }
+
+    Ok(())
+}
+
+async fn set_disabled(state: &AppState, loginname: &str, disabled: bool) -> Result<(), BoxError> {
+    state
+        .update_user(None, loginname, None, Some(disabled), None)
+        .await
+        .map_err(user_error)?;
+
+    Ok(())
+}
+
+/// Reads the first line of the standard input, so the password does not show up in the process
+/// list or the shell history
+fn read_password() -> Result<String, BoxError> {
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err("no password given, write it to the standard input".into());
+    }
+
+    Ok(password.to_string())
+}
+
+fn user_error(e: UserError) -> BoxError {
+    match e {
+        UserError::Invalid(reason) | UserError::NotAllowed(reason) => reason.into(),
+        UserError::AlreadyExists => "the user already exists".into(),
+        UserError::NotFound => "the user does not exist".into(),
+        UserError::WrongPassword => "wrong password".into(),
+        UserError::RegistrationNotAllowed => "registration is not allowed".into(),
+        UserError::InvalidToken => "the token is invalid, expired or already used".into(),
+        UserError::Internal => "internal error, see the log".into(),
+    }
+}
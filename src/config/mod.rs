@@ -0,0 +1,749 @@
+//! The settings of the application. They are layered, a later layer overrides the earlier ones:
+//! the defaults, the TOML configuration file, the `APP_*` environment variables and the command
+//! line flags. The secrets are not part of the configuration, they are read where they are used.
+//!
+//! The configuration is reloaded when the file changes or on SIGHUP, see `watch`. The settings in
+//! `RESTART_REQUIRED` are only read at startup, a reload changing them is rejected.
+//!
+//! ```toml
+//! [server]
+//! listener_address = "127.0.0.1:8080"
+//! request_timeout_secs = 30
+//! cors_allowed_origins = ["https://app.example.com"]
+//! secure_cookies = true
+//!
+//! [log]
+//! level = "debug"
+//!
+//! [auth]
+//! access_token_lifetime_secs = 300
+//! registration_mode = "open"
+//!
+//! [[auth.signing_keys]]
+//! kid = "2026-10"
+//! file = "keys/2026-10.secret"
+//!
+//! [[auth.signing_keys]]
+//! kid = "2026-09"
+//! file = "keys/2026-09.secret"
+//!
+//! [oidc]
+//! issuer_url = "https://accounts.example.com"
+//! client_id = "app"
+//! redirect_url = "https://app.example.com/api/oidc/callback"
+//! roles_claim = "groups"
+//! role_mapping = { "app-admins" = ["admin"] }
+//!
+//! [permissions.regular]
+//! permissions = []
+//!
+//! [permissions.admin]
+//! permissions = ["users:read", "users:write"]
+//! inherits = ["regular"]
+//! ```
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+pub mod watch;
+
+use crate::{
+    access_token::AccessTokenConfig, app_state::RegistrationMode, audit::AuditSinkKind, cli::Cli,
+    error::BoxError, keyring::SigningAlgorithm, mailer::MailerKind, permissions::PermissionPolicy,
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub log: LogConfig,
+    pub auth: AuthConfig,
+    pub database: DatabaseConfig,
+    pub audit: AuditConfig,
+    pub mail: MailConfig,
+    pub oidc: OidcProviderConfig,
+    /// replaces the built-in roles as a whole if set
+    pub permissions: PermissionPolicy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// where the server accepts the connections, e.g., 127.0.0.1:8080
+    pub listener_address: Option<String>,
+    pub body_limit_bytes: usize,
+    pub request_timeout_secs: u64,
+    /// how long a shutdown waits for the running requests before it cuts them
+    pub shutdown_timeout_secs: u64,
+    /// served under `/public`
+    pub static_dir: PathBuf,
+    /// the origins of the browser apps allowed to call the API, '*' allows every origin
+    pub cors_allowed_origins: Vec<String>,
+    /// marks the cookies Secure, the browsers send them over HTTPS only. Set it when the
+    /// application is served over HTTPS, e.g., behind a TLS terminating proxy.
+    pub secure_cookies: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listener_address: None,
+            body_limit_bytes: 2 * 1024 * 1024,
+            request_timeout_secs: 30,
+            shutdown_timeout_secs: 30,
+            static_dir: "public".into(),
+            cors_allowed_origins: Vec::new(),
+            secure_cookies: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: LogLevel,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub access_token_lifetime_secs: u64,
+    pub refresh_token_lifetime_secs: u64,
+    pub jwt_issuer: String,
+    pub jwt_audience: String,
+    pub jwt_leeway_secs: u64,
+    pub jwt_algorithm: SigningAlgorithm,
+    pub jwt_private_key_file: Option<PathBuf>,
+    pub jwt_secret_file: Option<PathBuf>,
+    pub registration_mode: RegistrationMode,
+    pub login_max_failures: u32,
+    pub login_lockout_secs: u64,
+    /// The keys of the `jwt_algorithm`, the first one signs, the others only verify. A reload
+    /// applies the changed keys, a key removed from the list is accepted until its tokens
+    /// expire. Without keys the key of `jwt_secret_file` or `jwt_private_key_file` is used.
+    pub signing_keys: Vec<SigningKeyConfig>,
+}
+
+/// A JWT signing key file, a base64 encoded secret for HS512 or a PEM private key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningKeyConfig {
+    /// put into the header of the signed tokens
+    pub kid: String,
+    pub file: PathBuf,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            access_token_lifetime_secs: 60,
+            refresh_token_lifetime_secs: 14 * 24 * 60 * 60,
+            jwt_issuer: "axum-app-template".into(),
+            jwt_audience: "axum-app-template".into(),
+            jwt_leeway_secs: 30,
+            jwt_algorithm: SigningAlgorithm::Hs512,
+            jwt_private_key_file: None,
+            jwt_secret_file: None,
+            registration_mode: RegistrationMode::InviteOnly,
+            login_max_failures: 5,
+            login_lockout_secs: 15 * 60,
+            signing_keys: Vec::new(),
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn access_token_config(&self) -> AccessTokenConfig {
+        AccessTokenConfig {
+            lifetime: Duration::from_secs(self.access_token_lifetime_secs),
+            issuer: self.jwt_issuer.clone(),
+            audience: self.jwt_audience.clone(),
+            leeway: Duration::from_secs(self.jwt_leeway_secs),
+        }
+    }
+
+    pub fn refresh_token_lifetime(&self) -> Duration {
+        Duration::from_secs(self.refresh_token_lifetime_secs)
+    }
+
+    pub fn login_lockout(&self) -> Duration {
+        Duration::from_secs(self.login_lockout_secs)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// the SQLite database file, ':memory:' opens an in-memory database, without it the data is
+    /// only kept in memory without SQLite
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub sink: AuditSinkKind,
+    /// used if the sink is 'file'
+    pub file: PathBuf,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            sink: AuditSinkKind::Store,
+            file: "audit.jsonl".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub mailer: MailerKind,
+    /// used if the mailer is 'file'
+    pub file: PathBuf,
+    /// the sender address of the mails sent through SMTP
+    pub from: String,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            mailer: MailerKind::Log,
+            file: "mails.txt".into(),
+            from: "axum-app-template <noreply@localhost>".into(),
+        }
+    }
+}
+
+/// The OpenID Connect login is enabled if the issuer is set. The client secret is not part of
+/// the configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcProviderConfig {
+    pub issuer_url: Option<String>,
+    pub client_id: Option<String>,
+    pub redirect_url: Option<String>,
+    pub scopes: Vec<String>,
+    pub username_claim: String,
+    pub roles_claim: Option<String>,
+    /// the values of the roles claim mapped to roles
+    pub role_mapping: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Default for OidcProviderConfig {
+    fn default() -> Self {
+        Self {
+            issuer_url: None,
+            client_id: None,
+            redirect_url: None,
+            scopes: vec!["openid".into(), "profile".into(), "email".into()],
+            username_claim: "preferred_username".into(),
+            roles_claim: None,
+            role_mapping: BTreeMap::new(),
+        }
+    }
+}
+
+/// The settings (or sections) that are only read at startup
+pub const RESTART_REQUIRED: [&str; 12] = [
+    "server.listener_address",
+    "server.body_limit_bytes",
+    "server.request_timeout_secs",
+    "server.static_dir",
+    "auth.jwt_algorithm",
+    "auth.jwt_private_key_file",
+    "auth.jwt_secret_file",
+    "database",
+    "audit",
+    "mail",
+    "oidc",
+    "permissions",
+];
+
+/// A setting that differs between two configurations
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigChange {
+    /// e.g., `log.level`
+    pub field: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+impl ConfigChange {
+    pub fn requires_restart(&self) -> bool {
+        RESTART_REQUIRED.iter().any(|restart_required| {
+            self.field == *restart_required
+                || self
+                    .field
+                    .strip_prefix(restart_required)
+                    .is_some_and(|rest| rest.starts_with('.'))
+        })
+    }
+}
+
+impl Config {
+    /// Reads the configuration file given by `--config` (or `APP_CONFIG`) if any, applies the
+    /// environment variables and the flags, then validates the result
+    pub fn load(cli: &Cli) -> Result<Self, BoxError> {
+        let mut config = match &cli.config {
+            Some(path) => Self::read_file(path)?,
+            None => Self::default(),
+        };
+
+        config.apply_cli(cli)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn read_file(path: &Path) -> Result<Self, BoxError> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            format!(
+                "could not read the configuration file '{}': {e}",
+                path.display()
+            )
+        })?;
+
+        toml::from_str(&content).map_err(|e| {
+            format!(
+                "could not parse the configuration file '{}': {e}",
+                path.display()
+            )
+            .into()
+        })
+    }
+
+    /// The environment variables are resolved by clap, a flag takes precedence over its variable
+    fn apply_cli(&mut self, cli: &Cli) -> Result<(), BoxError> {
+        override_with_some(&mut self.server.listener_address, &cli.listener_address);
+        override_with(&mut self.server.body_limit_bytes, &cli.body_limit_bytes);
+        override_with(
+            &mut self.server.request_timeout_secs,
+            &cli.request_timeout_secs,
+        );
+        override_with(
+            &mut self.server.shutdown_timeout_secs,
+            &cli.shutdown_timeout_secs,
+        );
+        override_with(&mut self.server.static_dir, &cli.static_dir);
+        override_with(
+            &mut self.server.cors_allowed_origins,
+            &cli.cors_allowed_origins,
+        );
+        override_with(&mut self.server.secure_cookies, &cli.secure_cookies);
+
+        override_with(&mut self.log.level, &cli.log_level);
+
+        let auth = &mut self.auth;
+        override_with(
+            &mut auth.access_token_lifetime_secs,
+            &cli.access_token_lifetime_secs,
+        );
+        override_with(
+            &mut auth.refresh_token_lifetime_secs,
+            &cli.refresh_token_lifetime_secs,
+        );
+        override_with(&mut auth.jwt_issuer, &cli.jwt_issuer);
+        override_with(&mut auth.jwt_audience, &cli.jwt_audience);
+        override_with(&mut auth.jwt_leeway_secs, &cli.jwt_leeway_secs);
+        override_with(&mut auth.jwt_algorithm, &cli.jwt_algorithm);
+        override_with_some(&mut auth.jwt_private_key_file, &cli.jwt_private_key_file);
+        if cli.jwt_secret.is_some() && cli.jwt_secret_file.is_some() {
+            return Err("APP_JWT_SECRET and --jwt-secret-file can not be given together".into());
+        }
+        // the secret itself is not part of the configuration, it replaces the file of the layer
+        // below like the file would
+        if cli.jwt_secret.is_some() {
+            auth.jwt_secret_file = None;
+        }
+        override_with_some(&mut auth.jwt_secret_file, &cli.jwt_secret_file);
+        override_with(&mut auth.registration_mode, &cli.registration_mode);
+        override_with(&mut auth.login_max_failures, &cli.login_max_failures);
+        override_with(&mut auth.login_lockout_secs, &cli.login_lockout_secs);
+
+        override_with_some(&mut self.database.path, &cli.database);
+
+        override_with(&mut self.audit.sink, &cli.audit_sink);
+        override_with(&mut self.audit.file, &cli.audit_file);
+
+        override_with(&mut self.mail.mailer, &cli.mailer);
+        override_with(&mut self.mail.file, &cli.mail_file);
+        override_with(&mut self.mail.from, &cli.mail_from);
+
+        let oidc = &mut self.oidc;
+        override_with_some(&mut oidc.issuer_url, &cli.oidc_issuer_url);
+        override_with_some(&mut oidc.client_id, &cli.oidc_client_id);
+        override_with_some(&mut oidc.redirect_url, &cli.oidc_redirect_url);
+        override_with(&mut oidc.scopes, &cli.oidc_scopes);
+        override_with(&mut oidc.username_claim, &cli.oidc_username_claim);
+        override_with_some(&mut oidc.roles_claim, &cli.oidc_roles_claim);
+
+        // the mappings given as flags replace the ones of the file
+        if let Some(role_mapping_args) = &cli.oidc_role_mappings {
+            oidc.role_mapping.clear();
+            for role_mapping_arg in role_mapping_args {
+                let (value, role) = role_mapping_arg.split_once('=').ok_or_else(|| {
+                    format!("invalid OIDC role mapping '{role_mapping_arg}', expected VALUE=ROLE")
+                })?;
+                oidc.role_mapping
+                    .entry(value.to_string())
+                    .or_default()
+                    .insert(role.to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the settings that can be checked without opening anything, every problem is
+    /// reported at once
+    pub fn validate(&self) -> Result<(), BoxError> {
+        let mut problems = Vec::new();
+
+        // required by the server only, not by the other commands
+        if let Some(listener_address) = &self.server.listener_address {
+            if let Err(e) = listener_address.to_socket_addrs() {
+                problems.push(format!(
+                    "server.listener_address '{listener_address}' is invalid: {e}"
+                ));
+            }
+        }
+        if self.server.body_limit_bytes == 0 {
+            problems.push("server.body_limit_bytes has to be positive".to_string());
+        }
+        if self.server.request_timeout_secs == 0 {
+            problems.push("server.request_timeout_secs has to be positive".to_string());
+        }
+        for origin in &self.server.cors_allowed_origins {
+            // an origin is a scheme, a host and an optional port, without a path
+            let valid = origin == "*"
+                || url::Url::parse(origin)
+                    .is_ok_and(|url| url.origin().ascii_serialization() == *origin);
+            if !valid {
+                problems.push(format!(
+                    "server.cors_allowed_origins contains an invalid origin '{origin}', expected '*' or e.g. 'https://app.example.com'"
+                ));
+            }
+        }
+
+        let auth = &self.auth;
+        if auth.access_token_lifetime_secs == 0 {
+            problems.push("auth.access_token_lifetime_secs has to be positive".to_string());
+        }
+        if auth.refresh_token_lifetime_secs <= auth.access_token_lifetime_secs {
+            problems.push(
+                "auth.refresh_token_lifetime_secs has to be longer than auth.access_token_lifetime_secs"
+                    .to_string(),
+            );
+        }
+        if auth.jwt_issuer.is_empty() {
+            problems.push("auth.jwt_issuer can not be empty".to_string());
+        }
+        if auth.jwt_audience.is_empty() {
+            problems.push("auth.jwt_audience can not be empty".to_string());
+        }
+        if auth.jwt_algorithm.is_symmetric() && auth.jwt_private_key_file.is_some() {
+            problems.push(
+                "auth.jwt_private_key_file is only used by the asymmetric algorithms, use auth.jwt_secret_file for HS512"
+                    .to_string(),
+            );
+        }
+        if !auth.jwt_algorithm.is_symmetric() && auth.jwt_secret_file.is_some() {
+            problems.push(
+                "auth.jwt_secret_file is only used by HS512, use auth.jwt_private_key_file for the asymmetric algorithms"
+                    .to_string(),
+            );
+        }
+        if !auth.signing_keys.is_empty()
+            && (auth.jwt_private_key_file.is_some() || auth.jwt_secret_file.is_some())
+        {
+            problems.push(
+                "auth.signing_keys replaces auth.jwt_private_key_file and auth.jwt_secret_file, they can not be set together"
+                    .to_string(),
+            );
+        }
+        let mut kids = BTreeSet::new();
+        for signing_key in &auth.signing_keys {
+            if signing_key.kid.is_empty() {
+                problems.push("auth.signing_keys contains a key without a kid".to_string());
+            } else if !kids.insert(&signing_key.kid) {
+                problems.push(format!(
+                    "auth.signing_keys contains the kid '{}' more than once",
+                    signing_key.kid
+                ));
+            }
+        }
+        if auth.login_max_failures == 0 {
+            problems.push("auth.login_max_failures has to be positive".to_string());
+        }
+
+        let oidc = &self.oidc;
+        if oidc.issuer_url.is_some() {
+            if oidc.client_id.is_none() {
+                problems.push("oidc.client_id is required if oidc.issuer_url is set".to_string());
+            }
+            if oidc.redirect_url.is_none() {
+                problems
+                    .push("oidc.redirect_url is required if oidc.issuer_url is set".to_string());
+            }
+        }
+        if !oidc.role_mapping.is_empty() && oidc.roles_claim.is_none() {
+            problems.push("oidc.role_mapping is only used if oidc.roles_claim is set".to_string());
+        }
+
+        if let Err(e) = self.permissions.validate() {
+            problems.push(format!("permissions: {e}"));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("invalid configuration:\n  - {}", problems.join("\n  - ")).into())
+        }
+    }
+
+    /// The settings that differ from the ones of the other configuration, ordered by their
+    /// names
+    pub fn changes(&self, other: &Config) -> Vec<ConfigChange> {
+        let mut old_fields = Map::new();
+        let mut new_fields = Map::new();
+        flatten("", to_value(self), &mut old_fields);
+        flatten("", to_value(other), &mut new_fields);
+
+        let mut changes = Vec::new();
+        for (field, old) in &old_fields {
+            let new = new_fields.remove(field).unwrap_or(Value::Null);
+            if *old != new {
+                changes.push(ConfigChange {
+                    field: field.clone(),
+                    old: old.clone(),
+                    new,
+                });
+            }
+        }
+        // the fields missing from the old configuration, e.g., new role mappings
+        for (field, new) in new_fields {
+            changes.push(ConfigChange {
+                field,
+                old: Value::Null,
+                new,
+            });
+        }
+        changes.sort_by(|a, b| a.field.cmp(&b.field));
+
+        changes
+    }
+}
+
+fn to_value(config: &Config) -> Value {
+    serde_json::to_value(config)
+        .inspect_err(|e| log::error!("Config::changes, to_value, error = {e}"))
+        .unwrap_or_default()
+}
+
+/// Collects the leaves of the tables by their dotted paths
+fn flatten(path: &str, value: Value, fields: &mut Map<String, Value>) {
+    match value {
+        Value::Object(table) => {
+            for (key, value) in table {
+                let path = if path.is_empty() {
+                    key
+                } else {
+                    format!("{path}.{key}")
+                };
+                flatten(&path, value, fields);
+            }
+        }
+        value => {
+            fields.insert(path.to_string(), value);
+        }
+    }
+}
+
+fn override_with<T: Clone>(value: &mut T, overriding_value: &Option<T>) {
+    if let Some(overriding_value) = overriding_value {
+        *value = overriding_value.clone();
+    }
+}
+
+fn override_with_some<T: Clone>(value: &mut Option<T>, overriding_value: &Option<T>) {
+    if overriding_value.is_some() {
+        value.clone_from(overriding_value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn the_permissions_section_replaces_the_built_in_roles() {
+        let config: Config = toml::from_str(
+            r#"
+            [permissions.regular]
+
+            [permissions.support]
+            permissions = ["users:read"]
+            inherits = ["regular"]
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        assert!(config.permissions.contains_role("support"));
+        assert!(!config.permissions.contains_role("admin"));
+        assert_eq!(
+            config
+                .permissions
+                .resolve_roles(&BTreeSet::from(["support".to_string()])),
+            BTreeSet::from(["regular".to_string(), "support".to_string()])
+        );
+    }
+
+    #[test]
+    fn the_built_in_roles_are_kept_without_a_permissions_section() {
+        let config: Config = toml::from_str("[log]\nlevel = 'debug'").unwrap();
+
+        assert!(config.permissions.contains_role("admin"));
+    }
+
+    #[test]
+    fn inheriting_from_an_unknown_role_is_invalid() {
+        let config: Config = toml::from_str(
+            r#"
+            [permissions.admin]
+            inherits = ["nobody"]
+            "#,
+        )
+        .unwrap();
+
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("permissions: role 'admin' inherits from unknown role 'nobody'"));
+    }
+
+    #[test]
+    fn the_kids_of_the_signing_keys_are_unique() {
+        let config: Config = toml::from_str(
+            r#"
+            [[auth.signing_keys]]
+            kid = "key"
+            file = "first.secret"
+
+            [[auth.signing_keys]]
+            kid = "key"
+            file = "second.secret"
+            "#,
+        )
+        .unwrap();
+
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("auth.signing_keys contains the kid 'key' more than once"));
+    }
+
+    #[test]
+    fn changing_the_signing_keys_does_not_require_a_restart() {
+        let reloaded: Config = toml::from_str(
+            r#"
+            [[auth.signing_keys]]
+            kid = "key"
+            file = "key.secret"
+            "#,
+        )
+        .unwrap();
+
+        let changes = Config::default().changes(&reloaded);
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].field, "auth.signing_keys");
+        assert!(!changes[0].requires_restart());
+    }
+
+    #[test]
+    fn changing_the_permissions_requires_a_restart() {
+        let reloaded = Config {
+            permissions: toml::from_str("[regular]").unwrap(),
+            ..Config::default()
+        };
+
+        let changes = Config::default().changes(&reloaded);
+
+        assert!(!changes.is_empty());
+        assert!(changes.iter().all(ConfigChange::requires_restart));
+    }
+
+    #[test]
+    fn the_secret_given_at_startup_replaces_the_secret_file_of_the_configuration_file() {
+        let file_config: Config =
+            toml::from_str("[auth]\njwt_secret_file = 'file.secret'").unwrap();
+
+        let mut config = file_config.clone();
+        config
+            .apply_cli(&Cli::parse_from([
+                "app",
+                "--jwt-secret-file",
+                "flag.secret",
+            ]))
+            .unwrap();
+        assert_eq!(
+            config.auth.jwt_secret_file,
+            Some(PathBuf::from("flag.secret"))
+        );
+
+        // the secret of the environment variable
+        let mut cli = Cli::parse_from(["app"]);
+        cli.jwt_secret = Some("c2VjcmV0".into());
+        let mut config = file_config.clone();
+        config.apply_cli(&cli).unwrap();
+        assert_eq!(config.auth.jwt_secret_file, None);
+
+        let mut cli = Cli::parse_from(["app", "--jwt-secret-file", "flag.secret"]);
+        cli.jwt_secret = Some("c2VjcmV0".into());
+        let mut config = file_config;
+        assert!(config.apply_cli(&cli).is_err());
+    }
+}
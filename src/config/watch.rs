@@ -0,0 +1,145 @@
+//! The triggers of the configuration reload: the changes of the configuration file and SIGHUP
+
+use std::{path::Path, time::Duration};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::error::BoxError;
+
+/// Saving a file usually causes several events, the ones within this time cause one reload
+const DEBOUNCE_DURATION: Duration = Duration::from_millis(250);
+
+/// Why the configuration is reloaded
+#[derive(Debug, Clone, Copy)]
+pub enum ReloadTrigger {
+    FileChanged,
+    Hangup,
+}
+
+pub struct ReloadTriggers {
+    receiver: mpsc::Receiver<ReloadTrigger>,
+    /// stops watching when dropped
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl ReloadTriggers {
+    /// Watches the configuration file if there is one, and listens for SIGHUP
+    pub fn new(config_file: Option<&Path>) -> Result<Self, BoxError> {
+        // a pending trigger covers every later one, so one slot is enough
+        let (sender, receiver) = mpsc::channel(1);
+
+        let watcher = config_file
+            .map(|config_file| watch_file(config_file, sender.clone()))
+            .transpose()?;
+
+        #[cfg(unix)]
+        {
+            let mut hangup =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    let _ = sender.try_send(ReloadTrigger::Hangup);
+                }
+            });
+        }
+
+        Ok(Self {
+            receiver,
+            _watcher: watcher,
+        })
+    }
+
+    /// Waits for the next trigger, `None` if there will be none
+    pub async fn next(&mut self) -> Option<ReloadTrigger> {
+        let trigger = self.receiver.recv().await;
+
+        tokio::time::sleep(DEBOUNCE_DURATION).await;
+        while self.receiver.try_recv().is_ok() {}
+
+        trigger
+    }
+}
+
+/// Watches the directory of the file, editors often replace the file instead of writing it, which
+/// would end the watch of the file itself
+fn watch_file(
+    config_file: &Path,
+    sender: mpsc::Sender<ReloadTrigger>,
+) -> Result<RecommendedWatcher, BoxError> {
+    let config_file = std::fs::canonicalize(config_file).map_err(|e| {
+        format!(
+            "could not resolve the configuration file '{}': {e}",
+            config_file.display()
+        )
+    })?;
+    let directory = config_file
+        .parent()
+        .ok_or("the configuration file has no parent directory")?
+        .to_path_buf();
+    let file_name = config_file
+        .file_name()
+        .map(|file_name| file_name.to_owned());
+
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                let config_file_changed = !matches!(event.kind, EventKind::Access(_))
+                    && event
+                        .paths
+                        .iter()
+                        .any(|path| path.file_name() == file_name.as_deref());
+                if config_file_changed {
+                    let _ = sender.try_send(ReloadTrigger::FileChanged);
+                }
+            }
+            Err(e) => log::error!("watch_file, error = {e}"),
+        })?;
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+
+    log::info!(
+        "Watching the configuration file, path = '{}'",
+        config_file.display()
+    );
+
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn saving_the_configuration_file_triggers_a_reload() {
+        let directory = std::env::temp_dir().join(format!("config-{}", Uuid::new_v4()));
+        std::fs::create_dir(&directory).unwrap();
+        let config_file = directory.join("config.toml");
+        std::fs::write(&config_file, "[log]\nlevel = 'info'\n").unwrap();
+        let mut reload_triggers = ReloadTriggers::new(Some(&config_file)).unwrap();
+
+        // other files of the directory are ignored
+        std::fs::write(directory.join("other.toml"), "").unwrap();
+        assert!(
+            tokio::time::timeout(DEBOUNCE_DURATION * 2, reload_triggers.next())
+                .await
+                .is_err()
+        );
+
+        std::fs::write(&config_file, "[log]\nlevel = 'debug'\n").unwrap();
+
+        let trigger = tokio::time::timeout(Duration::from_secs(5), reload_triggers.next())
+            .await
+            .unwrap();
+        assert!(matches!(trigger, Some(ReloadTrigger::FileChanged)));
+        // the events of the save are debounced into the one trigger
+        assert!(
+            tokio::time::timeout(DEBOUNCE_DURATION * 2, reload_triggers.next())
+                .await
+                .is_err()
+        );
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+//! CORS for the browser apps served from other origins. The allowed origins are read from the
+//! current configuration on every request, so a reload changes them immediately.
+//!
+//! Credentials are not allowed, the cross-origin clients authenticate with the `Authorization`
+//! header, the cookies stay protected by the same-origin policy and `csrf`.
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{
+            ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+            ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+            ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+        },
+        HeaderMap, HeaderValue, Method, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::app_state::AppState;
+
+const ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE";
+/// How long the browsers can cache the result of a preflight request
+const PREFLIGHT_MAX_AGE_SECS: &str = "600";
+
+/// Answers the preflight requests of the allowed origins and adds the CORS headers to the other
+/// responses. The requests of other origins pass without CORS headers, the browser blocks them.
+pub async fn handle(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let config = state.config();
+
+    apply(request, next, &config.server.cors_allowed_origins).await
+}
+
+async fn apply(request: Request, next: Next, allowed_origins: &[String]) -> Response {
+    let Some(origin) = request.headers().get(ORIGIN).cloned() else {
+        return next.run(request).await;
+    };
+
+    let allowed = allowed_origins.iter().any(|allowed_origin| {
+        allowed_origin == "*" || allowed_origin.as_bytes() == origin.as_bytes()
+    });
+
+    let preflight = request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+
+    if !allowed {
+        if preflight {
+            log::info!(
+                "CORS preflight of a not allowed origin, origin = '{}'",
+                origin.to_str().unwrap_or_default()
+            );
+        }
+        return next.run(request).await;
+    }
+
+    if preflight {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        insert_allow_origin(headers, origin);
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static(ALLOWED_METHODS),
+        );
+        if let Some(request_headers) = request.headers().get(ACCESS_CONTROL_REQUEST_HEADERS) {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, request_headers.clone());
+        }
+        headers.insert(
+            ACCESS_CONTROL_MAX_AGE,
+            HeaderValue::from_static(PREFLIGHT_MAX_AGE_SECS),
+        );
+        return response;
+    }
+
+    let mut response = next.run(request).await;
+    insert_allow_origin(response.headers_mut(), origin);
+
+    response
+}
+
+fn insert_allow_origin(headers: &mut HeaderMap, origin: HeaderValue) {
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    // the response depends on the origin, caches must not serve it to other origins
+    headers.append(VARY, HeaderValue::from_static("origin"));
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::header::ACCESS_CONTROL_ALLOW_CREDENTIALS, middleware, routing::get, Router};
+    use axum_test::TestServer;
+
+    use super::*;
+
+    fn server(allowed_origins: &[&str]) -> TestServer {
+        let allowed_origins = allowed_origins
+            .iter()
+            .map(|origin| origin.to_string())
+            .collect::<Vec<_>>();
+        let routes = Router::new()
+            .route("/", get(|| async { "ok" }).post(|| async { "ok" }))
+            .layer(middleware::from_fn(move |request, next| {
+                let allowed_origins = allowed_origins.clone();
+                async move { apply(request, next, &allowed_origins).await }
+            }));
+
+        TestServer::new(routes).unwrap()
+    }
+
+    fn preflight(server: &TestServer, origin: &'static str) -> axum_test::TestRequest {
+        server
+            .method(Method::OPTIONS, "/")
+            .add_header(ORIGIN, HeaderValue::from_static(origin))
+            .add_header(
+                ACCESS_CONTROL_REQUEST_METHOD,
+                HeaderValue::from_static("POST"),
+            )
+            .add_header(
+                ACCESS_CONTROL_REQUEST_HEADERS,
+                HeaderValue::from_static("authorization, content-type"),
+            )
+    }
+
+    #[tokio::test]
+    async fn the_responses_to_allowed_origins_carry_the_origin() {
+        let server = server(&["https://app.example.com"]);
+
+        let response = server
+            .get("/")
+            .add_header(ORIGIN, HeaderValue::from_static("https://app.example.com"))
+            .await;
+
+        response.assert_status_ok();
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(response.headers()[VARY], "origin");
+    }
+
+    #[tokio::test]
+    async fn the_responses_to_other_origins_carry_no_cors_headers() {
+        let server = server(&["https://app.example.com"]);
+
+        let response = server
+            .get("/")
+            .add_header(ORIGIN, HeaderValue::from_static("https://evil.example.com"))
+            .await;
+        // the browser blocks the response
+        response.assert_status_ok();
+        assert!(response
+            .headers()
+            .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        // the preflight is not answered, so the browser does not send the request
+        let response = preflight(&server, "https://evil.example.com").await;
+        response.assert_status(StatusCode::METHOD_NOT_ALLOWED);
+        assert!(response
+            .headers()
+            .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        let response = server.get("/").await;
+        assert!(response
+            .headers()
+            .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn the_preflight_requests_of_allowed_origins_are_answered() {
+        let server = server(&["https://app.example.com"]);
+
+        let response = preflight(&server, "https://app.example.com").await;
+
+        response.assert_status(StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], ALLOWED_METHODS);
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_HEADERS],
+            "authorization, content-type"
+        );
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], PREFLIGHT_MAX_AGE_SECS);
+    }
+
+    #[tokio::test]
+    async fn every_origin_is_allowed_by_the_wildcard_but_without_credentials() {
+        let server = server(&["*"]);
+
+        for response in [
+            server
+                .get("/")
+                .add_header(
+                    ORIGIN,
+                    HeaderValue::from_static("https://other.example.com"),
+                )
+                .await,
+            preflight(&server, "https://other.example.com").await,
+        ] {
+            assert_eq!(
+                response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+                "https://other.example.com"
+            );
+            assert!(response
+                .headers()
+                .get(ACCESS_CONTROL_ALLOW_CREDENTIALS)
+                .is_none());
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Double-submit CSRF protection for the requests authenticated by the cookies of the browser.
+//!
+//! Every client gets a random token in the `csrf_token` cookie, the pages render it too. Unsafe
+//! requests (e.g., POST) that carry cookies but no `Authorization` header have to send the token
+//! back in the `X-CSRF-Token` header. Other sites can make the browser send the cookies, but they
+//! can neither read the token nor set the header. Requests with an `Authorization` header are not
+//! checked, the browser never adds that header on its own.
+//!
+//! The cookie is marked Secure if `server.secure_cookies` is set.
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{AUTHORIZATION, COOKIE, SET_COOKIE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{app_state::AppState, opaque_token};
+
+pub const COOKIE_NAME: &str = "csrf_token";
+pub const HEADER_NAME: &str = "x-csrf-token";
+
+/// The token of the client, put into the request extensions for the handlers rendering pages
+#[derive(Clone)]
+pub struct CsrfToken(pub String);
+
+/// Rejects the forged requests with 403 and issues a token to the clients that have none
+pub async fn protect(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let secure_cookies = state.config().server.secure_cookies;
+
+    check(request, next, secure_cookies).await
+}
+
+async fn check(mut request: Request, next: Next, secure_cookies: bool) -> Response {
+    let cookie_token = cookie_value(request.headers(), COOKIE_NAME);
+
+    if !request.method().is_safe()
+        && !request.headers().contains_key(AUTHORIZATION)
+        && request.headers().contains_key(COOKIE)
+    {
+        let header_token = request
+            .headers()
+            .get(HEADER_NAME)
+            .and_then(|value| value.to_str().ok());
+
+        let valid = match (&cookie_token, header_token) {
+            (Some(cookie_token), Some(header_token)) => {
+                opaque_token::constant_time_eq(cookie_token.as_bytes(), header_token.as_bytes())
+            }
+            _ => false,
+        };
+        if !valid {
+            log::info!(
+                "CSRF token missing or invalid, method = '{}', path = '{}'",
+                request.method(),
+                request.uri().path()
+            );
+            return (StatusCode::FORBIDDEN, "missing or invalid CSRF token").into_response();
+        }
+    }
+
+    let (token, issued) = match cookie_token {
+        Some(token) => (token, false),
+        None => match opaque_token::generate() {
+            Ok(token) => (token, true),
+            Err(()) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        },
+    };
+
+    request.extensions_mut().insert(CsrfToken(token.clone()));
+
+    let mut response = next.run(request).await;
+
+    if issued {
+        // readable by scripts on purpose, they have to copy it into the header
+        match HeaderValue::from_str(&format!(
+            "{COOKIE_NAME}={token}; Path=/; SameSite=Strict{}",
+            if secure_cookies { "; Secure" } else { "" }
+        )) {
+            Ok(cookie) => {
+                response.headers_mut().append(SET_COOKIE, cookie);
+            }
+            Err(e) => log::error!("csrf::protect, cookie header, error = {e}"),
+        }
+    }
+
+    response
+}
+
+pub fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value.to_string())
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::get, Router};
+    use axum_test::{TestResponse, TestServer};
+
+    use super::*;
+
+    fn server(secure_cookies: bool) -> TestServer {
+        let router = Router::new()
+            .route("/", get(|| async {}).post(|| async {}))
+            .layer(middleware::from_fn(move |request, next| {
+                check(request, next, secure_cookies)
+            }));
+
+        TestServer::new(router).unwrap()
+    }
+
+    fn issued_cookie(response: &TestResponse) -> String {
+        response
+            .headers()
+            .get(SET_COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn header(value: &str) -> HeaderValue {
+        HeaderValue::from_str(value).unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_token_is_issued_to_the_clients_without_one() {
+        let response = server(false).get("/").await;
+        response.assert_status_ok();
+        let cookie = issued_cookie(&response);
+        assert!(cookie.starts_with("csrf_token="));
+        assert!(cookie.contains("SameSite=Strict"));
+        assert!(!cookie.contains("Secure"));
+
+        assert!(issued_cookie(&server(true).get("/").await).ends_with("; Secure"));
+
+        let response = server(false)
+            .get("/")
+            .add_header(COOKIE, header("csrf_token=the-token"))
+            .await;
+        assert!(response.headers().get(SET_COOKIE).is_none());
+    }
+
+    #[tokio::test]
+    async fn unsafe_requests_with_cookies_need_the_matching_header() {
+        let server = server(false);
+
+        server
+            .post("/")
+            .add_header(COOKIE, header("csrf_token=the-token"))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server
+            .post("/")
+            .add_header(COOKIE, header("csrf_token=the-token"))
+            .add_header(HEADER_NAME, header("another-token"))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        // the session cookies of the browser without a token
+        server
+            .post("/")
+            .add_header(COOKIE, header("session=the-session"))
+            .add_header(HEADER_NAME, header("the-token"))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        server
+            .post("/")
+            .add_header(COOKIE, header("session=the-session; csrf_token=the-token"))
+            .add_header(HEADER_NAME, header("the-token"))
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn safe_requests_and_requests_not_authenticated_by_cookies_pass() {
+        let server = server(false);
+
+        server
+            .get("/")
+            .add_header(COOKIE, header("csrf_token=the-token"))
+            .await
+            .assert_status_ok();
+        server
+            .post("/")
+            .add_header(COOKIE, header("csrf_token=the-token"))
+            .add_header(AUTHORIZATION, header("Bearer the-access-token"))
+            .await
+            .assert_status_ok();
+        server.post("/").await.assert_status_ok();
+    }
+}
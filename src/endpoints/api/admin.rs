@@ -0,0 +1,262 @@
+//! Handlers under `/api/admin`. Authorization is enforced by the `AuthorizationLayer`s attached
+//! where these routes are built, see `AppState::routes`.
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use axum_extra::extract::Query;
+use axum_helpers::auth::{AccessTokenResponse, LoginInfoExtractor};
+
+use crate::{
+    app_state::AppState,
+    audit::{AuditEvent, AuditQuery},
+    keyring::SigningKeyInfo,
+    messages::{
+        AuditEventsParams, CreateInviteRequest, CreateInviteResponse, CreateUserRequest,
+        ImpersonationResponse, PagingParams, UpdateUserRequest,
+    },
+    model::login_info::{LoginInfo, LoginName, StoredLoginInfo},
+};
+
+use super::{user_agent, users::user_error_response};
+
+pub async fn get_signing_keys(state: State<AppState>) -> Json<Vec<SigningKeyInfo>> {
+    log::info!("get_signing_keys");
+
+    Json(state.signing_keys())
+}
+
+pub async fn get_audit_events(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    state: State<AppState>,
+    Query(params): Query<AuditEventsParams>,
+) -> Result<Json<Vec<AuditEvent>>, StatusCode> {
+    log::info!(
+        "get_audit_events: loginname = '{}', filter_loginname = '{}', kind = '{:?}', since = '{:?}', until = '{:?}'",
+        login_info.loginname,
+        params.loginname.as_deref().unwrap_or_default(),
+        params.kind,
+        params.since,
+        params.until
+    );
+
+    let events = state
+        .audit_events(&AuditQuery {
+            loginname: params.loginname,
+            kind: params.kind,
+            since: params.since,
+            until: params.until,
+            offset: params.offset,
+            limit: params.limit.min(PagingParams::MAX_LIMIT),
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(events))
+}
+
+pub async fn get_users(
+    state: State<AppState>,
+    Query(paging): Query<PagingParams>,
+) -> Result<Json<Vec<StoredLoginInfo>>, StatusCode> {
+    log::info!(
+        "get_users: offset = '{}', limit = '{}'",
+        paging.offset,
+        paging.limit
+    );
+
+    let users = state
+        .store
+        .list_users(paging.offset, paging.limit.min(PagingParams::MAX_LIMIT))
+        .await
+        .inspect_err(|e| log::error!("get_users, list_users, error = {e}"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(users))
+}
+
+pub async fn get_user(
+    state: State<AppState>,
+    Path(loginname): Path<String>,
+) -> Result<Json<StoredLoginInfo>, StatusCode> {
+    log::info!("get_user: loginname = '{loginname}'");
+
+    let login_info = state
+        .store
+        .get_user(&LoginName(loginname))
+        .await
+        .inspect_err(|e| log::error!("get_user, get_user, error = {e}"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(login_info))
+}
+
+pub async fn create_user(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    state: State<AppState>,
+    Json(create_user_request): Json<CreateUserRequest>,
+) -> Result<(StatusCode, Json<StoredLoginInfo>), (StatusCode, String)> {
+    log::info!(
+        "create_user: loginname = '{}', created_by = '{}'",
+        create_user_request.loginname,
+        login_info.loginname
+    );
+
+    let created_login_info = state
+        .create_user(
+            Some(&login_info.loginname),
+            create_user_request.loginname,
+            &create_user_request.password,
+            create_user_request.roles,
+        )
+        .await
+        .map_err(user_error_response)?;
+
+    Ok((StatusCode::CREATED, Json(created_login_info)))
+}
+
+pub async fn update_user(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    state: State<AppState>,
+    Path(loginname): Path<String>,
+    Json(update_user_request): Json<UpdateUserRequest>,
+) -> Result<Json<StoredLoginInfo>, (StatusCode, String)> {
+    log::info!(
+        "update_user: loginname = '{loginname}', updated_by = '{}'",
+        login_info.loginname
+    );
+
+    if loginname == login_info.loginname && update_user_request.disabled == Some(true) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "admins can not disable themselves".into(),
+        ));
+    }
+
+    let updated_login_info = state
+        .update_user(
+            Some(&login_info.loginname),
+            &loginname,
+            update_user_request.roles,
+            update_user_request.disabled,
+            update_user_request.password.as_deref(),
+        )
+        .await
+        .map_err(user_error_response)?;
+
+    Ok(Json(updated_login_info))
+}
+
+pub async fn delete_user(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    state: State<AppState>,
+    Path(loginname): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    log::info!(
+        "delete_user: loginname = '{loginname}', deleted_by = '{}'",
+        login_info.loginname
+    );
+
+    if loginname == login_info.loginname {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "admins can not delete themselves".into(),
+        ));
+    }
+
+    match state
+        .delete_user(Some(&login_info.loginname), &loginname)
+        .await
+    {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((StatusCode::NOT_FOUND, String::new())),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, String::new())),
+    }
+}
+
+pub async fn unlock_user(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    state: State<AppState>,
+    Path(loginname): Path<String>,
+) -> StatusCode {
+    log::info!(
+        "unlock_user: loginname = '{loginname}', unlocked_by = '{}'",
+        login_info.loginname
+    );
+
+    if state.unlock_login(&login_info.loginname, &loginname) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Returns an access token of the user that carries the admin as actor. It is not refreshable,
+/// the impersonation is ended with `POST /api/impersonation/end` or when it expires.
+pub async fn impersonate_user(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    state: State<AppState>,
+    headers: HeaderMap,
+    Path(loginname): Path<String>,
+) -> Result<(StatusCode, AccessTokenResponse, Json<ImpersonationResponse>), (StatusCode, String)> {
+    log::info!(
+        "impersonate_user: loginname = '{loginname}', impersonator = '{}'",
+        login_info.loginname
+    );
+
+    let (expires_at, access_token_response) = state
+        .impersonate(&login_info, &loginname, user_agent(&headers))
+        .await
+        .map_err(user_error_response)?;
+
+    Ok((
+        StatusCode::OK,
+        access_token_response,
+        Json(ImpersonationResponse {
+            loginname,
+            impersonator: login_info.loginname.clone(),
+            expires_at,
+        }),
+    ))
+}
+
+pub async fn reset_user_totp(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    state: State<AppState>,
+    Path(loginname): Path<String>,
+) -> StatusCode {
+    log::info!(
+        "reset_user_totp: loginname = '{loginname}', reset_by = '{}'",
+        login_info.loginname
+    );
+
+    match state.reset_totp(&login_info.loginname, &loginname).await {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn create_invite(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    state: State<AppState>,
+    Json(create_invite_request): Json<CreateInviteRequest>,
+) -> Result<(StatusCode, Json<CreateInviteResponse>), (StatusCode, String)> {
+    log::info!("create_invite: created_by = '{}'", login_info.loginname);
+
+    let (invite_token, invite) = state
+        .create_invite(&login_info.loginname, create_invite_request.roles)
+        .await
+        .map_err(user_error_response)?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateInviteResponse {
+            invite_token,
+            expires_at: invite.expires_at,
+        }),
+    ))
+}
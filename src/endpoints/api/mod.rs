@@ -1,7 +1,16 @@
+pub mod admin;
+pub mod oidc;
+pub mod users;
+
+use std::net::SocketAddr;
+
 use axum::{
-    extract::{Path, State},
-    http::{StatusCode, Uri},
-    response::IntoResponse,
+    extract::{ConnectInfo, Path, State},
+    http::{
+        header::{RETRY_AFTER, USER_AGENT},
+        HeaderMap, StatusCode, Uri,
+    },
+    response::{IntoResponse, Response},
     Json,
 };
 use axum_extra::extract::Query;
@@ -10,26 +19,115 @@ use serde_json::json;
 use uuid::Uuid;
 
 use crate::{
-    app_state::AppState,
-    fn_decorators::check_required_role,
-    messages::{EchoPathResponse, EchoThisAndThatResponse, LoginRequest, LoginResponse},
-    model::login_info::LoginInfo,
+    app_state::{AppState, LoginOutcome},
+    audit::{AuditEvent, AuditEventKind},
+    error::{LoginError, RefreshError},
+    fn_decorators::check_required_permission,
+    messages::{
+        EchoPathResponse, EchoThisAndThatResponse, LoginRequest, LoginResponse, PagingParams,
+        RefreshRequest, SecondFactorRequest, SecondFactorRequiredResponse, SessionResponse,
+    },
+    model::login_info::{LoginInfo, LoginName, StoredLoginInfo},
+    permissions,
 };
 
 pub async fn login(
     State(mut state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
     Json(login_request): Json<LoginRequest>,
+) -> Result<Response, Response> {
+    let login_outcome = state
+        .login(
+            &login_request.loginname,
+            login_request.password,
+            user_agent(&headers),
+            connect_info.map(|ConnectInfo(addr)| addr.ip()),
+        )
+        .await
+        .map_err(login_error_response)?;
+
+    Ok(match login_outcome {
+        LoginOutcome::Authenticated(access_token_response) => (
+            StatusCode::OK,
+            access_token_response,
+            Json(LoginResponse {
+                loginname: login_request.loginname,
+            }),
+        )
+            .into_response(),
+        LoginOutcome::SecondFactorRequired { challenge_token } => (
+            StatusCode::OK,
+            Json(SecondFactorRequiredResponse {
+                loginname: login_request.loginname,
+                second_factor_required: true,
+                challenge_token,
+            }),
+        )
+            .into_response(),
+    })
+}
+
+pub async fn login_second_factor(
+    State(mut state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Json(second_factor_request): Json<SecondFactorRequest>,
+) -> Result<(StatusCode, AccessTokenResponse, Json<LoginResponse>), Response> {
+    let (loginname, access_token_response) = state
+        .complete_second_factor(
+            &second_factor_request.challenge_token,
+            &second_factor_request.code,
+            user_agent(&headers),
+            connect_info.map(|ConnectInfo(addr)| addr.ip()),
+        )
+        .await
+        .map_err(login_error_response)?;
+
+    Ok((
+        StatusCode::OK,
+        access_token_response,
+        Json(LoginResponse { loginname }),
+    ))
+}
+
+fn user_agent(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .map(|user_agent| user_agent.to_string())
+}
+
+fn login_error_response(e: LoginError) -> Response {
+    match e {
+        LoginError::InvalidCredentials => StatusCode::UNAUTHORIZED.into_response(),
+        LoginError::Disabled => StatusCode::FORBIDDEN.into_response(),
+        LoginError::Throttled(retry_after) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            // rounded up, so the client does not retry too early
+            [(RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string())],
+        )
+            .into_response(),
+        LoginError::Internal => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+pub async fn refresh(
+    State(mut state): State<AppState>,
+    Json(refresh_request): Json<RefreshRequest>,
 ) -> Result<(StatusCode, AccessTokenResponse, Json<LoginResponse>), StatusCode> {
-    let access_token_response = state
-        .login(&login_request.loginname, login_request.password)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (loginname, access_token_response) = state
+        .refresh(&refresh_request.refresh_token)
+        .await
+        .map_err(|e| match e {
+            RefreshError::InvalidToken => StatusCode::UNAUTHORIZED,
+            RefreshError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
 
     Ok((
         StatusCode::OK,
         access_token_response,
-        Json(LoginResponse {
-            loginname: login_request.loginname,
-        }),
+        Json(LoginResponse { loginname }),
     ))
 }
 
@@ -39,43 +137,138 @@ pub async fn logout(
     Ok(AuthLogoutResponse::new(Some("/"), Some("/")))
 }
 
-#[fn_decorator::use_decorator(check_required_role("admin"), override_return_type = impl IntoResponse, exact_parameters = [_login_info])]
+/// Ends the impersonation the request is made with, like a logout of the impersonation session
+pub async fn end_impersonation(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
+) -> Result<AuthLogoutResponse, (StatusCode, String)> {
+    log::info!(
+        "end_impersonation: loginname = '{}', impersonator = '{}'",
+        login_info.loginname,
+        login_info.impersonator.as_deref().unwrap_or_default()
+    );
+
+    match state.end_impersonation(&login_info).await {
+        Ok(true) => Ok(AuthLogoutResponse::new(Some("/"), Some("/"))),
+        Ok(false) => Err((
+            StatusCode::BAD_REQUEST,
+            "the request is not impersonated".into(),
+        )),
+        Err(()) => Err((StatusCode::INTERNAL_SERVER_ERROR, String::new())),
+    }
+}
+
+pub async fn get_sessions(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<SessionResponse>>, StatusCode> {
+    log::info!("get_sessions: loginname = '{}'", login_info.loginname);
+
+    let sessions = state
+        .store
+        .list_sessions(&LoginName(login_info.loginname.clone()))
+        .await
+        .inspect_err(|e| log::error!("get_sessions, list_sessions, error = {e}"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .filter(|session| session.is_active())
+        .map(|session| SessionResponse {
+            current: session.id == login_info.session_id,
+            id: session.id,
+            user_agent: session.user_agent,
+            created_at: session.created_at,
+            last_refreshed_at: session.last_refreshed_at,
+            expires_at: session.expires_at,
+            impersonator: session.impersonator,
+        })
+        .collect();
+
+    Ok(Json(sessions))
+}
+
+pub async fn revoke_session(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    log::info!(
+        "revoke_session: loginname = '{}', session_id = '{session_id}'",
+        login_info.loginname
+    );
+
+    // sessions of other users are reported as not found, their existence is not revealed
+    state
+        .store
+        .get_session(&session_id)
+        .await
+        .inspect_err(|e| log::error!("revoke_session, get_session, error = {e}"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|session| session.loginname == login_info.loginname && session.is_active())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    state
+        .revoke_session(&session_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[fn_decorator::use_decorator(check_required_permission(permissions::USERS_READ), override_return_type = impl IntoResponse, exact_parameters = [login_info, state])]
 pub async fn get_seen_users(
-    _login_info: LoginInfoExtractor<LoginInfo>,
+    login_info: LoginInfoExtractor<LoginInfo>,
     state: State<AppState>,
-) -> Json<serde_json::Value> {
-    log::info!("get_logged_in_users");
+    paging: Query<PagingParams>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    log::info!(
+        "get_logged_in_users: offset = '{}', limit = '{}'",
+        paging.offset,
+        paging.limit
+    );
 
     let login_infos = state
-        .logins
-        .read()
-        .iter()
-        .map(|(_access_token, login_info)| login_info.clone())
-        .collect::<Vec<_>>();
+        .store
+        .list_users(paging.offset, paging.limit.min(PagingParams::MAX_LIMIT))
+        .await
+        .inspect_err(|e| log::error!("get_seen_users, list_users, error = {e}"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit().record(
+        AuditEvent::new(AuditEventKind::SeenUsersRead)
+            .actor(&login_info.0.loginname)
+            .details(json!({ "offset": paging.offset, "count": login_infos.len() })),
+    );
 
-    Json(json!({
+    Ok(Json(json!({
         "login_infos": login_infos
-    }))
+    })))
 }
 
-#[fn_decorator::use_decorator(check_required_role("admin"), override_return_type = impl IntoResponse, exact_parameters = [_login_info])]
+#[fn_decorator::use_decorator(check_required_permission(permissions::USERS_READ), override_return_type = impl IntoResponse, exact_parameters = [login_info, state])]
 pub async fn get_seen_user(
-    _login_info: LoginInfoExtractor<LoginInfo>,
+    login_info: LoginInfoExtractor<LoginInfo>,
     state: State<AppState>,
     index: Path<u32>,
-) -> Result<Json<LoginInfo>, StatusCode> {
+) -> Result<Json<StoredLoginInfo>, StatusCode> {
     log::info!("get_logged_in_user: index = '{}'", index.0);
 
-    let login_info = state
-        .logins
-        .read()
-        .iter()
-        .nth(index.0 as usize)
-        .ok_or(StatusCode::NOT_FOUND)?
-        .1
-        .into();
+    let seen_login_info = state
+        .store
+        .list_users(index.0 as usize, 1)
+        .await
+        .inspect_err(|e| log::error!("get_seen_user, list_users, error = {e}"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .next()
+        .ok_or(StatusCode::NOT_FOUND)?;
 
-    Ok(Json(login_info))
+    state.audit().record(
+        AuditEvent::new(AuditEventKind::SeenUsersRead)
+            .loginname(&seen_login_info.loginname)
+            .actor(&login_info.0.loginname),
+    );
+
+    Ok(Json(seen_login_info))
 }
 
 pub async fn echo_this_and_that(
@@ -121,3 +314,25 @@ pub async fn echo_uuid_in_path(Path(uuid): Path<Uuid>) -> Json<Uuid> {
 
     Json(uuid)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn throttled_logins_are_answered_with_retry_after_in_whole_seconds() {
+        let response = login_error_response(LoginError::Throttled(Duration::from_millis(1500)));
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "2");
+    }
+
+    #[test]
+    fn retry_after_is_not_rounded_to_zero() {
+        let response = login_error_response(LoginError::Throttled(Duration::from_millis(1)));
+
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+    }
+}
@@ -0,0 +1,126 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{header::SET_COOKIE, HeaderMap, HeaderValue, StatusCode},
+    response::{AppendHeaders, IntoResponse, Redirect, Response},
+};
+
+use crate::{
+    app_state::AppState, csrf, error::OidcError, messages::OidcCallbackParams,
+    oidc::PENDING_AUTHORIZATION_LIFETIME, opaque_token,
+};
+
+/// Holds the state of the login started in the browser. The callback is only accepted together
+/// with it, otherwise an attacker could log the victim in to the attacker's account by sending
+/// the victim the callback url of a login started by the attacker.
+const STATE_COOKIE_NAME: &str = "oidc_state";
+const STATE_COOKIE_PATH: &str = "/api/oidc";
+
+/// Maps the error to a status code, the errors the user can act on are explained in the body
+fn oidc_error_response(e: OidcError) -> Response {
+    match e {
+        OidcError::NotConfigured => StatusCode::NOT_FOUND.into_response(),
+        OidcError::InvalidState => (
+            StatusCode::BAD_REQUEST,
+            "the login is expired or already completed, please start again",
+        )
+            .into_response(),
+        OidcError::Provider => StatusCode::BAD_GATEWAY.into_response(),
+        OidcError::UserConflict(reason) => (StatusCode::CONFLICT, reason).into_response(),
+        OidcError::Disabled => StatusCode::FORBIDDEN.into_response(),
+        OidcError::Internal => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Sent along the redirect from the provider, which is a cross-site navigation, so it has to be
+/// SameSite=Lax
+fn state_cookie(
+    state: &AppState,
+    value: &str,
+    max_age_secs: u64,
+) -> Result<HeaderValue, StatusCode> {
+    let config = state.config();
+    let secure = config.server.secure_cookies
+        || config
+            .oidc
+            .redirect_url
+            .as_deref()
+            .is_some_and(|redirect_url| redirect_url.starts_with("https://"));
+
+    HeaderValue::from_str(&format!(
+        "{STATE_COOKIE_NAME}={value}; Path={STATE_COOKIE_PATH}; Max-Age={max_age_secs}; HttpOnly; SameSite=Lax{}",
+        if secure { "; Secure" } else { "" }
+    ))
+    .inspect_err(|e| log::error!("state_cookie, error = {e}"))
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn login(State(state): State<AppState>) -> Result<Response, Response> {
+    let authorization_request = state
+        .oidc_authorization_request()
+        .await
+        .map_err(oidc_error_response)?;
+
+    let cookie = state_cookie(
+        &state,
+        &authorization_request.state,
+        PENDING_AUTHORIZATION_LIFETIME.as_secs(),
+    )
+    .map_err(IntoResponse::into_response)?;
+
+    Ok((
+        AppendHeaders([(SET_COOKIE, cookie)]),
+        Redirect::to(&authorization_request.url),
+    )
+        .into_response())
+}
+
+pub async fn callback(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(params): Query<OidcCallbackParams>,
+) -> Result<Response, Response> {
+    if let Some(error) = params.error {
+        log::info!(
+            "callback: the provider returned an error, error = '{error}', description = '{}'",
+            params.error_description.as_deref().unwrap_or_default()
+        );
+        return Err((StatusCode::UNAUTHORIZED, error).into_response());
+    }
+
+    let (Some(code), Some(state_param)) = (params.code, params.state) else {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    let state_cookie_matches =
+        csrf::cookie_value(&headers, STATE_COOKIE_NAME).is_some_and(|cookie| {
+            opaque_token::constant_time_eq(cookie.as_bytes(), state_param.as_bytes())
+        });
+    if !state_cookie_matches {
+        log::info!("callback: the state does not match the state cookie of the browser");
+        return Err(oidc_error_response(OidcError::InvalidState));
+    }
+
+    let (loginname, access_token_response) = state
+        .oidc_login(
+            &code,
+            &state_param,
+            super::user_agent(&headers),
+            connect_info.map(|ConnectInfo(addr)| addr.ip()),
+        )
+        .await
+        .map_err(oidc_error_response)?;
+
+    log::info!("callback: loginname = '{loginname}'");
+
+    let expired_cookie = state_cookie(&state, "", 0).map_err(IntoResponse::into_response)?;
+
+    Ok((
+        AppendHeaders([(SET_COOKIE, expired_cookie)]),
+        access_token_response,
+        Redirect::to("/"),
+    )
+        .into_response())
+}
@@ -0,0 +1,344 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use axum_helpers::auth::LoginInfoExtractor;
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    error::UserError,
+    messages::{
+        ChangePasswordRequest, ConfirmEmailVerificationRequest, ConfirmPasswordResetRequest,
+        CreateApiKeyRequest, CreateApiKeyResponse, DisableTotpRequest, PasswordResetRequest,
+        RecoveryCodesResponse, RegisterRequest, SetEmailRequest, TotpCodeRequest,
+        TotpEnrollmentResponse,
+    },
+    model::{
+        api_key::StoredApiKey,
+        login_info::{LoginInfo, LoginName, StoredLoginInfo},
+    },
+};
+
+/// Maps the error to a status code, the validation errors are explained in the body
+pub fn user_error_response(e: UserError) -> (StatusCode, String) {
+    match e {
+        UserError::Invalid(reason) => (StatusCode::BAD_REQUEST, reason),
+        UserError::AlreadyExists => (StatusCode::CONFLICT, "the user already exists".into()),
+        UserError::NotFound => (StatusCode::NOT_FOUND, String::new()),
+        UserError::WrongPassword => (StatusCode::FORBIDDEN, "wrong password".into()),
+        UserError::RegistrationNotAllowed => (
+            StatusCode::FORBIDDEN,
+            "registration is not allowed without a valid invite".into(),
+        ),
+        UserError::InvalidToken => (
+            StatusCode::BAD_REQUEST,
+            "the token is invalid, expired or already used".into(),
+        ),
+        UserError::NotAllowed(reason) => (StatusCode::FORBIDDEN, reason),
+        UserError::Internal => (StatusCode::INTERNAL_SERVER_ERROR, String::new()),
+    }
+}
+
+/// The credentials and the second factor of a user can not be changed by an admin impersonating
+/// the user
+fn reject_impersonation(login_info: &LoginInfo) -> Result<(), (StatusCode, String)> {
+    match login_info.impersonator {
+        Some(_) => Err((
+            StatusCode::FORBIDDEN,
+            "not allowed while impersonating".into(),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// The credentials, the email address and the second factor of a user can not be changed with
+/// an API key, whatever its scope. A leaked key must not be able to take over the account, e.g.,
+/// by redirecting the password reset mails.
+fn reject_api_key(login_info: &LoginInfo) -> Result<(), (StatusCode, String)> {
+    match login_info.api_key_id {
+        Some(_) => Err((StatusCode::FORBIDDEN, "not allowed with an API key".into())),
+        None => Ok(()),
+    }
+}
+
+pub async fn register(
+    State(state): State<AppState>,
+    Json(register_request): Json<RegisterRequest>,
+) -> Result<(StatusCode, Json<StoredLoginInfo>), (StatusCode, String)> {
+    log::info!("register: loginname = '{}'", register_request.loginname);
+
+    let login_info = state
+        .register_user(
+            register_request.loginname,
+            &register_request.password,
+            register_request.email,
+            register_request.invite_token.as_deref(),
+        )
+        .await
+        .map_err(user_error_response)?;
+
+    Ok((StatusCode::CREATED, Json(login_info)))
+}
+
+pub async fn get_current_user(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
+) -> Result<Json<StoredLoginInfo>, StatusCode> {
+    log::info!("get_current_user: loginname = '{}'", login_info.loginname);
+
+    let login_info = state
+        .store
+        .get_user(&LoginName(login_info.loginname.clone()))
+        .await
+        .inspect_err(|e| log::error!("get_current_user, get_user, error = {e}"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(login_info))
+}
+
+pub async fn change_password(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
+    Json(change_password_request): Json<ChangePasswordRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    log::info!("change_password: loginname = '{}'", login_info.loginname);
+
+    reject_impersonation(&login_info)?;
+    reject_api_key(&login_info)?;
+
+    state
+        .change_password(
+            &login_info,
+            &change_password_request.current_password,
+            &change_password_request.new_password,
+        )
+        .await
+        .map_err(user_error_response)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn enroll_totp(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
+) -> Result<Json<TotpEnrollmentResponse>, (StatusCode, String)> {
+    log::info!("enroll_totp: loginname = '{}'", login_info.loginname);
+
+    reject_impersonation(&login_info)?;
+    reject_api_key(&login_info)?;
+
+    let (secret, otpauth_uri) = state
+        .enroll_totp(&login_info.loginname)
+        .await
+        .map_err(user_error_response)?;
+
+    Ok(Json(TotpEnrollmentResponse {
+        secret,
+        otpauth_uri,
+    }))
+}
+
+pub async fn confirm_totp(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
+    Json(totp_code_request): Json<TotpCodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, (StatusCode, String)> {
+    log::info!("confirm_totp: loginname = '{}'", login_info.loginname);
+
+    reject_impersonation(&login_info)?;
+    reject_api_key(&login_info)?;
+
+    let recovery_codes = state
+        .confirm_totp(&login_info.loginname, &totp_code_request.code)
+        .await
+        .map_err(user_error_response)?;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+pub async fn disable_totp(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
+    Json(disable_totp_request): Json<DisableTotpRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    log::info!("disable_totp: loginname = '{}'", login_info.loginname);
+
+    reject_impersonation(&login_info)?;
+    reject_api_key(&login_info)?;
+
+    state
+        .disable_totp(
+            &login_info.loginname,
+            &disable_totp_request.current_password,
+        )
+        .await
+        .map_err(user_error_response)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Always answers with 202, whether the user exists and has a verified email address is not
+/// revealed
+pub async fn request_password_reset(
+    State(state): State<AppState>,
+    Json(password_reset_request): Json<PasswordResetRequest>,
+) -> StatusCode {
+    log::info!(
+        "request_password_reset: loginname = '{}'",
+        password_reset_request.loginname
+    );
+
+    match state
+        .request_password_reset(&password_reset_request.loginname)
+        .await
+    {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(()) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn confirm_password_reset(
+    State(state): State<AppState>,
+    Json(confirm_password_reset_request): Json<ConfirmPasswordResetRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    log::info!("confirm_password_reset");
+
+    state
+        .reset_password(
+            &confirm_password_reset_request.token,
+            &confirm_password_reset_request.new_password,
+        )
+        .await
+        .map_err(user_error_response)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn set_email(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
+    Json(set_email_request): Json<SetEmailRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    log::info!("set_email: loginname = '{}'", login_info.loginname);
+
+    reject_impersonation(&login_info)?;
+    reject_api_key(&login_info)?;
+
+    state
+        .set_email(
+            &login_info.loginname,
+            &set_email_request.current_password,
+            set_email_request.email,
+        )
+        .await
+        .map_err(user_error_response)?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn resend_email_verification(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    log::info!(
+        "resend_email_verification: loginname = '{}'",
+        login_info.loginname
+    );
+
+    state
+        .resend_email_verification(&login_info.loginname)
+        .await
+        .map_err(user_error_response)?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn confirm_email_verification(
+    State(state): State<AppState>,
+    Json(confirm_email_verification_request): Json<ConfirmEmailVerificationRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    log::info!("confirm_email_verification");
+
+    state
+        .verify_email(&confirm_email_verification_request.token)
+        .await
+        .map_err(user_error_response)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn create_api_key(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
+    Json(create_api_key_request): Json<CreateApiKeyRequest>,
+) -> Result<(StatusCode, Json<CreateApiKeyResponse>), (StatusCode, String)> {
+    log::info!("create_api_key: loginname = '{}'", login_info.loginname);
+
+    reject_impersonation(&login_info)?;
+    // a leaked key must not be able to create keys that outlive its revocation
+    reject_api_key(&login_info)?;
+
+    let (key, api_key) = state
+        .create_api_key(
+            &login_info,
+            create_api_key_request.name,
+            create_api_key_request.permissions,
+            create_api_key_request
+                .expires_in_days
+                .map(|days| Duration::from_secs(days.saturating_mul(24 * 60 * 60))),
+        )
+        .await
+        .map_err(user_error_response)?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateApiKeyResponse {
+            id: api_key.id,
+            key,
+            permissions: api_key.permissions,
+            expires_at: api_key.expires_at,
+        }),
+    ))
+}
+
+pub async fn list_api_keys(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<StoredApiKey>>, StatusCode> {
+    log::info!("list_api_keys: loginname = '{}'", login_info.loginname);
+
+    let api_keys = state
+        .store
+        .list_api_keys(&LoginName(login_info.loginname.clone()))
+        .await
+        .inspect_err(|e| log::error!("list_api_keys, list_api_keys, error = {e}"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(api_keys))
+}
+
+pub async fn revoke_api_key(
+    LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
+    Path(api_key_id): Path<Uuid>,
+) -> StatusCode {
+    log::info!(
+        "revoke_api_key: loginname = '{}', api_key_id = '{api_key_id}'",
+        login_info.loginname
+    );
+
+    // keys of other users are reported as not found, their existence is not revealed
+    match state
+        .revoke_api_key(&login_info.loginname, &api_key_id)
+        .await
+    {
+        Ok(true) => StatusCode::NO_CONTENT,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(()) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
@@ -0,0 +1,33 @@
+use axum::{extract::State, http::StatusCode, Json};
+
+use crate::{
+    app_state::AppState,
+    health::{HealthReport, HealthStatus},
+};
+
+/// Whether the application works, 503 if a critical check fails. The liveness probe, the
+/// orchestrator restarts the application if it keeps failing.
+pub async fn healthz(State(state): State<AppState>) -> (StatusCode, Json<HealthReport>) {
+    let report = state.health().report().await;
+    let status_code = if report.status == HealthStatus::Fail {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+
+    (status_code, Json(report))
+}
+
+/// Whether the application accepts requests, 503 during the startup, the shutdown, or if a
+/// critical check fails. The readiness probe, the load balancer sends requests only to the ready
+/// instances.
+pub async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<HealthReport>) {
+    let report = state.health().report().await;
+    let status_code = if report.ready && report.status != HealthStatus::Fail {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status_code, Json(report))
+}
@@ -1,11 +1,14 @@
-use axum::response::Html;
+use axum::{response::Html, Extension};
 use axum_helpers::auth::LoginInfoExtractor;
 
-use crate::model::login_info::LoginInfo;
+use crate::{csrf::CsrfToken, model::login_info::LoginInfo};
 
-use super::{get_index_html_head, get_index_html_tail};
+use super::{get_index_html_head, get_index_html_tail, route_table::ROUTES};
 
-pub async fn index(login_info: Option<LoginInfoExtractor<LoginInfo>>) -> Html<String> {
+pub async fn index(
+    login_info: Option<LoginInfoExtractor<LoginInfo>>,
+    Extension(CsrfToken(csrf_token)): Extension<CsrfToken>,
+) -> Html<String> {
     let header = if login_info.is_some() {
         r#"
             <link rel="stylesheet" href="public/main.css">
@@ -16,6 +19,9 @@ pub async fn index(login_info: Option<LoginInfoExtractor<LoginInfo>>) -> Html<St
 
                     await fetch("/api/logout", {
                         method: "POST",
+                        headers: {
+                            'X-CSRF-Token': document.querySelector('meta[name="csrf-token"]').content,
+                        },
                     });
 
                     location.reload();
@@ -32,27 +38,30 @@ pub async fn index(login_info: Option<LoginInfoExtractor<LoginInfo>>) -> Html<St
         "#
     };
 
+    let routes = ROUTES
+        .iter()
+        .map(|route| {
+            let route_text = format!("{} {}", route.method.to_lowercase(), route.path);
+            let route_html = match route.link {
+                Some(link) => format!(r#"<a href="{link}">{route_text}</a>"#),
+                None => route_text,
+            };
+            format!(
+                "<li><b>{route_html}</b>: {}</li>",
+                escape_html(route.description)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
     Html(format!(
         r#"
             {}
+                <meta name="csrf-token" content="{csrf_token}">
                 {header}
                 <h1>Endpoints</h1>
                 <ul>
-                    <li><b>get /</b>: returns this page</li>
-                    <li><b>get /login</b>: returns a page where a user can log in</li>
-
-                    <li><b>post /api/login</b>: logs a user in</li>
-                    <li><b>post /api/logout</b>: logs a user out</li>
-
-                    <li><b><a href="/api/seen-users">get /api/seen-users</a></b>: lists the users seen since the server started</li>
-                    <li><b><a href="/api/seen-users/0">get /api/seen-users/:index</a></b>: shows user at the given position</li>
-
-                    <li><b><a href="/api/create-uuid-v4">get /api/create-uuid-v4</a></b>: generates and returns a uuid value (v4)</li>
-                    <li><b><a href="/api/echo/foo/and/bar">get /api/echo/:this/and/:that</a></b>: returns this and that in a json object</li>
-                    <li><b><a href="/api/echo-path">get /api/echo-path</a></b>: returns the path of the request in a json object</li>
-                    <li><b><a href="/api/echo-query-params?key0=value0&key1=value1&listkey=a&listkey=b">get /api/echo-query-params</a></b>: returns all query params in a list</li>
-                    <li><b><a href="/api/echo-parsed-query-params?uuid=88292365-1919-4e00-b406-6988740f395c&list=value0&list=value1">get /api/echo-parsed-query-params?uuid=:uuid&list=:item0&list=:item1</a></b>: parses the query params and returns them in a list in a json object</li>
-                    <li><b><a href="/api/echo-uuid-in-path/88292365-1919-4e00-b406-6988740f395c">get /api/echo-uuid-in-path/:uuid</a></b>: returns the uuid in the path</li>
+                    {routes}
                 </ul>
             {}
         "#,
@@ -60,3 +69,9 @@ pub async fn index(login_info: Option<LoginInfoExtractor<LoginInfo>>) -> Html<St
         get_index_html_tail()
     ))
 }
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -1,9 +1,12 @@
-use axum::response::Html;
+use axum::{response::Html, Extension};
 use axum_helpers::auth::LoginInfoExtractor;
 
-use crate::model::login_info::LoginInfo;
+use crate::{csrf::CsrfToken, model::login_info::LoginInfo};
 
-pub async fn login(login_info: Option<LoginInfoExtractor<LoginInfo>>) -> Html<String> {
+pub async fn login(
+    login_info: Option<LoginInfoExtractor<LoginInfo>>,
+    Extension(CsrfToken(csrf_token)): Extension<CsrfToken>,
+) -> Html<String> {
     let body_content = if login_info.is_some() {
         r#"
             You are already logged in!
@@ -23,6 +26,7 @@ pub async fn login(login_info: Option<LoginInfoExtractor<LoginInfo>>) -> Html<St
                         method: "POST",
                         headers: {
                             'Content-Type': 'application/json',
+                            'X-CSRF-Token': document.querySelector('meta[name="csrf-token"]').content,
                         },
                         body: JSON.stringify({
                             loginname,
@@ -51,6 +55,9 @@ pub async fn login(login_info: Option<LoginInfoExtractor<LoginInfo>>) -> Html<St
     Html(format!(
         r#"
             <html>
+                <head>
+                    <meta name="csrf-token" content="{csrf_token}">
+                </head>
                 <body>
                     {body_content}
                 </body>
@@ -1,6 +1,9 @@
 pub mod api;
+pub mod health;
 mod index;
 mod login;
+pub mod route_table;
+pub mod well_known;
 
 pub use index::index;
 pub use login::login;
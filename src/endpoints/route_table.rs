@@ -0,0 +1,302 @@
+//! The table of the endpoints, shown on the index page and printed by the `routes` command. It
+//! has to be kept in sync with `AppState::routes`, axum can not list the routes of a router. A
+//! test of `AppState` checks that every listed route is routed.
+
+pub struct RouteInfo {
+    pub method: &'static str,
+    pub path: &'static str,
+    /// the link of the index page for trying the endpoint, e.g., with example parameters
+    pub link: Option<&'static str>,
+    pub description: &'static str,
+}
+
+pub const ROUTES: &[RouteInfo] = &[
+    RouteInfo {
+        method: "GET",
+        path: "/",
+        link: None,
+        description: "returns this page",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/login",
+        link: None,
+        description: "returns a page where a user can log in",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/.well-known/jwks.json",
+        link: Some("/.well-known/jwks.json"),
+        description: "returns the public keys of the asymmetric JWT signing keys in JWK format",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/healthz",
+        link: Some("/healthz"),
+        description: "returns the report of the health checks, 503 if a critical check fails",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/readyz",
+        link: Some("/readyz"),
+        description: "returns the report of the health checks, 503 if a critical check fails or the application is starting or shutting down",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/public/*path",
+        link: None,
+        description: "serves the files of the static directory",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/login",
+        link: None,
+        description: "logs a user in, repeated failures are delayed and lead to a temporary lockout",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/login/second-factor",
+        link: None,
+        description: "completes the login of a user with two-factor authentication, exchanges the challenge token and a TOTP or recovery code for the tokens",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/oidc/login",
+        link: Some("/api/oidc/login"),
+        description: "redirects to the OpenID Connect provider to log in there (if configured)",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/oidc/callback",
+        link: None,
+        description: "the provider redirects back here, logs the user in and redirects to this page, the user is created at the first login",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/logout",
+        link: None,
+        description: "logs a user out",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/impersonation/end",
+        link: None,
+        description: "ends the impersonation the request is made with",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/refresh",
+        link: None,
+        description: "exchanges a refresh token for a new access token and refresh token",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/password-reset",
+        link: None,
+        description: "sends a password reset mail to the verified email address of a user",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/password-reset/confirm",
+        link: None,
+        description: "sets a new password with the token of the password reset mail",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/email-verification/confirm",
+        link: None,
+        description: "verifies an email address with the token of the verification mail",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/users",
+        link: None,
+        description: "registers a new user, depending on the registration mode an invite token is required",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/users/me",
+        link: Some("/api/users/me"),
+        description: "shows the user that is logged in",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/users/me/password",
+        link: None,
+        description: "changes the password of the user, the current password is required",
+    },
+    RouteInfo {
+        method: "PUT",
+        path: "/api/users/me/email",
+        link: None,
+        description: "sets the email address of the user and sends a verification mail, the current password is required",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/users/me/email/verification",
+        link: None,
+        description: "sends the verification mail again",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/users/me/totp",
+        link: None,
+        description: "starts the TOTP enrollment, returns the secret and the otpauth URI",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/users/me/totp/confirm",
+        link: None,
+        description: "enables two-factor authentication with the first valid code, returns the recovery codes",
+    },
+    RouteInfo {
+        method: "DELETE",
+        path: "/api/users/me/totp",
+        link: None,
+        description: "disables two-factor authentication, the current password is required",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/users/me/api-keys",
+        link: Some("/api/users/me/api-keys"),
+        description: "lists the API keys of the user",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/users/me/api-keys",
+        link: None,
+        description: "creates an API key scoped to some permissions of the user, the key is shown only once and sent as 'Authorization: ApiKey <key>'",
+    },
+    RouteInfo {
+        method: "DELETE",
+        path: "/api/users/me/api-keys/:api_key_id",
+        link: None,
+        description: "revokes an API key of the user",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/sessions",
+        link: Some("/api/sessions"),
+        description: "lists the active sessions of the user",
+    },
+    RouteInfo {
+        method: "DELETE",
+        path: "/api/sessions/:session_id",
+        link: None,
+        description: "revokes a session of the user",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/seen-users",
+        link: Some("/api/seen-users"),
+        description: "lists the users seen since the server started",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/seen-users/:index",
+        link: Some("/api/seen-users/0"),
+        description: "shows user at the given position",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/admin/users",
+        link: Some("/api/admin/users"),
+        description: "lists the users",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/admin/users",
+        link: None,
+        description: "creates a user",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/admin/users/:loginname",
+        link: None,
+        description: "shows a user",
+    },
+    RouteInfo {
+        method: "PATCH",
+        path: "/api/admin/users/:loginname",
+        link: None,
+        description: "changes the roles, the password or the disabled state of a user",
+    },
+    RouteInfo {
+        method: "DELETE",
+        path: "/api/admin/users/:loginname",
+        link: None,
+        description: "deletes a user",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/admin/users/:loginname/unlock",
+        link: None,
+        description: "lifts the login lockout of a user",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/admin/users/:loginname/impersonate",
+        link: None,
+        description: "returns a short-lived access token of the user that also identifies the admin, for seeing the app as the user",
+    },
+    RouteInfo {
+        method: "DELETE",
+        path: "/api/admin/users/:loginname/totp",
+        link: None,
+        description: "removes the second factor of a user, e.g., if the device is lost",
+    },
+    RouteInfo {
+        method: "POST",
+        path: "/api/admin/invites",
+        link: None,
+        description: "creates an invite for the invite-only registration",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/admin/signing-keys",
+        link: Some("/api/admin/signing-keys"),
+        description: "lists the JWT signing keys",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/admin/audit-events",
+        link: Some("/api/admin/audit-events"),
+        description: "lists the audit events, the newest first, filtered by loginname, kind, since and until",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/create-uuid-v4",
+        link: Some("/api/create-uuid-v4"),
+        description: "generates and returns a uuid value (v4)",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/echo/:this/and/:that",
+        link: Some("/api/echo/foo/and/bar"),
+        description: "returns this and that in a json object",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/echo-path",
+        link: Some("/api/echo-path"),
+        description: "returns the path of the request in a json object",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/echo-query-params",
+        link: Some("/api/echo-query-params?key0=value0&key1=value1&listkey=a&listkey=b"),
+        description: "returns all query params in a list",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/echo-parsed-query-params",
+        link: Some("/api/echo-parsed-query-params?uuid=88292365-1919-4e00-b406-6988740f395c&list=value0&list=value1"),
+        description: "parses the query params and returns them in a list in a json object",
+    },
+    RouteInfo {
+        method: "GET",
+        path: "/api/echo-uuid-in-path/:uuid",
+        link: Some("/api/echo-uuid-in-path/88292365-1919-4e00-b406-6988740f395c"),
+        description: "returns the uuid in the path",
+    },
+];
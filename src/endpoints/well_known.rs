@@ -0,0 +1,12 @@
+use axum::{extract::State, Json};
+use serde_json::json;
+
+use crate::app_state::AppState;
+
+/// The public keys of the asymmetric JWT signing keys, other services can verify the access
+/// tokens with them
+pub async fn jwks(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(json!({
+        "keys": state.public_jwks(),
+    }))
+}
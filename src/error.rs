@@ -1 +1,52 @@
 pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug)]
+pub enum LoginError {
+    /// the loginname is unknown or the password is wrong, these cases are not distinguished
+    InvalidCredentials,
+    /// the credentials are valid but the user is disabled
+    Disabled,
+    /// too many failed attempts for the account or from the address, the next attempt is
+    /// allowed after the given time
+    Throttled(std::time::Duration),
+    Internal,
+}
+
+#[derive(Debug)]
+pub enum RefreshError {
+    /// the refresh token is unknown, expired, already used or its user is logged out
+    InvalidToken,
+    Internal,
+}
+
+#[derive(Debug)]
+pub enum UserError {
+    /// the login name, the password or the roles do not meet the requirements
+    Invalid(String),
+    AlreadyExists,
+    NotFound,
+    /// the current password given for a password change is wrong
+    WrongPassword,
+    /// registration is disabled, or the invite is missing, unknown, expired or already used
+    RegistrationNotAllowed,
+    /// the password reset or email verification token is invalid, expired or already used
+    InvalidToken,
+    /// the caller is not allowed to do this in its current state, e.g., while impersonating
+    NotAllowed(String),
+    Internal,
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    /// no OpenID Connect provider is configured
+    NotConfigured,
+    /// the state is unknown or expired, e.g., the callback was opened twice
+    InvalidState,
+    /// the provider is unavailable, rejected the authorization code or issued an invalid ID token
+    Provider,
+    /// the provider did not assert a usable username, or it belongs to a local user
+    UserConflict(String),
+    /// the user is authenticated by the provider but disabled locally
+    Disabled,
+    Internal,
+}
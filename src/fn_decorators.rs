@@ -1,18 +1,39 @@
 use std::future::Future;
 
-use axum::{http::StatusCode, response::IntoResponse};
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
 use axum_helpers::auth::LoginInfoExtractor;
+use serde_json::json;
 
-use crate::model::login_info::LoginInfo;
+use crate::{
+    app_state::AppState,
+    audit::{AuditEvent, AuditEventKind},
+    model::login_info::LoginInfo,
+};
 
-pub async fn check_required_role<FutureType: Future<Output = impl IntoResponse>>(
-    required_role: &str,
-    f: impl FnOnce(LoginInfoExtractor<LoginInfo>) -> FutureType,
+/// The denied requests are recorded into the audit log of the state, so the decorated function
+/// has to take the state as an exact parameter too
+pub async fn check_required_permission<FutureType: Future<Output = impl IntoResponse>>(
+    required_permission: &str,
+    f: impl FnOnce(LoginInfoExtractor<LoginInfo>, State<AppState>) -> FutureType,
     LoginInfoExtractor(login_info): LoginInfoExtractor<LoginInfo>,
+    State(state): State<AppState>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    if login_info.role == required_role {
-        Ok(f(LoginInfoExtractor(login_info)).await)
-    } else {
-        Err(StatusCode::FORBIDDEN)
+    if login_info.permissions.contains(required_permission) {
+        return Ok(f(LoginInfoExtractor(login_info), State(state)).await);
     }
+
+    log::info!(
+        "Access denied, loginname = '{}', required_permission = '{required_permission}'",
+        login_info.loginname
+    );
+
+    let mut event = AuditEvent::new(AuditEventKind::AccessDenied)
+        .loginname(&login_info.loginname)
+        .details(json!({ "required_permission": required_permission }));
+    if let Some(impersonator) = &login_info.impersonator {
+        event = event.actor(impersonator);
+    }
+    state.audit().record(event);
+
+    Err(StatusCode::FORBIDDEN)
 }
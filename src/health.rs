@@ -0,0 +1,218 @@
+//! The health of the application. The components register their checks, `/healthz` and `/readyz`
+//! run them and answer with a report of every check.
+//!
+//! A failing critical check makes the application unhealthy, a failing non-critical one only
+//! degrades it, e.g., the application works without sending mails. The errors of the checks are
+//! logged, the report does not contain them, the endpoints are public.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::async_trait;
+use parking_lot::RwLock;
+use serde::Serialize;
+use tokio::task::JoinSet;
+
+use crate::{
+    audit::AuditLog,
+    error::BoxError,
+    mailer::Mailer,
+    store::Store,
+    timestamp::{self, UnixTimestamp},
+};
+
+/// A check taking longer than this fails, a hanging dependency must not hang the probes
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[async_trait]
+pub trait HealthCheck: Send + Sync {
+    async fn check(&self) -> Result<(), BoxError>;
+}
+
+/// Ordered by severity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HealthStatus {
+    Pass,
+    /// a non-critical check failed
+    Warn,
+    /// a critical check failed
+    Fail,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    /// false during the startup and the shutdown
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, CheckReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    pub status: HealthStatus,
+    pub critical: bool,
+    pub latency_ms: f64,
+}
+
+#[derive(Clone)]
+struct RegisteredCheck {
+    name: &'static str,
+    critical: bool,
+    check: Arc<dyn HealthCheck>,
+}
+
+#[derive(Default)]
+pub struct Health {
+    checks: RwLock<Vec<RegisteredCheck>>,
+    ready: AtomicBool,
+}
+
+impl Health {
+    pub fn register(&self, name: &'static str, critical: bool, check: Arc<dyn HealthCheck>) {
+        self.checks.write().push(RegisteredCheck {
+            name,
+            critical,
+            check,
+        });
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        if self.ready.swap(ready, Ordering::Relaxed) != ready {
+            log::info!("Readiness changed, ready = {ready}");
+        }
+    }
+
+    /// Runs every check concurrently
+    pub async fn report(&self) -> HealthReport {
+        let mut running = JoinSet::new();
+        for registered in self.checks.read().iter().cloned() {
+            running.spawn(async move {
+                let started_at = Instant::now();
+                let result = tokio::time::timeout(CHECK_TIMEOUT, registered.check.check())
+                    .await
+                    .unwrap_or_else(|_| Err("the check timed out".into()));
+                let latency = started_at.elapsed();
+
+                let status = match result {
+                    Ok(()) => HealthStatus::Pass,
+                    Err(e) => {
+                        log::warn!(
+                            "Health check failed, name = '{}', error = {e}",
+                            registered.name
+                        );
+                        if registered.critical {
+                            HealthStatus::Fail
+                        } else {
+                            HealthStatus::Warn
+                        }
+                    }
+                };
+
+                (
+                    registered.name,
+                    CheckReport {
+                        status,
+                        critical: registered.critical,
+                        latency_ms: latency.as_secs_f64() * 1000.0,
+                    },
+                )
+            });
+        }
+
+        let mut checks = BTreeMap::new();
+        while let Some(result) = running.join_next().await {
+            match result {
+                Ok((name, check_report)) => {
+                    checks.insert(name, check_report);
+                }
+                Err(e) => log::error!("Health::report, join, error = {e}"),
+            }
+        }
+
+        HealthReport {
+            status: checks
+                .values()
+                .map(|check_report| check_report.status)
+                .max()
+                .unwrap_or(HealthStatus::Pass),
+            ready: self.is_ready(),
+            checks,
+        }
+    }
+}
+
+/// Reads from the store, which fails if e.g. the database can not be opened
+pub struct StoreCheck(pub Arc<dyn Store>);
+
+#[async_trait]
+impl HealthCheck for StoreCheck {
+    async fn check(&self) -> Result<(), BoxError> {
+        self.0.list_users(0, 1).await.map(|_| ())
+    }
+}
+
+pub struct MailerCheck(pub Arc<dyn Mailer>);
+
+#[async_trait]
+impl HealthCheck for MailerCheck {
+    async fn check(&self) -> Result<(), BoxError> {
+        self.0.check().await
+    }
+}
+
+/// The recorded audit events are lost if the writer stopped
+pub struct AuditWriterCheck(pub AuditLog);
+
+#[async_trait]
+impl HealthCheck for AuditWriterCheck {
+    async fn check(&self) -> Result<(), BoxError> {
+        if self.0.is_writer_running() {
+            Ok(())
+        } else {
+            Err("the audit writer is not running".into())
+        }
+    }
+}
+
+/// Beaten by a recurring task, the check fails if the task has not run for too long, e.g., it hangs
+pub struct Heartbeat {
+    last_beat_at: AtomicU64,
+    max_age: Duration,
+}
+
+impl Heartbeat {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            last_beat_at: AtomicU64::new(timestamp::now()),
+            max_age,
+        }
+    }
+
+    pub fn beat(&self) {
+        self.last_beat_at.store(timestamp::now(), Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl HealthCheck for Heartbeat {
+    async fn check(&self) -> Result<(), BoxError> {
+        let last_beat_at: UnixTimestamp = self.last_beat_at.load(Ordering::Relaxed);
+        let age = timestamp::now().saturating_sub(last_beat_at);
+        if age <= self.max_age.as_secs() {
+            Ok(())
+        } else {
+            Err(format!("the last beat was {age} seconds ago").into())
+        }
+    }
+}
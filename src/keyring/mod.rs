@@ -0,0 +1,377 @@
+mod signing_key;
+
+pub use signing_key::{SigningAlgorithm, SigningKey};
+
+use std::time::Duration;
+
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    config::SigningKeyConfig,
+    error::BoxError,
+    timestamp::{self, UnixTimestamp},
+};
+
+/// The algorithm of the tokens signed with the internal keys of the signing keys
+const INTERNAL_ALGORITHM: Algorithm = Algorithm::HS512;
+
+/// Loads the configured keys in the configured order
+pub fn load_keys(
+    algorithm: SigningAlgorithm,
+    key_configs: &[SigningKeyConfig],
+) -> Result<Vec<SigningKey>, BoxError> {
+    key_configs
+        .iter()
+        .map(|key_config| SigningKey::load(algorithm, key_config))
+        .collect()
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct SigningKeyInfo {
+    pub kid: String,
+    pub algorithm: SigningAlgorithm,
+    /// when the key was removed from the configuration, it verifies until its tokens expire
+    pub retired_at: Option<UnixTimestamp>,
+    pub active: bool,
+}
+
+/// The keys used to sign and verify the JWTs. The first configured key signs, the other
+/// configured keys and the retired ones only verify.
+///
+/// The keys are rotated through the configuration: a new key is put first, the previous one is
+/// kept in the list or removed. A removed key is retired, it is accepted until every token it
+/// signed has expired. Instances behind a load balancer share the configuration, so they share
+/// the keys too.
+pub struct Keyring {
+    /// the configured keys in the configured order followed by the retired keys, the first one
+    /// is the active key
+    keys: Vec<SigningKey>,
+    /// the longest lifetime of the tokens signed by the keys
+    token_lifetime: Duration,
+}
+
+impl Keyring {
+    /// The first key is the active one, the keys can not be empty
+    pub fn new(keys: Vec<SigningKey>, token_lifetime: Duration) -> Self {
+        Self {
+            keys,
+            token_lifetime,
+        }
+    }
+
+    /// Changes how long the retired keys are kept, e.g., when the token lifetime is reconfigured
+    pub fn set_token_lifetime(&mut self, token_lifetime: Duration) {
+        self.token_lifetime = token_lifetime;
+    }
+
+    pub fn active_kid(&self) -> &str {
+        &self.active_key().kid
+    }
+
+    pub fn key_infos(&self) -> Vec<SigningKeyInfo> {
+        let active_kid = &self.active_key().kid;
+
+        self.keys
+            .iter()
+            .map(|key| SigningKeyInfo {
+                kid: key.kid.clone(),
+                algorithm: key.algorithm,
+                retired_at: key.retired_at,
+                active: key.kid == *active_kid,
+            })
+            .collect()
+    }
+
+    /// The public keys in JWK format, symmetric keys are not included
+    pub fn public_jwks(&self) -> Vec<serde_json::Value> {
+        self.keys
+            .iter()
+            .filter_map(|key| key.public_jwk.clone())
+            .collect()
+    }
+
+    /// Replaces the configured keys, e.g., when the configuration is reloaded. The keys that are
+    /// not configured anymore are retired, the first key is the new active one. The keys can not
+    /// be empty.
+    pub fn set_keys(&mut self, keys: Vec<SigningKey>) {
+        let now = timestamp::now();
+        let mut retired_keys = std::mem::take(&mut self.keys)
+            .into_iter()
+            .filter(|retired_key| keys.iter().all(|key| key.kid != retired_key.kid))
+            .map(|mut retired_key| {
+                if retired_key.retired_at.is_none() {
+                    log::info!("Signing key retired, kid = '{}'", retired_key.kid);
+                    retired_key.retired_at = Some(now);
+                }
+                retired_key
+            })
+            .collect();
+
+        self.keys = keys;
+        self.keys.append(&mut retired_keys);
+    }
+
+    /// Removes the retired keys whose tokens have all expired
+    pub fn remove_expired_keys(&mut self) {
+        let now = timestamp::now();
+        let token_lifetime = self.token_lifetime.as_secs();
+
+        self.keys.retain(|key| match key.retired_at {
+            Some(retired_at) if retired_at + token_lifetime < now => {
+                log::info!("Signing key removed, kid = '{}'", key.kid);
+                false
+            }
+            _ => true,
+        });
+    }
+
+    pub fn encode(&self, claims: &impl Serialize) -> Result<String, ()> {
+        let key = self.active_key();
+
+        sign(claims, key.jwt_algorithm(), &key.kid, &key.encoding_key)
+    }
+
+    /// Verifies the signature and the claims checked by the validation. The algorithm of the
+    /// validation is replaced by the one of the key.
+    pub fn decode<ClaimsType: DeserializeOwned>(
+        &self,
+        token: &str,
+        validation: &Validation,
+    ) -> Result<ClaimsType, ()> {
+        let key = self.find_key(token)?;
+
+        verify(token, key.jwt_algorithm(), &key.decoding_key, validation)
+    }
+
+    /// Signs a token only this application verifies with the internal key of the active key.
+    /// The internal keys are not in the JWKS, so the services verifying the access tokens
+    /// against it can not be tricked into accepting these tokens.
+    pub fn encode_internal(&self, claims: &impl Serialize) -> Result<String, ()> {
+        let key = self.active_key();
+
+        sign(
+            claims,
+            INTERNAL_ALGORITHM,
+            &key.kid,
+            &key.internal_encoding_key,
+        )
+    }
+
+    /// Verifies a token signed by [`Keyring::encode_internal`]
+    pub fn decode_internal<ClaimsType: DeserializeOwned>(
+        &self,
+        token: &str,
+        validation: &Validation,
+    ) -> Result<ClaimsType, ()> {
+        let key = self.find_key(token)?;
+
+        verify(
+            token,
+            INTERNAL_ALGORITHM,
+            &key.internal_decoding_key,
+            validation,
+        )
+    }
+
+    fn find_key(&self, token: &str) -> Result<&SigningKey, ()> {
+        let kid = jsonwebtoken::decode_header(token)
+            .inspect_err(|e| log::error!("Keyring::decode, decode_header, error = {e}"))
+            .map_err(|_| ())?
+            .kid
+            .ok_or_else(|| log::error!("Keyring::decode, token has no kid"))?;
+
+        self.keys
+            .iter()
+            .find(|key| key.kid == kid)
+            .ok_or_else(|| log::error!("Keyring::decode, unknown kid = '{kid}'"))
+    }
+
+    fn active_key(&self) -> &SigningKey {
+        // there is always at least one configured key, only retired keys are removed
+        &self.keys[0]
+    }
+}
+
+fn sign(
+    claims: &impl Serialize,
+    algorithm: Algorithm,
+    kid: &str,
+    encoding_key: &EncodingKey,
+) -> Result<String, ()> {
+    let mut header = Header::new(algorithm);
+    header.kid = Some(kid.to_string());
+
+    jsonwebtoken::encode(&header, claims, encoding_key)
+        .inspect_err(|e| log::error!("Keyring::encode, error = {e}"))
+        .map_err(|_| ())
+}
+
+fn verify<ClaimsType: DeserializeOwned>(
+    token: &str,
+    algorithm: Algorithm,
+    decoding_key: &DecodingKey,
+    validation: &Validation,
+) -> Result<ClaimsType, ()> {
+    // the algorithm belongs to the key, the alg header of the token is not trusted
+    let mut validation = validation.clone();
+    validation.algorithms = vec![algorithm];
+
+    jsonwebtoken::decode::<ClaimsType>(token, decoding_key, &validation)
+        .map(|token_data| token_data.claims)
+        .inspect_err(|e| log::error!("Keyring::decode, decode, error = {e}"))
+        .map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn key(kid: &str) -> SigningKey {
+        SigningKey::hmac(format!("the secret of the key {kid}").as_bytes()).with_kid(kid.into())
+    }
+
+    fn token(keyring: &Keyring) -> String {
+        keyring
+            .encode(&json!({ "sub": "user", "exp": timestamp::now() + 60 }))
+            .unwrap()
+    }
+
+    fn kid_of(token: &str) -> String {
+        jsonwebtoken::decode_header(token).unwrap().kid.unwrap()
+    }
+
+    fn verifies(keyring: &Keyring, token: &str) -> bool {
+        keyring
+            .decode::<Value>(token, &Validation::default())
+            .is_ok()
+    }
+
+    #[test]
+    fn the_first_key_signs_and_every_key_verifies() {
+        let keyring = Keyring::new(vec![key("new"), key("old")], Duration::from_secs(60));
+        let old_keyring = Keyring::new(vec![key("old")], Duration::from_secs(60));
+
+        let new_token = token(&keyring);
+
+        assert_eq!(kid_of(&new_token), "new");
+        assert!(verifies(&keyring, &new_token));
+        assert!(verifies(&keyring, &token(&old_keyring)));
+        assert!(!verifies(&old_keyring, &new_token));
+    }
+
+    #[test]
+    fn removed_keys_verify_until_their_tokens_expire() {
+        let mut keyring = Keyring::new(vec![key("old")], Duration::from_secs(60));
+        let old_token = token(&keyring);
+
+        keyring.set_keys(vec![key("new")]);
+
+        assert_eq!(keyring.active_kid(), "new");
+        assert_eq!(kid_of(&token(&keyring)), "new");
+        assert!(verifies(&keyring, &old_token));
+        let key_infos = keyring.key_infos();
+        assert!(key_infos[0].active && key_infos[0].retired_at.is_none());
+        assert!(!key_infos[1].active && key_infos[1].retired_at.is_some());
+
+        keyring.remove_expired_keys();
+        assert!(verifies(&keyring, &old_token));
+
+        keyring.keys[1].retired_at = Some(timestamp::now() - 61);
+        keyring.remove_expired_keys();
+        assert!(!verifies(&keyring, &old_token));
+        assert_eq!(keyring.key_infos().len(), 1);
+    }
+
+    #[test]
+    fn internal_tokens_are_only_verified_as_internal_tokens() {
+        let keyring = Keyring::new(vec![key("key")], Duration::from_secs(60));
+        let claims = json!({ "sub": "user", "exp": timestamp::now() + 60 });
+
+        let internal_token = keyring.encode_internal(&claims).unwrap();
+        let access_token = token(&keyring);
+
+        assert_eq!(kid_of(&internal_token), "key");
+        assert!(keyring
+            .decode_internal::<Value>(&internal_token, &Validation::default())
+            .is_ok());
+        assert!(!verifies(&keyring, &internal_token));
+        assert!(keyring
+            .decode_internal::<Value>(&access_token, &Validation::default())
+            .is_err());
+    }
+
+    #[test]
+    fn keys_configured_again_are_not_retired() {
+        let mut keyring = Keyring::new(vec![key("old")], Duration::from_secs(60));
+
+        keyring.set_keys(vec![key("new")]);
+        keyring.set_keys(vec![key("new"), key("old")]);
+
+        let key_infos = keyring.key_infos();
+        assert_eq!(key_infos.len(), 2);
+        assert!(key_infos
+            .iter()
+            .all(|key_info| key_info.retired_at.is_none()));
+    }
+
+    #[test]
+    fn the_kid_selects_the_verifying_key() {
+        let ec_key = SigningKey::generate(SigningAlgorithm::Es256).unwrap();
+        let ec_kid = ec_key.kid.clone();
+        let mut keyring = Keyring::new(vec![ec_key], Duration::from_secs(60));
+        let ec_token = token(&keyring);
+        assert_eq!(kid_of(&ec_token), ec_kid);
+
+        // rotated to a key of another algorithm, the retired key still verifies its tokens
+        keyring.set_keys(vec![SigningKey::generate(SigningAlgorithm::EdDsa).unwrap()]);
+        assert_ne!(kid_of(&token(&keyring)), ec_kid);
+        assert!(verifies(&keyring, &ec_token));
+
+        // a key with the same kid but another key material does not verify it
+        let impostor_keyring = Keyring::new(
+            vec![SigningKey::generate(SigningAlgorithm::Es256)
+                .unwrap()
+                .with_kid(ec_kid)],
+            Duration::from_secs(60),
+        );
+        assert!(!verifies(&impostor_keyring, &ec_token));
+        // nor a keyring without the kid
+        let other_keyring = Keyring::new(
+            vec![SigningKey::generate(SigningAlgorithm::Rs256).unwrap()],
+            Duration::from_secs(60),
+        );
+        assert!(!verifies(&other_keyring, &ec_token));
+    }
+
+    #[test]
+    fn the_jwks_has_the_public_keys_only() {
+        let keyring = Keyring::new(
+            vec![
+                SigningKey::generate(SigningAlgorithm::Rs256).unwrap(),
+                SigningKey::generate(SigningAlgorithm::Es256).unwrap(),
+                SigningKey::generate(SigningAlgorithm::EdDsa).unwrap(),
+                key("hmac"),
+            ],
+            Duration::from_secs(60),
+        );
+
+        let public_jwks = keyring.public_jwks();
+
+        assert_eq!(public_jwks.len(), 3);
+        for (public_jwk, key_info) in public_jwks.iter().zip(keyring.key_infos()) {
+            assert_eq!(public_jwk["kid"], key_info.kid);
+            assert_eq!(public_jwk["use"], "sig");
+            assert_ne!(public_jwk["kty"], "oct");
+            // the private parameters of the RSA, EC and OKP keys and the secret of oct keys
+            for private_parameter in ["d", "p", "q", "dp", "dq", "qi", "oth", "k"] {
+                assert!(
+                    public_jwk.get(private_parameter).is_none(),
+                    "{private_parameter} in {public_jwk}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use rand_core::OsRng;
+use rsa::{
+    pkcs1::DecodeRsaPrivateKey,
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding},
+    traits::PublicKeyParts,
+    RsaPrivateKey,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{config::SigningKeyConfig, error::BoxError, secret, timestamp::UnixTimestamp};
+
+const RSA_KEY_BITS: usize = 2048;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+pub enum SigningAlgorithm {
+    #[value(name = "HS512")]
+    #[serde(rename = "HS512")]
+    Hs512,
+    #[value(name = "RS256")]
+    #[serde(rename = "RS256")]
+    Rs256,
+    #[value(name = "ES256")]
+    #[serde(rename = "ES256")]
+    Es256,
+    #[value(name = "EdDSA")]
+    #[serde(rename = "EdDSA")]
+    EdDsa,
+}
+
+impl SigningAlgorithm {
+    pub fn is_symmetric(&self) -> bool {
+        *self == Self::Hs512
+    }
+
+    fn jwt_algorithm(&self) -> Algorithm {
+        match self {
+            Self::Hs512 => Algorithm::HS512,
+            Self::Rs256 => Algorithm::RS256,
+            Self::Es256 => Algorithm::ES256,
+            Self::EdDsa => Algorithm::EdDSA,
+        }
+    }
+}
+
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: SigningAlgorithm,
+    pub encoding_key: EncodingKey,
+    pub decoding_key: DecodingKey,
+    /// the public key as a JWK, None for symmetric keys
+    pub public_jwk: Option<serde_json::Value>,
+    /// The HMAC key of the tokens only this application verifies, e.g., the password reset
+    /// tokens. It is derived from the private key material, so it is never published, and the
+    /// instances sharing the key derive the same one.
+    pub internal_encoding_key: EncodingKey,
+    pub internal_decoding_key: DecodingKey,
+    /// When the key was removed from the configuration. The key is still accepted for
+    /// verification until every token it signed has expired.
+    pub retired_at: Option<UnixTimestamp>,
+}
+
+impl SigningKey {
+    pub fn hmac(secret: &[u8]) -> Self {
+        Self::new(
+            // the kid is derived from the secret, so instances sharing the secret use the same kid
+            derive_kid(secret),
+            SigningAlgorithm::Hs512,
+            EncodingKey::from_secret(secret),
+            DecodingKey::from_secret(secret),
+            None,
+            secret,
+        )
+    }
+
+    /// Loads a private key in PKCS#8 PEM format (RSA keys can be in PKCS#1, EC keys in SEC1
+    /// format as well)
+    pub fn from_pem(algorithm: SigningAlgorithm, pem: &str) -> Result<Self, BoxError> {
+        match algorithm {
+            SigningAlgorithm::Hs512 => Err("HS512 keys cannot be loaded from PEM files".into()),
+            SigningAlgorithm::Rs256 => {
+                let private_key = RsaPrivateKey::from_pkcs8_pem(pem)
+                    .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+                    .map_err(|e| format!("could not parse the RSA private key: {e}"))?;
+                Self::from_rsa_key(private_key)
+            }
+            SigningAlgorithm::Es256 => {
+                let private_key = p256::SecretKey::from_pkcs8_pem(pem)
+                    .or_else(|_| p256::SecretKey::from_sec1_pem(pem))
+                    .map_err(|e| format!("could not parse the P-256 private key: {e}"))?;
+                Self::from_p256_key(private_key)
+            }
+            SigningAlgorithm::EdDsa => {
+                let private_key = ed25519_dalek::SigningKey::from_pkcs8_pem(pem)
+                    .map_err(|e| format!("could not parse the Ed25519 private key: {e}"))?;
+                Self::from_ed25519_key(private_key)
+            }
+        }
+    }
+
+    /// Loads a configured key, an HS512 secret is base64 encoded in the file, the other keys are
+    /// PEM files
+    pub fn load(
+        algorithm: SigningAlgorithm,
+        key_config: &SigningKeyConfig,
+    ) -> Result<Self, BoxError> {
+        let key = if algorithm.is_symmetric() {
+            Self::hmac(&secret::load(None, Some(&key_config.file))?)
+        } else {
+            let pem = std::fs::read_to_string(&key_config.file).map_err(|e| {
+                format!(
+                    "could not read the JWT private key file '{}': {e}",
+                    key_config.file.display()
+                )
+            })?;
+            Self::from_pem(algorithm, &pem)?
+        };
+
+        Ok(key.with_kid(key_config.kid.clone()))
+    }
+
+    /// Replaces the kid derived from the key material
+    pub fn with_kid(mut self, kid: String) -> Self {
+        if let Some(public_jwk) = &mut self.public_jwk {
+            public_jwk["kid"] = json!(kid);
+        }
+        self.kid = kid;
+
+        self
+    }
+
+    pub fn generate(algorithm: SigningAlgorithm) -> Result<Self, BoxError> {
+        match algorithm {
+            SigningAlgorithm::Hs512 => {
+                let mut secret = vec![0; secret::MIN_SECRET_LENGTH];
+                getrandom::getrandom(&mut secret)?;
+                Ok(Self::hmac(&secret))
+            }
+            SigningAlgorithm::Rs256 => {
+                Self::from_rsa_key(RsaPrivateKey::new(&mut OsRng, RSA_KEY_BITS)?)
+            }
+            SigningAlgorithm::Es256 => Self::from_p256_key(p256::SecretKey::random(&mut OsRng)),
+            SigningAlgorithm::EdDsa => {
+                Self::from_ed25519_key(ed25519_dalek::SigningKey::generate(&mut OsRng))
+            }
+        }
+    }
+
+    fn from_rsa_key(private_key: RsaPrivateKey) -> Result<Self, BoxError> {
+        let pem = private_key.to_pkcs8_pem(LineEnding::LF)?;
+        let n = private_key.n().to_bytes_be();
+        let e = private_key.e().to_bytes_be();
+
+        let (n, e) = (URL_SAFE_NO_PAD.encode(&n), URL_SAFE_NO_PAD.encode(&e));
+        let decoding_key = DecodingKey::from_rsa_components(&n, &e)?;
+
+        Ok(Self::new(
+            derive_kid(format!("{n}.{e}").as_bytes()),
+            SigningAlgorithm::Rs256,
+            EncodingKey::from_rsa_pem(pem.as_bytes())?,
+            decoding_key,
+            Some(json!({ "kty": "RSA", "n": n, "e": e })),
+            pem.as_bytes(),
+        ))
+    }
+
+    fn from_p256_key(private_key: p256::SecretKey) -> Result<Self, BoxError> {
+        let pem = private_key.to_pkcs8_pem(LineEnding::LF)?;
+        let public_point = private_key.public_key().to_encoded_point(false);
+        let (x, y) = public_point
+            .x()
+            .zip(public_point.y())
+            .ok_or("the P-256 public key has no affine coordinates")?;
+
+        let (x, y) = (URL_SAFE_NO_PAD.encode(x), URL_SAFE_NO_PAD.encode(y));
+        let decoding_key = DecodingKey::from_ec_components(&x, &y)?;
+
+        Ok(Self::new(
+            derive_kid(format!("{x}.{y}").as_bytes()),
+            SigningAlgorithm::Es256,
+            EncodingKey::from_ec_pem(pem.as_bytes())?,
+            decoding_key,
+            Some(json!({ "kty": "EC", "crv": "P-256", "x": x, "y": y })),
+            pem.as_bytes(),
+        ))
+    }
+
+    fn from_ed25519_key(private_key: ed25519_dalek::SigningKey) -> Result<Self, BoxError> {
+        let pem = private_key.to_pkcs8_pem(LineEnding::LF)?;
+
+        let x = URL_SAFE_NO_PAD.encode(private_key.verifying_key().as_bytes());
+        let decoding_key = DecodingKey::from_ed_components(&x)?;
+
+        Ok(Self::new(
+            derive_kid(x.as_bytes()),
+            SigningAlgorithm::EdDsa,
+            EncodingKey::from_ed_pem(pem.as_bytes())?,
+            decoding_key,
+            Some(json!({ "kty": "OKP", "crv": "Ed25519", "x": x })),
+            pem.as_bytes(),
+        ))
+    }
+
+    fn new(
+        kid: String,
+        algorithm: SigningAlgorithm,
+        encoding_key: EncodingKey,
+        decoding_key: DecodingKey,
+        public_jwk: Option<serde_json::Value>,
+        private_key_material: &[u8],
+    ) -> Self {
+        let public_jwk = public_jwk.map(|mut public_jwk| {
+            public_jwk["kid"] = json!(kid);
+            public_jwk["use"] = json!("sig");
+            public_jwk["alg"] = json!(format!("{:?}", algorithm.jwt_algorithm()));
+            public_jwk
+        });
+
+        let internal_secret = derive_internal_secret(private_key_material);
+
+        Self {
+            kid,
+            algorithm,
+            encoding_key,
+            decoding_key,
+            public_jwk,
+            internal_encoding_key: EncodingKey::from_secret(&internal_secret),
+            internal_decoding_key: DecodingKey::from_secret(&internal_secret),
+            retired_at: None,
+        }
+    }
+
+    pub fn jwt_algorithm(&self) -> Algorithm {
+        self.algorithm.jwt_algorithm()
+    }
+}
+
+fn derive_kid(key_material: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(&Sha256::digest(key_material)[..12])
+}
+
+/// The kid is derived from the public part, this one from the private part, which is not
+/// revealed by the hash
+fn derive_internal_secret(private_key_material: &[u8]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(b"internal-token-key:")
+        .chain_update(private_key_material)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use jsonwebtoken::{jwk::Jwk, Header, Validation};
+    use rsa::pkcs1::EncodeRsaPrivateKey;
+    use serde_json::Value;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::timestamp;
+
+    const ASYMMETRIC_ALGORITHMS: [SigningAlgorithm; 3] = [
+        SigningAlgorithm::Rs256,
+        SigningAlgorithm::Es256,
+        SigningAlgorithm::EdDsa,
+    ];
+
+    fn token(key: &SigningKey) -> String {
+        let mut header = Header::new(key.jwt_algorithm());
+        header.kid = Some(key.kid.clone());
+
+        jsonwebtoken::encode(
+            &header,
+            &json!({ "sub": "user", "exp": timestamp::now() + 60 }),
+            &key.encoding_key,
+        )
+        .unwrap()
+    }
+
+    fn verifies(key: &SigningKey, decoding_key: &DecodingKey, token: &str) -> bool {
+        jsonwebtoken::decode::<Value>(token, decoding_key, &Validation::new(key.jwt_algorithm()))
+            .is_ok()
+    }
+
+    /// The JWK the services verifying the tokens use
+    fn published_key(key: &SigningKey) -> DecodingKey {
+        let jwk: Jwk = serde_json::from_value(key.public_jwk.clone().unwrap()).unwrap();
+        DecodingKey::from_jwk(&jwk).unwrap()
+    }
+
+    fn temp_file(content: &str) -> PathBuf {
+        let file = std::env::temp_dir().join(format!("signing-key-{}", Uuid::new_v4()));
+        std::fs::write(&file, content).unwrap();
+        file
+    }
+
+    #[test]
+    fn generated_keys_verify_their_tokens() {
+        for algorithm in ASYMMETRIC_ALGORITHMS {
+            let key = SigningKey::generate(algorithm).unwrap();
+            let other_key = SigningKey::generate(algorithm).unwrap();
+            let token = token(&key);
+
+            assert!(verifies(&key, &key.decoding_key, &token), "{algorithm:?}");
+            assert!(
+                verifies(&key, &published_key(&key), &token),
+                "{algorithm:?}"
+            );
+            assert!(
+                !verifies(&key, &other_key.decoding_key, &token),
+                "{algorithm:?}"
+            );
+        }
+
+        let key = SigningKey::generate(SigningAlgorithm::Hs512).unwrap();
+        assert!(key.public_jwk.is_none());
+        assert!(verifies(&key, &key.decoding_key, &token(&key)));
+    }
+
+    #[test]
+    fn keys_are_loaded_from_pem_files_in_every_supported_format() {
+        let rsa_key = RsaPrivateKey::new(&mut OsRng, RSA_KEY_BITS).unwrap();
+        let p256_key = p256::SecretKey::random(&mut OsRng);
+        let ed25519_key = ed25519_dalek::SigningKey::generate(&mut OsRng);
+
+        let pems = [
+            (
+                SigningAlgorithm::Rs256,
+                rsa_key.to_pkcs8_pem(LineEnding::LF).unwrap().to_string(),
+            ),
+            (
+                SigningAlgorithm::Rs256,
+                rsa_key.to_pkcs1_pem(LineEnding::LF).unwrap().to_string(),
+            ),
+            (
+                SigningAlgorithm::Es256,
+                p256_key.to_pkcs8_pem(LineEnding::LF).unwrap().to_string(),
+            ),
+            (
+                SigningAlgorithm::Es256,
+                p256_key.to_sec1_pem(LineEnding::LF).unwrap().to_string(),
+            ),
+            (
+                SigningAlgorithm::EdDsa,
+                ed25519_key
+                    .to_pkcs8_pem(LineEnding::LF)
+                    .unwrap()
+                    .to_string(),
+            ),
+        ];
+
+        for (algorithm, pem) in pems {
+            let key = SigningKey::load(
+                algorithm,
+                &SigningKeyConfig {
+                    kid: "configured".to_string(),
+                    file: temp_file(&pem),
+                },
+            )
+            .unwrap();
+
+            assert_eq!(key.kid, "configured");
+            assert_eq!(key.public_jwk.as_ref().unwrap()["kid"], "configured");
+            assert!(
+                verifies(&key, &published_key(&key), &token(&key)),
+                "{algorithm:?}"
+            );
+            // the instances loading the same file derive the same internal key
+            let same_key = SigningKey::from_pem(algorithm, &pem).unwrap();
+            let internal_token = jsonwebtoken::encode(
+                &Header::new(Algorithm::HS512),
+                &json!({ "exp": timestamp::now() + 60 }),
+                &key.internal_encoding_key,
+            )
+            .unwrap();
+            assert!(jsonwebtoken::decode::<Value>(
+                &internal_token,
+                &same_key.internal_decoding_key,
+                &Validation::new(Algorithm::HS512)
+            )
+            .is_ok());
+        }
+    }
+
+    #[test]
+    fn keys_of_another_algorithm_are_not_loaded() {
+        let p256_pem = p256::SecretKey::random(&mut OsRng)
+            .to_pkcs8_pem(LineEnding::LF)
+            .unwrap();
+
+        assert!(SigningKey::from_pem(SigningAlgorithm::Rs256, &p256_pem).is_err());
+        assert!(SigningKey::from_pem(SigningAlgorithm::EdDsa, &p256_pem).is_err());
+        assert!(SigningKey::from_pem(SigningAlgorithm::Hs512, &p256_pem).is_err());
+    }
+
+    #[test]
+    fn hmac_keys_are_loaded_from_base64_secret_files() {
+        let key_config = SigningKeyConfig {
+            kid: "configured".to_string(),
+            file: temp_file(&secret::generate().unwrap()),
+        };
+
+        let key = SigningKey::load(SigningAlgorithm::Hs512, &key_config).unwrap();
+        let same_key = SigningKey::load(SigningAlgorithm::Hs512, &key_config).unwrap();
+
+        assert_eq!(key.kid, "configured");
+        assert!(key.public_jwk.is_none());
+        assert!(verifies(&key, &same_key.decoding_key, &token(&key)));
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// The delay after the first failed attempt, it doubles with every further failure
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Many users can share an address (e.g., behind a NAT), so an address is locked out after this
+/// many times more failures than an account
+const IP_FAILURE_MULTIPLIER: u32 = 10;
+
+struct FailureCounter {
+    failures: u32,
+    blocked_until: Instant,
+    last_failure_at: Instant,
+}
+
+/// Failed login attempts per account and per client address. After every failure the next attempt
+/// is allowed only after an exponentially growing delay, after `max_failures` failures the account
+/// is locked out for `lockout_duration`.
+///
+/// The counters are kept in memory, independently of the user store, so they are reset by a
+/// restart and not shared between instances.
+pub struct LoginThrottle {
+    max_failures: u32,
+    lockout_duration: Duration,
+    accounts: HashMap<String, FailureCounter>,
+    ips: HashMap<IpAddr, FailureCounter>,
+}
+
+impl LoginThrottle {
+    pub fn new(max_failures: u32, lockout_duration: Duration) -> Self {
+        Self {
+            max_failures: max_failures.max(1),
+            lockout_duration,
+            accounts: HashMap::new(),
+            ips: HashMap::new(),
+        }
+    }
+
+    /// Changes the limits, e.g., when the configuration is reloaded. The running lockouts keep
+    /// their end.
+    pub fn set_limits(&mut self, max_failures: u32, lockout_duration: Duration) {
+        self.max_failures = max_failures.max(1);
+        self.lockout_duration = lockout_duration;
+    }
+
+    /// Returns the time after which the next attempt is allowed if the account or the address is
+    /// blocked
+    pub fn check(&self, loginname: &str, ip: Option<IpAddr>) -> Result<(), Duration> {
+        let now = Instant::now();
+
+        let blocked_until = [
+            self.accounts.get(loginname),
+            ip.and_then(|ip| self.ips.get(&ip)),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|counter| counter.blocked_until)
+        .max();
+
+        match blocked_until {
+            Some(blocked_until) if blocked_until > now => Err(blocked_until - now),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record_failure(&mut self, loginname: &str, ip: Option<IpAddr>) {
+        let max_failures = self.max_failures;
+        let lockout_duration = self.lockout_duration;
+
+        let failures = Self::increment(
+            &mut self.accounts,
+            loginname.to_string(),
+            max_failures,
+            lockout_duration,
+        );
+        if failures == max_failures {
+            log::warn!("Account locked out, loginname = '{loginname}', failures = {failures}");
+        }
+
+        if let Some(ip) = ip {
+            let max_failures = max_failures.saturating_mul(IP_FAILURE_MULTIPLIER);
+            let failures = Self::increment(&mut self.ips, ip, max_failures, lockout_duration);
+            if failures == max_failures {
+                log::warn!("Address locked out, ip = '{ip}', failures = {failures}");
+            }
+        }
+    }
+
+    /// Resets the counter of the account. The counter of the address is kept, otherwise an
+    /// attacker could reset it by logging in with an own account from time to time.
+    pub fn record_success(&mut self, loginname: &str) {
+        self.accounts.remove(loginname);
+    }
+
+    /// Returns false if the account was not throttled
+    pub fn unlock(&mut self, loginname: &str) -> bool {
+        self.accounts.remove(loginname).is_some()
+    }
+
+    /// Forgets the counters whose last failure is older than the lockout duration and that do
+    /// not block anymore
+    pub fn remove_stale_counters(&mut self) {
+        let now = Instant::now();
+        let lockout_duration = self.lockout_duration;
+        let is_active = |counter: &FailureCounter| {
+            counter.blocked_until > now || counter.last_failure_at + lockout_duration > now
+        };
+
+        self.accounts.retain(|_, counter| is_active(counter));
+        self.ips.retain(|_, counter| is_active(counter));
+    }
+
+    fn increment<K: Eq + Hash>(
+        counters: &mut HashMap<K, FailureCounter>,
+        key: K,
+        max_failures: u32,
+        lockout_duration: Duration,
+    ) -> u32 {
+        let now = Instant::now();
+        let counter = counters.entry(key).or_insert(FailureCounter {
+            failures: 0,
+            blocked_until: now,
+            last_failure_at: now,
+        });
+
+        counter.failures = counter.failures.saturating_add(1);
+        counter.last_failure_at = now;
+        counter.blocked_until = now
+            + if counter.failures >= max_failures {
+                lockout_duration
+            } else {
+                BASE_BACKOFF
+                    .saturating_mul(1 << (counter.failures - 1).min(16))
+                    .min(MAX_BACKOFF)
+            };
+
+        counter.failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn retry_after(throttle: &LoginThrottle, loginname: &str, ip: Option<IpAddr>) -> Duration {
+        throttle.check(loginname, ip).unwrap_err()
+    }
+
+    #[test]
+    fn the_backoff_doubles_with_every_failure() {
+        let mut throttle = LoginThrottle::new(5, LOCKOUT_DURATION);
+        assert!(throttle.check("user", None).is_ok());
+
+        throttle.record_failure("user", None);
+        let first = retry_after(&throttle, "user", None);
+        assert!(first > Duration::ZERO && first <= BASE_BACKOFF);
+
+        throttle.record_failure("user", None);
+        let second = retry_after(&throttle, "user", None);
+        assert!(second > BASE_BACKOFF && second <= 2 * BASE_BACKOFF);
+
+        // the other accounts are not affected
+        assert!(throttle.check("other", None).is_ok());
+    }
+
+    #[test]
+    fn the_account_is_locked_out_at_the_threshold() {
+        let mut throttle = LoginThrottle::new(3, LOCKOUT_DURATION);
+
+        for _ in 0..2 {
+            throttle.record_failure("user", None);
+        }
+        assert!(retry_after(&throttle, "user", None) <= MAX_BACKOFF);
+
+        throttle.record_failure("user", None);
+        let lockout = retry_after(&throttle, "user", None);
+        assert!(lockout > MAX_BACKOFF && lockout <= LOCKOUT_DURATION);
+    }
+
+    #[test]
+    fn a_successful_login_resets_the_account_but_not_the_address() {
+        let mut throttle = LoginThrottle::new(3, LOCKOUT_DURATION);
+        throttle.record_failure("user", Some(IP));
+
+        throttle.record_success("user");
+
+        assert!(throttle.check("user", None).is_ok());
+        assert!(throttle.check("other", Some(IP)).is_err());
+    }
+
+    #[test]
+    fn unlocking_lifts_the_lockout() {
+        let mut throttle = LoginThrottle::new(1, LOCKOUT_DURATION);
+        throttle.record_failure("user", None);
+        assert!(throttle.check("user", None).is_err());
+
+        assert!(throttle.unlock("user"));
+
+        assert!(throttle.check("user", None).is_ok());
+        assert!(!throttle.unlock("user"));
+    }
+}
@@ -0,0 +1,66 @@
+use std::path::PathBuf;
+
+use axum::async_trait;
+
+use crate::{
+    error::BoxError,
+    syn::{arc_mutex_new, ArcMutex},
+};
+
+use super::{Mail, Mailer};
+
+/// Appends the mails to a file instead of sending them
+pub struct FileMailer {
+    path: PathBuf,
+    // keeps the mails of concurrent sends from interleaving
+    lock: ArcMutex<()>,
+}
+
+impl FileMailer {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: arc_mutex_new(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: Mail) -> Result<(), BoxError> {
+        let content = format!(
+            "To: {}\nSubject: {}\n\n{}\n\n",
+            mail.to, mail.subject, mail.body
+        );
+
+        let path = self.path.clone();
+        let lock = self.lock.clone();
+        tokio::task::spawn_blocking(move || {
+            let _guard = lock.lock();
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| std::io::Write::write_all(&mut file, content.as_bytes()))
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    /// The file is created by the first mail, its directory has to exist
+    async fn check(&self) -> Result<(), BoxError> {
+        let directory = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => std::path::Path::new("."),
+        };
+        if tokio::fs::metadata(directory)
+            .await
+            .is_ok_and(|metadata| metadata.is_dir())
+        {
+            Ok(())
+        } else {
+            Err(format!("the directory '{}' does not exist", directory.display()).into())
+        }
+    }
+}
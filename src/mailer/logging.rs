@@ -0,0 +1,22 @@
+use axum::async_trait;
+
+use crate::error::BoxError;
+
+use super::{Mail, Mailer};
+
+/// Writes the mails to the log instead of sending them
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, mail: Mail) -> Result<(), BoxError> {
+        log::info!(
+            "Mail, to = '{}', subject = '{}', body =\n{}",
+            mail.to,
+            mail.subject,
+            mail.body
+        );
+
+        Ok(())
+    }
+}
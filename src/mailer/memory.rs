@@ -0,0 +1,36 @@
+use axum::async_trait;
+
+use crate::{
+    error::BoxError,
+    syn::{arc_mutex_new, ArcMutex},
+};
+
+use super::{Mail, Mailer};
+
+/// Keeps the mails in memory, so tests can inspect them without a mail server
+#[derive(Clone, Default)]
+pub struct MemoryMailer {
+    mails: ArcMutex<Vec<Mail>>,
+}
+
+impl MemoryMailer {
+    pub fn new() -> Self {
+        Self {
+            mails: arc_mutex_new(Vec::new()),
+        }
+    }
+
+    /// Returns the mails sent since the last call
+    pub fn take_sent_mails(&self) -> Vec<Mail> {
+        std::mem::take(&mut self.mails.lock())
+    }
+}
+
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, mail: Mail) -> Result<(), BoxError> {
+        self.mails.lock().push(mail);
+
+        Ok(())
+    }
+}
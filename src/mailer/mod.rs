@@ -0,0 +1,52 @@
+mod file;
+mod logging;
+#[cfg(test)]
+mod memory;
+#[cfg(feature = "smtp")]
+mod smtp;
+
+pub use file::FileMailer;
+pub use logging::LogMailer;
+#[cfg(test)]
+pub use memory::MemoryMailer;
+#[cfg(feature = "smtp")]
+pub use smtp::SmtpMailer;
+
+use axum::async_trait;
+
+use crate::error::BoxError;
+
+/// Environment variable of the SMTP URL, it can contain credentials so it is not passed as a
+/// command line argument
+pub const SMTP_URL_ENV_VAR: &str = "APP_SMTP_URL";
+
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Sends the mails of the application, e.g., password reset and email verification mails
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: Mail) -> Result<(), BoxError>;
+
+    /// Checks whether the mails can be sent, used by the health check
+    async fn check(&self) -> Result<(), BoxError> {
+        Ok(())
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum MailerKind {
+    /// writes the mails to the log, for development
+    Log,
+    /// appends the mails to a file, for development
+    File,
+    /// sends the mails through an SMTP server
+    Smtp,
+}
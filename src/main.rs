@@ -1,55 +1,459 @@
+mod access_token;
+mod api_key;
 mod app_state;
+mod audit;
+mod authorization_layer;
 mod cli;
+mod commands;
+mod config;
+mod cors;
+mod csrf;
 mod endpoints;
 mod error;
 mod fn_decorators;
+mod health;
+mod keyring;
+mod login_throttle;
+mod mailer;
 mod messages;
 mod model;
+mod oidc;
+mod opaque_token;
+mod password;
+mod permissions;
+mod secret;
+mod shutdown;
+mod store;
 mod syn;
+mod timestamp;
+mod totp;
 
-use std::net::ToSocketAddrs;
+use std::{
+    collections::BTreeSet,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::Arc,
+};
 
-use app_state::AppState;
-use axum_helpers::app::AxumApp;
+use access_token::NoCustomClaims;
+use app_state::{AppState, AppStateDependencies};
+use audit::{AuditSink, AuditSinkKind, FileAuditSink};
+use axum_helpers::app::AxumAppState;
 
 use clap::Parser;
-use cli::Cli;
+use cli::{Cli, Command};
+use config::{watch::ReloadTriggers, Config};
 use error::BoxError;
+use health::Heartbeat;
+use keyring::SigningKey;
+use mailer::{FileMailer, LogMailer, Mailer, MailerKind};
+use oidc::{OidcClient, OidcConfig};
+use permissions::PermissionPolicy;
+use shutdown::Shutdown;
+use store::{InMemoryStore, SqliteStore, Store};
+use tokio::{net::TcpListener, task::JoinSet};
+
+/// How often the periodic tasks run, e.g., the removal of the expired sessions
+const PERIODIC_TASK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() -> Result<(), BoxError> {
     let cli = Cli::parse();
 
+    match cli.command.clone().unwrap_or(Command::Serve) {
+        Command::Serve => {
+            let config = load_config(&cli);
+            init_logger(&config);
+            serve(cli, config).await?;
+        }
+        Command::Routes => commands::print_routes(),
+        Command::CheckConfig => {
+            let config = load_config(&cli);
+            init_logger(&config);
+            if let Err(e) = commands::check_config(&config, &cli) {
+                exit_with_error(e);
+            }
+        }
+        Command::GenerateSecret => println!("{}", secret::generate()?),
+        Command::User(user_command) => {
+            let config = load_config(&cli);
+            init_logger(&config);
+            let state = create_persistent_state(&config, &cli)?;
+            let result = commands::run_user_command(&state, user_command).await;
+            state.audit().flush().await;
+            if let Err(e) = result {
+                exit_with_error(e);
+            }
+        }
+        Command::Token(token_command) => {
+            let config = load_config(&cli);
+            init_logger(&config);
+            let state = create_persistent_state(&config, &cli)?;
+            let result = commands::run_token_command(&state, token_command).await;
+            state.audit().flush().await;
+            if let Err(e) = result {
+                exit_with_error(e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Exits with the readable error if the configuration is invalid
+fn load_config(cli: &Cli) -> Config {
+    Config::load(cli).unwrap_or_else(|e| exit_with_error(e))
+}
+
+fn init_logger(config: &Config) {
+    // the level is limited by the max level of the log crate, which a reload can change
     env_logger::builder()
-        // .filter_level(log::LevelFilter::max())
-        .filter_level(log::LevelFilter::Info)
+        .filter_level(log::LevelFilter::Trace)
         .init();
+    log::set_max_level(config.log.level.into());
+}
+
+fn exit_with_error(e: BoxError) -> ! {
+    eprintln!("{e}");
+    std::process::exit(2);
+}
+
+async fn serve(cli: Cli, config: Config) -> Result<(), BoxError> {
+    let Some(listener_address) = config.server.listener_address.clone() else {
+        exit_with_error(
+            "server.listener_address is not set (--listener-address, APP_LISTENER_ADDRESS or the configuration file)"
+                .into(),
+        );
+    };
 
     log::info!("Starting application!");
 
-    let mut secret = [0; 32];
-    getrandom::getrandom(&mut secret)?;
-    let state = AppState::new(secret);
+    if !config.server.static_dir.is_dir() {
+        log::warn!(
+            "The static directory does not exist, path = '{}'",
+            config.server.static_dir.display()
+        );
+    }
+
+    let admin_password = secret::read(
+        cli::ADMIN_PASSWORD_ENV_VAR,
+        cli.admin_password.as_deref(),
+        "--admin-password-file",
+        cli.admin_password_file.as_deref(),
+    )?;
+
+    let mut state = create_state(&config, &cli)?;
 
-    let mut app = AxumApp::new(state.clone());
-    for addr in cli.listener_address.to_socket_addrs().unwrap() {
-        let _ = app
-            .spawn_server(addr)
+    if let Some(admin_password) = admin_password {
+        state
+            .add_user("admin", admin_password, BTreeSet::from(["admin".into()]))
             .await
-            .inspect_err(|e| log::error!("error = {e:?}"));
+            .map_err(|_| "could not create the admin user")?;
     }
 
-    // periodic tasks
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+    let shutdown = Shutdown::default();
+
+    let mut servers = JoinSet::new();
+    for addr in listener_address.to_socket_addrs()? {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("serve, bind, addr = {addr}, error = {e}");
+                continue;
+            }
+        };
+        log::info!("Listening, address = '{addr}'");
+
+        let routes = state
+            .routes()
+            .into_make_service_with_connect_info::<SocketAddr>();
+        let mut server_shutdown = shutdown.subscribe();
+        servers.spawn(async move {
+            axum::serve(listener, routes)
+                .with_graceful_shutdown(async move { server_shutdown.started().await })
+                .await
+                .inspect_err(|e| log::error!("serve, addr = {addr}, error = {e}"))
+        });
+    }
+
+    let mut background_tasks = JoinSet::new();
+
+    // configuration reload, the flags and the environment variables are applied again, they keep
+    // overriding the file
+    let mut reload_triggers = ReloadTriggers::new(cli.config.as_deref())?;
+    let reloading_state = state.clone();
+    let mut reload_shutdown = shutdown.subscribe();
+    background_tasks.spawn(async move {
         loop {
-            interval.tick().await;
+            let trigger = tokio::select! {
+                trigger = reload_triggers.next() => trigger,
+                _ = reload_shutdown.started() => break,
+            };
+            let Some(trigger) = trigger else {
+                break;
+            };
+
+            log::info!("Reloading the configuration, trigger = {trigger:?}");
+            match Config::load(&cli) {
+                Ok(config) => {
+                    let _ = reloading_state.reload_config(config);
+                }
+                Err(e) => log::error!(
+                    "Configuration reload failed, the current configuration is kept, error = {e}"
+                ),
+            }
+        }
+    });
+
+    // periodic tasks, a started run is finished, the shutdown stops the loop between the runs
+    let periodic_state = state.clone();
+    let mut periodic_shutdown = shutdown.subscribe();
+    let scheduler_heartbeat = Arc::new(Heartbeat::new(PERIODIC_TASK_INTERVAL * 6));
+    state
+        .health()
+        .register("scheduler", false, scheduler_heartbeat.clone());
+    background_tasks.spawn(async move {
+        let mut interval = tokio::time::interval(PERIODIC_TASK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = periodic_shutdown.started() => break,
+            }
+            scheduler_heartbeat.beat();
 
             // tasks to be executed
+            periodic_state.remove_expired_signing_keys();
+            periodic_state.remove_stale_login_throttle_counters();
+            periodic_state.remove_expired_oidc_authorizations();
+            let _ = periodic_state
+                .store
+                .delete_expired_sessions(timestamp::now())
+                .await
+                .inspect_err(|e| log::error!("delete_expired_sessions, error = {e}"));
+            let _ = periodic_state
+                .store
+                .delete_expired_refresh_tokens(timestamp::now())
+                .await
+                .inspect_err(|e| log::error!("delete_expired_refresh_tokens, error = {e}"));
+            let _ = periodic_state
+                .store
+                .delete_expired_invites(timestamp::now())
+                .await
+                .inspect_err(|e| log::error!("delete_expired_invites, error = {e}"));
         }
     });
 
-    app.join().await;
+    state.health().set_ready(true);
+
+    let mut clean = true;
+    tokio::select! {
+        signal = shutdown::signal() => match signal {
+            Ok(signal) => log::info!("Shutting down, signal = {signal:?}"),
+            // the servers can not be stopped by a signal, better to stop them now
+            Err(e) => {
+                log::error!("serve, signal, error = {e}");
+                clean = false;
+            }
+        },
+        // without a signal, the servers only stop on an error
+        _ = shutdown::join_servers(&mut servers) => {
+            log::error!("No server is running, shutting down");
+            clean = false;
+        }
+    }
+
+    // the servers stop accepting connections and wait for the running requests, the probes on the
+    // open connections see that the application is not ready
+    state.health().set_ready(false);
+    shutdown.start();
+    let shutdown_timeout = state.config().server.shutdown_timeout();
+    // a second signal cuts the running requests
+    let second_signal = async {
+        let signal = shutdown::next_signal().await;
+        log::info!("Second signal, signal = {signal:?}");
+    };
+    clean &= shutdown::drain(&mut servers, shutdown_timeout, second_signal).await;
+
+    // a running periodic task finishes its store operations
+    while background_tasks.join_next().await.is_some() {}
+
+    state.audit().flush().await;
+    let dropped_audit_events = state.audit().dropped_count();
+    if dropped_audit_events > 0 {
+        log::warn!(
+            "Audit events were dropped because the queue was full, count = {dropped_audit_events}"
+        );
+    }
+    log::info!("Stopped, clean = {clean}");
+    log::logger().flush();
+
+    if !clean {
+        std::process::exit(1);
+    }
 
     Ok(())
 }
+
+/// The state shared by the server and the commands
+fn create_state(config: &Config, cli: &Cli) -> Result<AppState, BoxError> {
+    let signing_keys = load_signing_keys(config, cli)?;
+    // the store is an audit sink too
+    let (store, store_audit_sink): (Arc<dyn Store>, Arc<dyn AuditSink>) =
+        match &config.database.path {
+            Some(database) => {
+                log::info!("Opening database, path = '{}'", database.display());
+                let store = Arc::new(SqliteStore::open(database)?);
+                (store.clone(), store)
+            }
+            None => {
+                let store = Arc::new(InMemoryStore::new());
+                (store.clone(), store)
+            }
+        };
+    let audit_sink: Arc<dyn AuditSink> = match config.audit.sink {
+        AuditSinkKind::Store => store_audit_sink,
+        AuditSinkKind::File => {
+            log::info!(
+                "Audit events are written to '{}'",
+                config.audit.file.display()
+            );
+            Arc::new(FileAuditSink::new(&config.audit.file))
+        }
+    };
+    let mailer = create_mailer(config, cli)?;
+    let oidc = create_oidc_client(config, cli, &config.permissions)?;
+
+    Ok(AppState::new(
+        config,
+        AppStateDependencies {
+            signing_keys,
+            // replace it to add application specific claims to the access tokens
+            claims_hook: Arc::new(NoCustomClaims),
+            store,
+            permission_policy: config.permissions.clone(),
+            mailer,
+            oidc,
+            audit_sink,
+        },
+    ))
+}
+
+/// The state for the commands changing the users, they are pointless on an in-memory store
+fn create_persistent_state(config: &Config, cli: &Cli) -> Result<AppState, BoxError> {
+    if config.database.path.is_none() {
+        exit_with_error(
+            "the command needs a database (--database, APP_DATABASE or database.path of the configuration file)"
+                .into(),
+        );
+    }
+
+    create_state(config, cli)
+}
+
+/// The configured keys, or the single key of the secret or of the private key file
+fn load_signing_keys(config: &Config, cli: &Cli) -> Result<Vec<SigningKey>, BoxError> {
+    if !config.auth.signing_keys.is_empty() {
+        return keyring::load_keys(config.auth.jwt_algorithm, &config.auth.signing_keys);
+    }
+
+    load_signing_key(config, cli).map(|signing_key| vec![signing_key])
+}
+
+fn load_signing_key(config: &Config, cli: &Cli) -> Result<SigningKey, BoxError> {
+    let algorithm = config.auth.jwt_algorithm;
+    if algorithm.is_symmetric() {
+        let secret = secret::load(
+            cli.jwt_secret.as_deref(),
+            config.auth.jwt_secret_file.as_deref(),
+        )?;
+        return Ok(SigningKey::hmac(&secret));
+    }
+
+    match &config.auth.jwt_private_key_file {
+        Some(private_key_file) => {
+            let pem = std::fs::read_to_string(private_key_file).map_err(|e| {
+                format!(
+                    "could not read the JWT private key file '{}': {e}",
+                    private_key_file.display()
+                )
+            })?;
+            SigningKey::from_pem(algorithm, &pem)
+        }
+        None => {
+            log::warn!(
+                "No JWT private key is configured, using a generated one, tokens are invalidated on restart"
+            );
+            SigningKey::generate(algorithm)
+        }
+    }
+}
+
+// the flags are only needed for the SMTP url
+#[cfg_attr(not(feature = "smtp"), allow(unused_variables))]
+fn create_mailer(config: &Config, cli: &Cli) -> Result<Arc<dyn Mailer>, BoxError> {
+    match config.mail.mailer {
+        MailerKind::Log => Ok(Arc::new(LogMailer)),
+        MailerKind::File => Ok(Arc::new(FileMailer::new(&config.mail.file))),
+        #[cfg(feature = "smtp")]
+        MailerKind::Smtp => {
+            let smtp_url = secret::read(
+                mailer::SMTP_URL_ENV_VAR,
+                cli.smtp_url.as_deref(),
+                "--smtp-url-file",
+                cli.smtp_url_file.as_deref(),
+            )?
+            .ok_or_else(|| {
+                format!(
+                    "the SMTP url has to be set with {} or --smtp-url-file",
+                    mailer::SMTP_URL_ENV_VAR
+                )
+            })?;
+            Ok(Arc::new(mailer::SmtpMailer::new(
+                &smtp_url,
+                &config.mail.from,
+            )?))
+        }
+        #[cfg(not(feature = "smtp"))]
+        MailerKind::Smtp => Err("the application was built without the 'smtp' feature".into()),
+    }
+}
+
+fn create_oidc_client(
+    config: &Config,
+    cli: &Cli,
+    permission_policy: &PermissionPolicy,
+) -> Result<Option<OidcClient>, BoxError> {
+    let oidc = &config.oidc;
+    let Some(issuer_url) = &oidc.issuer_url else {
+        return Ok(None);
+    };
+
+    if let Some(role) = oidc
+        .role_mapping
+        .values()
+        .flatten()
+        .find(|role| !permission_policy.contains_role(role))
+    {
+        return Err(format!("unknown role '{role}' in the OIDC role mapping").into());
+    }
+
+    log::info!("OpenID Connect login enabled, issuer = '{issuer_url}'");
+
+    Ok(Some(OidcClient::new(OidcConfig {
+        issuer_url: issuer_url.clone(),
+        client_id: oidc.client_id.clone().ok_or("oidc.client_id is not set")?,
+        client_secret: secret::read(
+            oidc::CLIENT_SECRET_ENV_VAR,
+            cli.oidc_client_secret.as_deref(),
+            "--oidc-client-secret-file",
+            cli.oidc_client_secret_file.as_deref(),
+        )?,
+        redirect_url: oidc
+            .redirect_url
+            .clone()
+            .ok_or("oidc.redirect_url is not set")?,
+        scopes: oidc.scopes.clone(),
+        username_claim: oidc.username_claim.clone(),
+        roles_claim: oidc.roles_claim.clone(),
+        role_mapping: oidc.role_mapping.clone(),
+    })?))
+}
@@ -1,3 +1,9 @@
+use std::collections::BTreeSet;
+
+use uuid::Uuid;
+
+use crate::{audit::AuditEventKind, permissions, timestamp::UnixTimestamp};
+
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct LoginRequest {
     pub loginname: String,
@@ -9,6 +15,211 @@ pub struct LoginResponse {
     pub loginname: String,
 }
 
+/// Returned by the login instead of the tokens if the user has a second factor
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SecondFactorRequiredResponse {
+    pub loginname: String,
+    pub second_factor_required: bool,
+    /// has to be sent to /api/login/second-factor together with the code
+    pub challenge_token: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SecondFactorRequest {
+    pub challenge_token: String,
+    /// a TOTP code or a recovery code
+    pub code: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RegisterRequest {
+    pub loginname: String,
+    pub password: String,
+    /// a verification mail is sent to the address
+    #[serde(default)]
+    pub email: Option<String>,
+    /// required if the registration is invite-only
+    #[serde(default)]
+    pub invite_token: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PasswordResetRequest {
+    pub loginname: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ConfirmPasswordResetRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SetEmailRequest {
+    pub email: String,
+    pub current_password: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ConfirmEmailVerificationRequest {
+    pub token: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct TotpEnrollmentResponse {
+    /// base32 encoded, for entering it manually into an authenticator app
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DisableTotpRequest {
+    pub current_password: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct CreateUserRequest {
+    pub loginname: String,
+    pub password: String,
+    #[serde(default = "default_roles")]
+    pub roles: BTreeSet<String>,
+}
+
+/// The fields that are not set are left unchanged
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct UpdateUserRequest {
+    #[serde(default)]
+    pub roles: Option<BTreeSet<String>>,
+    #[serde(default)]
+    pub disabled: Option<bool>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct CreateInviteRequest {
+    #[serde(default = "default_roles")]
+    pub roles: BTreeSet<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct CreateInviteResponse {
+    pub invite_token: String,
+    pub expires_at: UnixTimestamp,
+}
+
+fn default_roles() -> BTreeSet<String> {
+    BTreeSet::from([permissions::DEFAULT_ROLE.into()])
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SessionResponse {
+    pub id: Uuid,
+    pub user_agent: Option<String>,
+    pub created_at: UnixTimestamp,
+    pub last_refreshed_at: UnixTimestamp,
+    pub expires_at: UnixTimestamp,
+    /// true if this is the session of the request
+    pub current: bool,
+    /// the admin acting as the user if the session was started by an impersonation
+    pub impersonator: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ImpersonationResponse {
+    pub loginname: String,
+    pub impersonator: String,
+    /// the impersonation session can not be refreshed beyond this time
+    pub expires_at: UnixTimestamp,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    /// defaults to every permission of the user
+    #[serde(default)]
+    pub permissions: Option<BTreeSet<String>>,
+    /// the key does not expire if not set
+    #[serde(default)]
+    pub expires_in_days: Option<u64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct CreateApiKeyResponse {
+    pub id: Uuid,
+    /// sent as `Authorization: ApiKey <key>`, it is shown only once
+    pub key: String,
+    pub permissions: BTreeSet<String>,
+    pub expires_at: Option<UnixTimestamp>,
+}
+
+/// The query params the OpenID Connect provider redirects back with, either the code or the error
+/// is set
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct OidcCallbackParams {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+/// The filters of the audit event query, the ones that are not set match every event
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct AuditEventsParams {
+    /// matches the events about the user and the ones caused by the user
+    #[serde(default)]
+    pub loginname: Option<String>,
+    #[serde(default)]
+    pub kind: Option<AuditEventKind>,
+    /// unix timestamp, inclusive
+    #[serde(default)]
+    pub since: Option<UnixTimestamp>,
+    /// unix timestamp, exclusive
+    #[serde(default)]
+    pub until: Option<UnixTimestamp>,
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default = "PagingParams::default_limit")]
+    pub limit: usize,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PagingParams {
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default = "PagingParams::default_limit")]
+    pub limit: usize,
+}
+
+impl PagingParams {
+    pub const MAX_LIMIT: usize = 1000;
+
+    fn default_limit() -> usize {
+        100
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct EchoThisAndThatResponse {
     pub this: String,
@@ -0,0 +1,27 @@
+use std::collections::BTreeSet;
+
+use uuid::Uuid;
+
+use crate::timestamp::UnixTimestamp;
+
+/// Long-lived credential of a machine client, acting on behalf of its user
+#[derive(Clone, serde::Serialize)]
+pub struct StoredApiKey {
+    pub id: Uuid,
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    pub loginname: String,
+    /// chosen by the user to tell the keys apart, e.g., "ci"
+    pub name: String,
+    /// the key can use only these permissions, and only as long as the user has them
+    pub permissions: BTreeSet<String>,
+    pub created_at: UnixTimestamp,
+    pub expires_at: Option<UnixTimestamp>,
+    pub last_used_at: Option<UnixTimestamp>,
+}
+
+impl StoredApiKey {
+    pub fn is_expired(&self, now: UnixTimestamp) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
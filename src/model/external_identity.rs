@@ -0,0 +1,11 @@
+use crate::timestamp::UnixTimestamp;
+
+/// Links the account of an external identity provider to a local user
+#[derive(Clone)]
+pub struct StoredExternalIdentity {
+    pub issuer: String,
+    /// the `sub` claim, unique and stable per issuer
+    pub subject: String,
+    pub loginname: String,
+    pub created_at: UnixTimestamp,
+}
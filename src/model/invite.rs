@@ -0,0 +1,15 @@
+use std::collections::BTreeSet;
+
+use crate::timestamp::UnixTimestamp;
+
+/// Allows registering a single user when the registration is invite-only
+#[derive(Clone)]
+pub struct StoredInvite {
+    pub token_hash: String,
+    /// the roles of the user registered with the invite
+    pub roles: BTreeSet<String>,
+    pub created_by: String,
+    pub created_at: UnixTimestamp,
+    pub expires_at: UnixTimestamp,
+    pub used: bool,
+}
@@ -1,21 +1,136 @@
+use std::collections::BTreeSet;
+
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use crate::{
+    model::api_key::StoredApiKey, permissions::PermissionPolicy, timestamp::UnixTimestamp,
+};
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct LoginName(pub String);
+
+impl LoginName {
+    pub const MIN_LENGTH: usize = 3;
+    pub const MAX_LENGTH: usize = 32;
+
+    /// Login names are 3 to 32 characters long, contain ASCII letters, digits, '.', '_' and '-'
+    /// only and start with a letter or a digit
+    pub fn validate(loginname: &str) -> Result<(), String> {
+        if loginname.len() < Self::MIN_LENGTH || loginname.len() > Self::MAX_LENGTH {
+            return Err(format!(
+                "the login name has to be {} to {} characters long",
+                Self::MIN_LENGTH,
+                Self::MAX_LENGTH
+            ));
+        }
+
+        if !loginname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        {
+            return Err(
+                "the login name can only contain letters, digits, '.', '_' and '-'".to_string(),
+            );
+        }
+
+        if !loginname.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            return Err("the login name has to start with a letter or a digit".to_string());
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, serde::Serialize)]
 pub struct LoginInfo {
     pub loginname: String,
-    pub role: String,
+    pub roles: BTreeSet<String>,
+    /// the permissions granted by the roles
+    pub permissions: BTreeSet<String>,
+    /// nil if the request is authenticated with an API key
+    pub session_id: Uuid,
+    /// set if the request is authenticated with an API key, the permissions are limited to the
+    /// scope of the key then
+    pub api_key_id: Option<Uuid>,
+    /// the admin acting as the user if the session was started by an impersonation
+    pub impersonator: Option<String>,
+    /// the claims the `ClaimsHook` added to the access token, empty for API keys
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub custom_claims: Map<String, Value>,
 }
 
 #[derive(Clone, serde::Serialize)]
 pub struct StoredLoginInfo {
     pub loginname: String,
-    pub role: String,
-    pub logged_in: bool,
+    pub roles: BTreeSet<String>,
+    #[serde(skip_serializing)]
+    pub password_hash: String,
+    pub created_at: UnixTimestamp,
+    pub last_login_at: Option<UnixTimestamp>,
+    /// disabled users can not log in and their sessions are not accepted
+    pub disabled: bool,
+    pub email: Option<String>,
+    /// password reset mails are only sent to verified addresses
+    pub email_verified: bool,
 }
 
-impl From<&StoredLoginInfo> for LoginInfo {
-    fn from(stored_login_info: &StoredLoginInfo) -> Self {
+impl LoginInfo {
+    pub fn new(
+        stored_login_info: &StoredLoginInfo,
+        session_id: Uuid,
+        impersonator: Option<String>,
+        permission_policy: &PermissionPolicy,
+    ) -> Self {
+        Self {
+            loginname: stored_login_info.loginname.clone(),
+            roles: stored_login_info.roles.clone(),
+            permissions: permission_policy.resolve_permissions(&stored_login_info.roles),
+            session_id,
+            api_key_id: None,
+            impersonator,
+            custom_claims: Map::new(),
+        }
+    }
+
+    /// The login info of a request authenticated with the API key. The key can not have more
+    /// permissions than its user currently has.
+    pub fn for_api_key(
+        stored_login_info: &StoredLoginInfo,
+        api_key: &StoredApiKey,
+        permission_policy: &PermissionPolicy,
+    ) -> Self {
+        let permissions = permission_policy
+            .resolve_permissions(&stored_login_info.roles)
+            .intersection(&api_key.permissions)
+            .cloned()
+            .collect();
+
         Self {
             loginname: stored_login_info.loginname.clone(),
-            role: stored_login_info.role.clone(),
+            roles: stored_login_info.roles.clone(),
+            permissions,
+            session_id: Uuid::nil(),
+            api_key_id: Some(api_key.id),
+            impersonator: None,
+            custom_claims: Map::new(),
         }
     }
 }
+
+/// Only a sanity check, whether the address works is proven by the email verification
+pub fn validate_email(email: &str) -> Result<(), String> {
+    const MAX_LENGTH: usize = 254;
+
+    let valid = email.len() <= MAX_LENGTH
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        && email.rsplit_once('@').is_some_and(|(local_part, domain)| {
+            !local_part.is_empty() && domain.contains('.') && !domain.starts_with('.')
+        });
+
+    if valid {
+        Ok(())
+    } else {
+        Err("invalid email address".to_string())
+    }
+}
@@ -1 +1,7 @@
+pub mod api_key;
+pub mod external_identity;
+pub mod invite;
 pub mod login_info;
+pub mod refresh_token;
+pub mod session;
+pub mod totp;
@@ -0,0 +1,13 @@
+use uuid::Uuid;
+
+use crate::timestamp::UnixTimestamp;
+
+#[derive(Clone)]
+pub struct StoredRefreshToken {
+    pub token_hash: String,
+    /// Every refresh token created by rotating a token of the family belongs to the same family
+    pub family_id: Uuid,
+    pub loginname: String,
+    pub expires_at: UnixTimestamp,
+    pub used: bool,
+}
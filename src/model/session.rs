@@ -0,0 +1,24 @@
+use uuid::Uuid;
+
+use crate::timestamp::{self, UnixTimestamp};
+
+/// A login of a user on one device. The access tokens carry the id of their session, the refresh
+/// tokens of the session belong to the refresh token family with the same id.
+#[derive(Clone, serde::Serialize)]
+pub struct StoredSession {
+    pub id: Uuid,
+    pub loginname: String,
+    pub user_agent: Option<String>,
+    pub created_at: UnixTimestamp,
+    pub last_refreshed_at: UnixTimestamp,
+    pub expires_at: UnixTimestamp,
+    pub revoked: bool,
+    /// the admin acting as the user if the session was started by an impersonation
+    pub impersonator: Option<String>,
+}
+
+impl StoredSession {
+    pub fn is_active(&self) -> bool {
+        !self.revoked && self.expires_at > timestamp::now()
+    }
+}
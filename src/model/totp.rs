@@ -0,0 +1,15 @@
+use crate::timestamp::UnixTimestamp;
+
+#[derive(Clone)]
+pub struct StoredTotp {
+    pub loginname: String,
+    /// base32 encoded, it is needed in plain text to verify the codes
+    pub secret: String,
+    /// the second factor is only required after the user proved to have the secret by entering
+    /// a valid code
+    pub confirmed: bool,
+    /// the codes of this and the earlier time steps are not accepted anymore, so a code can not
+    /// be replayed
+    pub last_used_step: Option<u64>,
+    pub created_at: UnixTimestamp,
+}
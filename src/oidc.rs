@@ -0,0 +1,635 @@
+//! Login through an external OpenID Connect provider with the authorization code flow and PKCE
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::{BoxError, OidcError},
+    model::login_info::LoginName,
+    opaque_token,
+    syn::{arc_mutex_new, arc_rw_lock_new, ArcMutex, ArcRwLock},
+    timestamp::{self, UnixTimestamp},
+};
+
+pub const CLIENT_SECRET_ENV_VAR: &str = "APP_OIDC_CLIENT_SECRET";
+
+/// The user has to come back from the provider within this time
+pub const PENDING_AUTHORIZATION_LIFETIME: Duration = Duration::from_secs(10 * 60);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct OidcConfig {
+    /// e.g., https://accounts.example.com, the discovery document is loaded from
+    /// `<issuer_url>/.well-known/openid-configuration`
+    pub issuer_url: String,
+    pub client_id: String,
+    /// sent with client_secret_post if set, public clients rely on PKCE only
+    pub client_secret: Option<String>,
+    /// the callback endpoint of the application, as registered at the provider
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    /// the claim the loginname of new users is derived from
+    pub username_claim: String,
+    /// if set, the roles of the users are managed by the provider: at every login they are
+    /// replaced by the mapped values of this claim
+    pub roles_claim: Option<String>,
+    /// maps the values of the roles claim to local roles, unmapped values are ignored
+    pub role_mapping: BTreeMap<String, BTreeSet<String>>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+struct PendingAuthorization {
+    code_verifier: String,
+    nonce: String,
+    created_at: UnixTimestamp,
+}
+
+/// Where the user is sent to log in at the provider
+pub struct AuthorizationRequest {
+    pub url: String,
+    /// the callback is only accepted with it, the login endpoint binds it to the browser
+    pub state: String,
+}
+
+/// The user as asserted by a validated ID token
+pub struct OidcIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    /// None if the roles are not managed by the provider
+    pub roles: Option<BTreeSet<String>>,
+}
+
+/// The pending authorizations (the state, the nonce and the PKCE verifier of the logins started
+/// but not completed yet) are kept in the memory of this instance only. A restart loses them, so
+/// the users in the middle of a login have to start again, and behind a load balancer the
+/// callback has to reach the instance the login was started at, e.g., with sticky sessions.
+pub struct OidcClient {
+    config: OidcConfig,
+    http_client: reqwest::Client,
+    // loaded at the first login, so the application starts even if the provider is unavailable
+    metadata: ArcRwLock<Option<ProviderMetadata>>,
+    jwks: ArcRwLock<JwkSet>,
+    pending_authorizations: ArcMutex<HashMap<String, PendingAuthorization>>,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Result<Self, BoxError> {
+        Ok(Self {
+            config,
+            http_client: reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?,
+            metadata: arc_rw_lock_new(None),
+            jwks: arc_rw_lock_new(JwkSet { keys: Vec::new() }),
+            pending_authorizations: arc_mutex_new(HashMap::new()),
+        })
+    }
+
+    /// Returns the url of the provider the user has to be redirected to. The state, the nonce
+    /// and the PKCE verifier are kept until the user comes back to the callback.
+    pub async fn authorization_request(&self) -> Result<AuthorizationRequest, OidcError> {
+        let metadata = self.metadata().await.map_err(provider_error)?;
+
+        let state = opaque_token::generate().map_err(|_| OidcError::Internal)?;
+        let nonce = opaque_token::generate().map_err(|_| OidcError::Internal)?;
+        let code_verifier = opaque_token::generate().map_err(|_| OidcError::Internal)?;
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+        let scope = std::iter::once("openid")
+            .chain(
+                self.config
+                    .scopes
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|scope| *scope != "openid"),
+            )
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let url = url::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", self.config.redirect_url.as_str()),
+                ("scope", scope.as_str()),
+                ("state", state.as_str()),
+                ("nonce", nonce.as_str()),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| provider_error(format!("invalid authorization endpoint: {e}").into()))?;
+
+        self.pending_authorizations.lock().insert(
+            state.clone(),
+            PendingAuthorization {
+                code_verifier,
+                nonce,
+                created_at: timestamp::now(),
+            },
+        );
+
+        Ok(AuthorizationRequest {
+            url: url.into(),
+            state,
+        })
+    }
+
+    /// Exchanges the authorization code for the tokens and validates the ID token
+    pub async fn complete_authorization(
+        &self,
+        code: &str,
+        state: &str,
+    ) -> Result<OidcIdentity, OidcError> {
+        let pending_authorization = self
+            .pending_authorizations
+            .lock()
+            .remove(state)
+            .filter(|pending_authorization| {
+                pending_authorization.created_at + PENDING_AUTHORIZATION_LIFETIME.as_secs()
+                    > timestamp::now()
+            })
+            .ok_or(OidcError::InvalidState)?;
+
+        let metadata = self.metadata().await.map_err(provider_error)?;
+        let id_token = self
+            .exchange_code(&metadata, code, &pending_authorization.code_verifier)
+            .await
+            .map_err(provider_error)?;
+        let claims = self
+            .validate_id_token(&metadata, &id_token, &pending_authorization.nonce)
+            .await
+            .map_err(provider_error)?;
+
+        let string_claim = |name: &str| claims.get(name).and_then(Value::as_str).map(String::from);
+
+        Ok(OidcIdentity {
+            issuer: metadata.issuer,
+            subject: string_claim("sub").ok_or(OidcError::Provider)?,
+            username: string_claim(&self.config.username_claim),
+            email: string_claim("email"),
+            email_verified: claims
+                .get("email_verified")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            roles: self
+                .config
+                .roles_claim
+                .as_ref()
+                .map(|roles_claim| self.map_roles(claims.get(roles_claim).unwrap_or(&Value::Null))),
+        })
+    }
+
+    pub fn remove_expired_authorizations(&self) {
+        let now = timestamp::now();
+        self.pending_authorizations
+            .lock()
+            .retain(|_, pending_authorization| {
+                pending_authorization.created_at + PENDING_AUTHORIZATION_LIFETIME.as_secs() > now
+            });
+    }
+
+    async fn metadata(&self) -> Result<ProviderMetadata, BoxError> {
+        if let Some(metadata) = self.metadata.read().as_ref() {
+            return Ok(metadata.clone());
+        }
+
+        let issuer_url = self.config.issuer_url.trim_end_matches('/');
+        let metadata: ProviderMetadata = self
+            .http_client
+            .get(format!("{issuer_url}/.well-known/openid-configuration"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if metadata.issuer.trim_end_matches('/') != issuer_url {
+            return Err(format!(
+                "the issuer of the discovery document '{}' does not match '{issuer_url}'",
+                metadata.issuer
+            )
+            .into());
+        }
+
+        log::info!("OIDC provider discovered, issuer = '{}'", metadata.issuer);
+
+        *self.metadata.write() = Some(metadata.clone());
+
+        Ok(metadata)
+    }
+
+    async fn exchange_code(
+        &self,
+        metadata: &ProviderMetadata,
+        code: &str,
+        code_verifier: &str,
+    ) -> Result<String, BoxError> {
+        // the serializer is not Send, so it must not live across the await
+        let form = {
+            let mut form = url::form_urlencoded::Serializer::new(String::new());
+            form.append_pair("grant_type", "authorization_code")
+                .append_pair("code", code)
+                .append_pair("redirect_uri", &self.config.redirect_url)
+                .append_pair("client_id", &self.config.client_id)
+                .append_pair("code_verifier", code_verifier);
+            if let Some(client_secret) = &self.config.client_secret {
+                form.append_pair("client_secret", client_secret);
+            }
+            form.finish()
+        };
+
+        let token_response: TokenResponse = self
+            .http_client
+            .post(&metadata.token_endpoint)
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .body(form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(token_response.id_token)
+    }
+
+    /// Checks the signature, the issuer, the audience, the expiration and the nonce of the ID
+    /// token and returns its claims
+    async fn validate_id_token(
+        &self,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<Map<String, Value>, BoxError> {
+        let header = jsonwebtoken::decode_header(id_token)?;
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err("ID tokens signed with a symmetric algorithm are not supported".into());
+        }
+
+        let decoding_key = match self.find_key(header.kid.as_deref())? {
+            Some(decoding_key) => decoding_key,
+            None => {
+                // the provider may have rotated its keys
+                self.load_jwks(metadata).await?;
+                self.find_key(header.kid.as_deref())?
+                    .ok_or("the signing key of the ID token is unknown")?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims =
+            jsonwebtoken::decode::<Map<String, Value>>(id_token, &decoding_key, &validation)?
+                .claims;
+
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err("the nonce of the ID token does not match".into());
+        }
+
+        Ok(claims)
+    }
+
+    fn find_key(&self, kid: Option<&str>) -> Result<Option<DecodingKey>, BoxError> {
+        let jwks = self.jwks.read();
+        let jwk = match kid {
+            Some(kid) => jwks.find(kid),
+            // without a kid only an unambiguous key set can be used
+            None if jwks.keys.len() == 1 => jwks.keys.first(),
+            None => None,
+        };
+
+        Ok(jwk.map(DecodingKey::from_jwk).transpose()?)
+    }
+
+    async fn load_jwks(&self, metadata: &ProviderMetadata) -> Result<(), BoxError> {
+        let jwks: JwkSet = self
+            .http_client
+            .get(&metadata.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        log::info!(
+            "OIDC provider keys loaded, issuer = '{}', key_count = {}",
+            metadata.issuer,
+            jwks.keys.len()
+        );
+
+        *self.jwks.write() = jwks;
+
+        Ok(())
+    }
+
+    /// The roles claim can be a string or an array of strings
+    fn map_roles(&self, roles_claim: &Value) -> BTreeSet<String> {
+        let values: Vec<&str> = match roles_claim {
+            Value::String(value) => vec![value.as_str()],
+            Value::Array(values) => values.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+
+        values
+            .into_iter()
+            .filter_map(|value| self.config.role_mapping.get(value))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Derives a valid loginname from the username claim. The characters that are not allowed in
+/// loginnames are replaced, e.g., "Jane Doe" becomes "Jane_Doe".
+pub fn loginname_from_username(username: &str) -> Option<String> {
+    let loginname = username
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(LoginName::MAX_LENGTH)
+        .collect::<String>();
+
+    LoginName::validate(&loginname).ok().map(|_| loginname)
+}
+
+fn provider_error(e: BoxError) -> OidcError {
+    log::error!("OIDC provider error, error = {e}");
+    OidcError::Provider
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        extract::State,
+        http::StatusCode,
+        routing::{get, post},
+        Form, Json, Router,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::keyring::{SigningAlgorithm, SigningKey};
+
+    pub const CLIENT_ID: &str = "the-application";
+
+    struct MockAuthorization {
+        code_challenge: String,
+        claims: Map<String, Value>,
+    }
+
+    #[derive(Clone)]
+    struct MockProviderState {
+        issuer_url: String,
+        signing_key: Arc<SigningKey>,
+        authorizations: ArcMutex<HashMap<String, MockAuthorization>>,
+    }
+
+    /// An OpenID Connect provider serving the discovery document, its keys and the token
+    /// endpoint on a local port. The login of the user at the provider is replaced by
+    /// [`MockProvider::authorize`].
+    pub struct MockProvider {
+        state: MockProviderState,
+    }
+
+    impl MockProvider {
+        pub async fn start() -> Self {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let state = MockProviderState {
+                issuer_url: format!("http://{}", listener.local_addr().unwrap()),
+                signing_key: Arc::new(SigningKey::generate(SigningAlgorithm::Es256).unwrap()),
+                authorizations: arc_mutex_new(HashMap::new()),
+            };
+
+            let router = Router::new()
+                .route("/.well-known/openid-configuration", get(discovery))
+                .route("/jwks", get(jwks))
+                .route("/token", post(token))
+                .with_state(state.clone());
+            tokio::spawn(async move { axum::serve(listener, router).await });
+
+            Self { state }
+        }
+
+        pub fn client_config(&self) -> OidcConfig {
+            OidcConfig {
+                issuer_url: self.state.issuer_url.clone(),
+                client_id: CLIENT_ID.to_string(),
+                client_secret: None,
+                redirect_url: "http://localhost/api/oidc/callback".to_string(),
+                scopes: vec!["email".to_string()],
+                username_claim: "preferred_username".to_string(),
+                roles_claim: Some("groups".to_string()),
+                role_mapping: BTreeMap::from([(
+                    "administrators".to_string(),
+                    BTreeSet::from(["admin".to_string()]),
+                )]),
+            }
+        }
+
+        /// Logs the user in at the provider and returns the authorization code the provider
+        /// redirects back with. The claims are added to the ID token, overriding the defaults.
+        pub fn authorize(&self, authorization_url: &str, claims: Value) -> String {
+            let url = url::Url::parse(authorization_url).unwrap();
+            let param = |name: &str| {
+                url.query_pairs()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.into_owned())
+                    .unwrap()
+            };
+            assert_eq!(param("code_challenge_method"), "S256");
+
+            let Value::Object(mut id_token_claims) = json!({
+                "iss": self.state.issuer_url,
+                "aud": param("client_id"),
+                "sub": "the-subject",
+                "exp": timestamp::now() + 60,
+                "nonce": param("nonce"),
+            }) else {
+                unreachable!()
+            };
+            let Value::Object(claims) = claims else {
+                panic!("the claims have to be an object")
+            };
+            id_token_claims.extend(claims);
+
+            let code = opaque_token::generate().unwrap();
+            self.state.authorizations.lock().insert(
+                code.clone(),
+                MockAuthorization {
+                    code_challenge: param("code_challenge"),
+                    claims: id_token_claims,
+                },
+            );
+
+            code
+        }
+    }
+
+    async fn discovery(State(state): State<MockProviderState>) -> Json<Value> {
+        Json(json!({
+            "issuer": state.issuer_url,
+            "authorization_endpoint": format!("{}/authorize", state.issuer_url),
+            "token_endpoint": format!("{}/token", state.issuer_url),
+            "jwks_uri": format!("{}/jwks", state.issuer_url),
+        }))
+    }
+
+    async fn jwks(State(state): State<MockProviderState>) -> Json<Value> {
+        Json(json!({ "keys": [state.signing_key.public_jwk] }))
+    }
+
+    /// Issues the ID token only for a known code and the PKCE verifier of its challenge
+    async fn token(
+        State(state): State<MockProviderState>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<Value>, StatusCode> {
+        let authorization = form
+            .get("code")
+            .and_then(|code| state.authorizations.lock().remove(code))
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        let code_verifier = form.get("code_verifier").ok_or(StatusCode::BAD_REQUEST)?;
+        if URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+            != authorization.code_challenge
+        {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let mut header = jsonwebtoken::Header::new(state.signing_key.jwt_algorithm());
+        header.kid = Some(state.signing_key.kid.clone());
+        let id_token = jsonwebtoken::encode(
+            &header,
+            &authorization.claims,
+            &state.signing_key.encoding_key,
+        )
+        .unwrap();
+
+        Ok(Json(json!({
+            "access_token": "an-access-token",
+            "token_type": "Bearer",
+            "id_token": id_token,
+        })))
+    }
+
+    #[tokio::test]
+    async fn the_identity_is_taken_from_the_id_token_of_the_provider() {
+        let provider = MockProvider::start().await;
+        let client = OidcClient::new(provider.client_config()).unwrap();
+
+        let authorization_request = client.authorization_request().await.unwrap();
+        let code = provider.authorize(
+            &authorization_request.url,
+            json!({
+                "preferred_username": "Jane Doe",
+                "email": "jane@example.com",
+                "email_verified": true,
+                "groups": ["administrators", "unmapped"],
+            }),
+        );
+        let identity = client
+            .complete_authorization(&code, &authorization_request.state)
+            .await
+            .unwrap();
+
+        assert_eq!(identity.issuer, provider.state.issuer_url);
+        assert_eq!(identity.subject, "the-subject");
+        assert_eq!(identity.username.as_deref(), Some("Jane Doe"));
+        assert_eq!(identity.email.as_deref(), Some("jane@example.com"));
+        assert!(identity.email_verified);
+        assert_eq!(identity.roles, Some(BTreeSet::from(["admin".to_string()])));
+    }
+
+    #[tokio::test]
+    async fn a_state_is_accepted_only_once() {
+        let provider = MockProvider::start().await;
+        let client = OidcClient::new(provider.client_config()).unwrap();
+
+        let authorization_request = client.authorization_request().await.unwrap();
+        let code = provider.authorize(&authorization_request.url, json!({}));
+        client
+            .complete_authorization(&code, &authorization_request.state)
+            .await
+            .unwrap();
+
+        let code = provider.authorize(&authorization_request.url, json!({}));
+        assert!(matches!(
+            client
+                .complete_authorization(&code, &authorization_request.state)
+                .await,
+            Err(OidcError::InvalidState)
+        ));
+    }
+
+    #[tokio::test]
+    async fn an_id_token_of_another_login_is_rejected() {
+        let provider = MockProvider::start().await;
+        let client = OidcClient::new(provider.client_config()).unwrap();
+
+        let authorization_request = client.authorization_request().await.unwrap();
+        let code = provider.authorize(
+            &authorization_request.url,
+            json!({ "nonce": "the-nonce-of-another-login" }),
+        );
+
+        assert!(matches!(
+            client
+                .complete_authorization(&code, &authorization_request.state)
+                .await,
+            Err(OidcError::Provider)
+        ));
+    }
+
+    #[tokio::test]
+    async fn an_id_token_for_another_client_is_rejected() {
+        let provider = MockProvider::start().await;
+        let client = OidcClient::new(provider.client_config()).unwrap();
+
+        let authorization_request = client.authorization_request().await.unwrap();
+        let code = provider.authorize(
+            &authorization_request.url,
+            json!({ "aud": "another-application" }),
+        );
+
+        assert!(matches!(
+            client
+                .complete_authorization(&code, &authorization_request.state)
+                .await,
+            Err(OidcError::Provider)
+        ));
+    }
+}
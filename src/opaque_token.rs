@@ -0,0 +1,22 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use sha2::{Digest, Sha256};
+
+/// Generates a random, url-safe token with 256 bits of entropy
+pub fn generate() -> Result<String, ()> {
+    let mut bytes = [0; 32];
+    getrandom::getrandom(&mut bytes)
+        .inspect_err(|e| log::error!("opaque_token::generate, error = {e}"))
+        .map_err(|_| ())?;
+
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// Compares without exiting early, so the time taken does not reveal the matching prefix
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Tokens are only stored hashed. They have enough entropy that a fast hash is sufficient.
+pub fn hash(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
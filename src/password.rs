@@ -0,0 +1,58 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use lazy_static::lazy_static;
+use uuid::Uuid;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+lazy_static! {
+    // verified against when the loginname is unknown, so unknown users and wrong passwords
+    // take the same amount of time
+    static ref DUMMY_PASSWORD_HASH: String = hash_password(Uuid::new_v4().to_string())
+        .unwrap_or_else(|_| panic!("could not create dummy password hash"));
+}
+
+pub fn validate_password(password: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "the password has to be at least {MIN_PASSWORD_LENGTH} characters long"
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn hash_password(password: impl AsRef<[u8]>) -> Result<String, ()> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_ref(), &salt)
+        .map(|password_hash| password_hash.to_string())
+        .inspect_err(|e| log::error!("hash_password, error = {e}"))
+        .map_err(|_| ())
+}
+
+/// Returns true if the password matches the hash. If there is no hash (e.g., the user does not
+/// exist), the password is still verified against a dummy hash and false is returned.
+pub fn verify_password(password: impl AsRef<[u8]>, password_hash: Option<&str>) -> bool {
+    let (password_hash, is_dummy) = match password_hash {
+        Some(password_hash) => (password_hash, false),
+        None => (DUMMY_PASSWORD_HASH.as_str(), true),
+    };
+
+    let password_hash = match PasswordHash::new(password_hash) {
+        Ok(password_hash) => password_hash,
+        Err(e) => {
+            log::error!("verify_password, could not parse password hash, error = {e}");
+            return false;
+        }
+    };
+
+    let verified = Argon2::default()
+        .verify_password(password.as_ref(), &password_hash)
+        .is_ok();
+
+    verified && !is_dummy
+}
@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::error::BoxError;
+
+/// the role of the users registered without an invite
+pub const DEFAULT_ROLE: &str = "regular";
+
+pub const USERS_READ: &str = "users:read";
+pub const USERS_WRITE: &str = "users:write";
+pub const USERS_IMPERSONATE: &str = "users:impersonate";
+pub const SIGNING_KEYS_READ: &str = "signing-keys:read";
+pub const AUDIT_READ: &str = "audit:read";
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoleDefinition {
+    #[serde(default)]
+    pub permissions: BTreeSet<String>,
+    /// the role has every permission of these roles too
+    #[serde(default)]
+    pub inherits: BTreeSet<String>,
+}
+
+/// Maps the roles to the permissions they grant, the `[permissions]` section of the
+/// configuration, e.g.:
+///
+/// ```toml
+/// [permissions.regular]
+/// permissions = []
+///
+/// [permissions.admin]
+/// permissions = ["users:read", "users:write"]
+/// inherits = ["regular"]
+/// ```
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct PermissionPolicy {
+    roles: BTreeMap<String, RoleDefinition>,
+}
+
+impl Default for PermissionPolicy {
+    fn default() -> Self {
+        Self {
+            roles: BTreeMap::from([
+                (DEFAULT_ROLE.into(), RoleDefinition::default()),
+                (
+                    "admin".into(),
+                    RoleDefinition {
+                        permissions: [
+                            USERS_READ,
+                            USERS_WRITE,
+                            USERS_IMPERSONATE,
+                            SIGNING_KEYS_READ,
+                            AUDIT_READ,
+                        ]
+                        .into_iter()
+                        .map(String::from)
+                        .collect(),
+                        inherits: BTreeSet::from([DEFAULT_ROLE.into()]),
+                    },
+                ),
+            ]),
+        }
+    }
+}
+
+impl PermissionPolicy {
+    pub fn validate(&self) -> Result<(), BoxError> {
+        for (role, definition) in self.roles.iter() {
+            if let Some(unknown_role) = definition
+                .inherits
+                .iter()
+                .find(|inherited_role| !self.roles.contains_key(*inherited_role))
+            {
+                return Err(
+                    format!("role '{role}' inherits from unknown role '{unknown_role}'").into(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn contains_role(&self, role: &str) -> bool {
+        self.roles.contains_key(role)
+    }
+
+    /// Returns the roles and every role they inherit from, directly or through other roles
+    pub fn resolve_roles<'a>(
+        &self,
+        roles: impl IntoIterator<Item = &'a String>,
+    ) -> BTreeSet<String> {
+        let mut visited_roles = BTreeSet::new();
+        let mut roles_to_visit = roles.into_iter().collect::<Vec<_>>();
+
+        while let Some(role) = roles_to_visit.pop() {
+            if !visited_roles.insert(role.clone()) {
+                continue;
+            }
+
+            if let Some(definition) = self.roles.get(role) {
+                roles_to_visit.extend(definition.inherits.iter());
+            }
+        }
+
+        visited_roles
+    }
+
+    /// Returns every permission granted by the roles, including the inherited ones. Unknown
+    /// roles grant no permissions.
+    pub fn resolve_permissions<'a>(
+        &self,
+        roles: impl IntoIterator<Item = &'a String>,
+    ) -> BTreeSet<String> {
+        self.resolve_roles(roles)
+            .iter()
+            .filter_map(|role| self.roles.get(role))
+            .flat_map(|definition| definition.permissions.iter().cloned())
+            .collect()
+    }
+}
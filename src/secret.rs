@@ -0,0 +1,129 @@
+use std::path::Path;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::error::BoxError;
+
+/// HS512 requires a key of at least 512 bits
+pub const MIN_SECRET_LENGTH: usize = 64;
+
+pub const SECRET_ENV_VAR: &str = "APP_JWT_SECRET";
+
+/// Generates a random secret and returns it base64 encoded
+pub fn generate() -> Result<String, BoxError> {
+    let mut secret = [0; MIN_SECRET_LENGTH];
+    getrandom::getrandom(&mut secret)?;
+
+    Ok(STANDARD.encode(secret))
+}
+
+/// Loads the base64 encoded JWT signing secret given directly or by its file, `Config::load`
+/// leaves at most one of them. If none of them is set, a random secret is generated, which
+/// invalidates every token when the application restarts.
+pub fn load(secret: Option<&str>, secret_file: Option<&Path>) -> Result<Vec<u8>, BoxError> {
+    if let Some(secret) = secret {
+        return decode(secret, SECRET_ENV_VAR);
+    }
+
+    if let Some(secret_file) = secret_file {
+        let secret = std::fs::read_to_string(secret_file).map_err(|e| {
+            format!(
+                "could not read the JWT secret file '{}': {e}",
+                secret_file.display()
+            )
+        })?;
+        return decode(&secret, &format!("file '{}'", secret_file.display()));
+    }
+
+    log::warn!(
+        "No JWT secret is configured, using a random one, tokens are invalidated on restart"
+    );
+
+    let mut secret = vec![0; MIN_SECRET_LENGTH];
+    getrandom::getrandom(&mut secret)?;
+
+    Ok(secret)
+}
+
+/// Returns the secret given by the environment variable or by the file, the trailing line break
+/// of the file is not part of it. They can not be given together.
+pub fn read(
+    env_var: &str,
+    secret: Option<&str>,
+    file_flag: &str,
+    secret_file: Option<&Path>,
+) -> Result<Option<String>, BoxError> {
+    match (secret, secret_file) {
+        (Some(_), Some(_)) => {
+            Err(format!("{env_var} and {file_flag} can not be given together").into())
+        }
+        (Some(secret), None) => Ok(Some(secret.to_string())),
+        (None, Some(secret_file)) => {
+            let secret = std::fs::read_to_string(secret_file).map_err(|e| {
+                format!(
+                    "could not read the file '{}' of {file_flag}: {e}",
+                    secret_file.display()
+                )
+            })?;
+            Ok(Some(secret.trim_end_matches(['\r', '\n']).to_string()))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+fn decode(secret: &str, source: &str) -> Result<Vec<u8>, BoxError> {
+    let secret = STANDARD
+        .decode(secret.trim())
+        .map_err(|e| format!("the JWT secret from {source} is not valid base64: {e}"))?;
+
+    if secret.len() < MIN_SECRET_LENGTH {
+        return Err(format!(
+            "the JWT secret from {source} is {} bytes long, HS512 requires at least {MIN_SECRET_LENGTH} bytes (the generate-secret command creates one)",
+            secret.len()
+        )
+        .into());
+    }
+
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_secrets_are_accepted() {
+        let secret = generate().unwrap();
+
+        assert_eq!(load(Some(&secret), None).unwrap().len(), MIN_SECRET_LENGTH);
+    }
+
+    #[test]
+    fn secret_files_are_read_without_the_line_break() {
+        let secret_file = std::env::temp_dir().join(format!("secret-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&secret_file, "a secret \n").unwrap();
+
+        assert_eq!(
+            read("APP_SECRET", None, "--secret-file", Some(&secret_file)).unwrap(),
+            Some("a secret ".to_string())
+        );
+        assert!(read(
+            "APP_SECRET",
+            Some("a secret"),
+            "--secret-file",
+            Some(&secret_file)
+        )
+        .is_err());
+
+        std::fs::remove_file(secret_file).unwrap();
+    }
+
+    #[test]
+    fn too_short_secrets_are_rejected() {
+        let secret = STANDARD.encode([0; MIN_SECRET_LENGTH - 1]);
+
+        let e = load(Some(&secret), None).unwrap_err().to_string();
+        assert!(e.contains("is 63 bytes long"), "{e}");
+        assert!(e.contains("generate-secret command"), "{e}");
+    }
+}
@@ -0,0 +1,230 @@
+//! The graceful shutdown: the termination signals and the notification of the servers and the
+//! background tasks
+
+use std::{future::Future, time::Duration};
+
+use tokio::{sync::watch, task::JoinSet};
+
+use crate::error::BoxError;
+
+/// Why the application shuts down
+#[derive(Debug, Clone, Copy)]
+pub enum ShutdownSignal {
+    Terminate,
+    Interrupt,
+}
+
+/// Waits for SIGTERM or SIGINT (Ctrl+C)
+pub async fn signal() -> Result<ShutdownSignal, BoxError> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => Ok(ShutdownSignal::Terminate),
+            result = tokio::signal::ctrl_c() => result.map(|_| ShutdownSignal::Interrupt).map_err(Into::into),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await?;
+        Ok(ShutdownSignal::Interrupt)
+    }
+}
+
+/// Tells the servers and the background tasks to stop, every clone of the receiving side sees it
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            sender: watch::channel(false).0,
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn subscribe(&self) -> ShutdownReceiver {
+        ShutdownReceiver(self.sender.subscribe())
+    }
+
+    pub fn start(&self) {
+        self.sender.send_replace(true);
+    }
+}
+
+#[derive(Clone)]
+pub struct ShutdownReceiver(watch::Receiver<bool>);
+
+impl ShutdownReceiver {
+    /// Completes when the shutdown has started
+    pub async fn started(&mut self) {
+        // the sender lives until the end of serve, an error means it is gone, which is a shutdown too
+        let _ = self.0.wait_for(|started| *started).await;
+    }
+}
+
+/// Waits for every server, true if any of them failed
+pub async fn join_servers(servers: &mut JoinSet<std::io::Result<()>>) -> bool {
+    let mut failed = false;
+    while let Some(result) = servers.join_next().await {
+        failed |= !matches!(result, Ok(Ok(())));
+    }
+    failed
+}
+
+/// Waits for the servers to finish the running requests after the shutdown has started. It stops
+/// waiting when the timeout elapses or the interruption (e.g., a second signal) completes, the
+/// requests still running are cut when the application exits. Returns true if every server
+/// stopped cleanly.
+pub async fn drain(
+    servers: &mut JoinSet<std::io::Result<()>>,
+    timeout: Duration,
+    interruption: impl Future<Output = ()>,
+) -> bool {
+    tokio::select! {
+        failed = tokio::time::timeout(timeout, join_servers(servers)) => match failed {
+            Ok(failed) => !failed,
+            Err(_) => {
+                log::error!(
+                    "The running requests did not finish in time, they are cut, timeout = {}s",
+                    timeout.as_secs()
+                );
+                servers.shutdown().await;
+                false
+            }
+        },
+        _ = interruption => {
+            log::warn!("Shutting down immediately");
+            servers.shutdown().await;
+            false
+        }
+    }
+}
+
+/// Completes on the next signal. If the signals can not be received, the error is logged and it
+/// never completes, so the shutdown goes on without it.
+pub async fn next_signal() -> ShutdownSignal {
+    match signal().await {
+        Ok(signal) => signal,
+        Err(e) => {
+            log::error!("shutdown, signal, error = {e}");
+            std::future::pending().await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{routing::get, Router};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    /// A server whose only route answers after the delay, stopped by the shutdown
+    async fn start_server(
+        shutdown: &Shutdown,
+        servers: &mut JoinSet<std::io::Result<()>>,
+        delay: Duration,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let routes = Router::new().route(
+            "/",
+            get(move || async move {
+                tokio::time::sleep(delay).await;
+                "done"
+            }),
+        );
+        let mut server_shutdown = shutdown.subscribe();
+        servers.spawn(async move {
+            axum::serve(listener, routes)
+                .with_graceful_shutdown(async move { server_shutdown.started().await })
+                .await
+        });
+
+        addr
+    }
+
+    /// Sends a request and waits until the server is handling it, the task returns the response
+    async fn start_request(addr: SocketAddr) -> tokio::task::JoinHandle<String> {
+        let request = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        request
+    }
+
+    #[tokio::test]
+    async fn the_running_requests_are_finished() {
+        let shutdown = Shutdown::default();
+        let mut servers = JoinSet::new();
+        let addr = start_server(&shutdown, &mut servers, Duration::from_millis(300)).await;
+        let request = start_request(addr).await;
+
+        shutdown.start();
+
+        assert!(
+            drain(
+                &mut servers,
+                Duration::from_secs(10),
+                std::future::pending()
+            )
+            .await
+        );
+        let response = request.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("done"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn the_running_requests_are_cut_after_the_timeout() {
+        let shutdown = Shutdown::default();
+        let mut servers = JoinSet::new();
+        let addr = start_server(&shutdown, &mut servers, Duration::from_secs(60)).await;
+        let request = start_request(addr).await;
+
+        shutdown.start();
+
+        assert!(
+            !drain(
+                &mut servers,
+                Duration::from_millis(100),
+                std::future::pending()
+            )
+            .await
+        );
+        assert!(servers.is_empty());
+        assert!(!request.is_finished());
+    }
+
+    #[tokio::test]
+    async fn the_running_requests_are_cut_on_interruption() {
+        let shutdown = Shutdown::default();
+        let mut servers = JoinSet::new();
+        let addr = start_server(&shutdown, &mut servers, Duration::from_secs(60)).await;
+        let request = start_request(addr).await;
+
+        shutdown.start();
+
+        assert!(!drain(&mut servers, Duration::from_secs(60), async {}).await);
+        assert!(servers.is_empty());
+        assert!(!request.is_finished());
+    }
+}
@@ -0,0 +1,494 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use axum::async_trait;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditEvent, AuditQuery, AuditSink},
+    error::BoxError,
+    model::{
+        api_key::StoredApiKey,
+        external_identity::StoredExternalIdentity,
+        invite::StoredInvite,
+        login_info::{LoginName, StoredLoginInfo},
+        refresh_token::StoredRefreshToken,
+        session::StoredSession,
+        totp::StoredTotp,
+    },
+    syn::{arc_rw_lock_new, ArcRwLock},
+    timestamp::UnixTimestamp,
+};
+
+use super::{
+    ApiKeyStore, ExternalIdentityStore, InviteStore, RefreshTokenStore, SessionStore, TotpStore,
+    UserStore,
+};
+
+#[derive(Clone, Default)]
+pub struct InMemoryStore {
+    logins: ArcRwLock<BTreeMap<LoginName, StoredLoginInfo>>,
+    sessions: ArcRwLock<HashMap<Uuid, StoredSession>>,
+    refresh_tokens: ArcRwLock<HashMap<String, StoredRefreshToken>>,
+    invites: ArcRwLock<HashMap<String, StoredInvite>>,
+    totps: ArcRwLock<HashMap<LoginName, StoredTotp>>,
+    recovery_code_hashes: ArcRwLock<HashMap<LoginName, HashSet<String>>>,
+    external_identities: ArcRwLock<HashMap<(String, String), StoredExternalIdentity>>,
+    api_keys: ArcRwLock<HashMap<String, StoredApiKey>>,
+    audit_events: ArcRwLock<Vec<AuditEvent>>,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self {
+            logins: arc_rw_lock_new(BTreeMap::new()),
+            sessions: arc_rw_lock_new(HashMap::new()),
+            refresh_tokens: arc_rw_lock_new(HashMap::new()),
+            invites: arc_rw_lock_new(HashMap::new()),
+            totps: arc_rw_lock_new(HashMap::new()),
+            recovery_code_hashes: arc_rw_lock_new(HashMap::new()),
+            external_identities: arc_rw_lock_new(HashMap::new()),
+            api_keys: arc_rw_lock_new(HashMap::new()),
+            audit_events: arc_rw_lock_new(Vec::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl UserStore for InMemoryStore {
+    async fn get_user(&self, loginname: &LoginName) -> Result<Option<StoredLoginInfo>, BoxError> {
+        Ok(self.logins.read().get(loginname).cloned())
+    }
+
+    async fn insert_user(&self, login_info: StoredLoginInfo) -> Result<bool, BoxError> {
+        let mut logins = self.logins.write();
+        let loginname = LoginName(login_info.loginname.clone());
+        if logins.contains_key(&loginname) {
+            return Ok(false);
+        }
+        logins.insert(loginname, login_info);
+
+        Ok(true)
+    }
+
+    async fn update_user(&self, login_info: StoredLoginInfo) -> Result<bool, BoxError> {
+        Ok(self
+            .logins
+            .write()
+            .get_mut(&LoginName(login_info.loginname.clone()))
+            .map(|stored_login_info| {
+                stored_login_info.roles = login_info.roles;
+                stored_login_info.password_hash = login_info.password_hash;
+                stored_login_info.disabled = login_info.disabled;
+                stored_login_info.email = login_info.email;
+                stored_login_info.email_verified = login_info.email_verified;
+            })
+            .is_some())
+    }
+
+    async fn upsert_user(&self, login_info: StoredLoginInfo) -> Result<(), BoxError> {
+        self.logins
+            .write()
+            .insert(LoginName(login_info.loginname.clone()), login_info);
+
+        Ok(())
+    }
+
+    async fn list_users(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<StoredLoginInfo>, BoxError> {
+        Ok(self
+            .logins
+            .read()
+            .values()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn record_login(
+        &self,
+        loginname: &LoginName,
+        timestamp: UnixTimestamp,
+    ) -> Result<bool, BoxError> {
+        Ok(self
+            .logins
+            .write()
+            .get_mut(loginname)
+            .map(|login_info| login_info.last_login_at = Some(timestamp))
+            .is_some())
+    }
+
+    async fn delete_user(&self, loginname: &LoginName) -> Result<bool, BoxError> {
+        let deleted = self.logins.write().remove(loginname).is_some();
+
+        self.sessions
+            .write()
+            .retain(|_, session| session.loginname != loginname.0);
+        self.refresh_tokens
+            .write()
+            .retain(|_, refresh_token| refresh_token.loginname != loginname.0);
+        self.totps.write().remove(loginname);
+        self.recovery_code_hashes.write().remove(loginname);
+        self.external_identities
+            .write()
+            .retain(|_, external_identity| external_identity.loginname != loginname.0);
+        self.api_keys
+            .write()
+            .retain(|_, api_key| api_key.loginname != loginname.0);
+
+        Ok(deleted)
+    }
+}
+
+#[async_trait]
+impl SessionStore for InMemoryStore {
+    async fn insert_session(&self, session: StoredSession) -> Result<(), BoxError> {
+        self.sessions.write().insert(session.id, session);
+
+        Ok(())
+    }
+
+    async fn get_session(&self, session_id: &Uuid) -> Result<Option<StoredSession>, BoxError> {
+        Ok(self.sessions.read().get(session_id).cloned())
+    }
+
+    async fn list_sessions(&self, loginname: &LoginName) -> Result<Vec<StoredSession>, BoxError> {
+        let mut sessions = self
+            .sessions
+            .read()
+            .values()
+            .filter(|session| session.loginname == loginname.0)
+            .cloned()
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|session| session.created_at);
+
+        Ok(sessions)
+    }
+
+    async fn extend_session(
+        &self,
+        session_id: &Uuid,
+        last_refreshed_at: UnixTimestamp,
+        expires_at: UnixTimestamp,
+    ) -> Result<bool, BoxError> {
+        Ok(self
+            .sessions
+            .write()
+            .get_mut(session_id)
+            .map(|session| {
+                session.last_refreshed_at = last_refreshed_at;
+                session.expires_at = expires_at;
+            })
+            .is_some())
+    }
+
+    async fn revoke_session(&self, session_id: &Uuid) -> Result<bool, BoxError> {
+        Ok(self
+            .sessions
+            .write()
+            .get_mut(session_id)
+            .map(|session| session.revoked = true)
+            .is_some())
+    }
+
+    async fn delete_expired_sessions(&self, now: UnixTimestamp) -> Result<usize, BoxError> {
+        let mut sessions = self.sessions.write();
+        let count_before = sessions.len();
+        sessions.retain(|_, session| session.expires_at > now);
+
+        Ok(count_before - sessions.len())
+    }
+}
+
+#[async_trait]
+impl RefreshTokenStore for InMemoryStore {
+    async fn insert_refresh_token(
+        &self,
+        refresh_token: StoredRefreshToken,
+    ) -> Result<(), BoxError> {
+        self.refresh_tokens
+            .write()
+            .insert(refresh_token.token_hash.clone(), refresh_token);
+
+        Ok(())
+    }
+
+    async fn get_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<StoredRefreshToken>, BoxError> {
+        Ok(self.refresh_tokens.read().get(token_hash).cloned())
+    }
+
+    async fn use_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<StoredRefreshToken>, BoxError> {
+        Ok(self
+            .refresh_tokens
+            .write()
+            .get_mut(token_hash)
+            .map(|refresh_token| {
+                let previous = refresh_token.clone();
+                refresh_token.used = true;
+                previous
+            }))
+    }
+
+    async fn delete_refresh_token_family(&self, family_id: &Uuid) -> Result<(), BoxError> {
+        self.refresh_tokens
+            .write()
+            .retain(|_, refresh_token| refresh_token.family_id != *family_id);
+
+        Ok(())
+    }
+
+    async fn delete_expired_refresh_tokens(&self, now: UnixTimestamp) -> Result<usize, BoxError> {
+        let mut refresh_tokens = self.refresh_tokens.write();
+        let count_before = refresh_tokens.len();
+        refresh_tokens.retain(|_, refresh_token| refresh_token.expires_at > now);
+
+        Ok(count_before - refresh_tokens.len())
+    }
+}
+
+#[async_trait]
+impl InviteStore for InMemoryStore {
+    async fn insert_invite(&self, invite: StoredInvite) -> Result<(), BoxError> {
+        self.invites
+            .write()
+            .insert(invite.token_hash.clone(), invite);
+
+        Ok(())
+    }
+
+    async fn use_invite(&self, token_hash: &str) -> Result<Option<StoredInvite>, BoxError> {
+        Ok(self.invites.write().get_mut(token_hash).map(|invite| {
+            let previous = invite.clone();
+            invite.used = true;
+            previous
+        }))
+    }
+
+    async fn delete_expired_invites(&self, now: UnixTimestamp) -> Result<usize, BoxError> {
+        let mut invites = self.invites.write();
+        let count_before = invites.len();
+        invites.retain(|_, invite| invite.expires_at > now);
+
+        Ok(count_before - invites.len())
+    }
+}
+
+#[async_trait]
+impl TotpStore for InMemoryStore {
+    async fn get_totp(&self, loginname: &LoginName) -> Result<Option<StoredTotp>, BoxError> {
+        Ok(self.totps.read().get(loginname).cloned())
+    }
+
+    async fn upsert_totp(&self, totp: StoredTotp) -> Result<(), BoxError> {
+        self.totps
+            .write()
+            .insert(LoginName(totp.loginname.clone()), totp);
+
+        Ok(())
+    }
+
+    async fn confirm_totp(
+        &self,
+        loginname: &LoginName,
+        recovery_code_hashes: Vec<String>,
+    ) -> Result<bool, BoxError> {
+        let confirmed = self
+            .totps
+            .write()
+            .get_mut(loginname)
+            .map(|totp| totp.confirmed = true)
+            .is_some();
+
+        if confirmed {
+            self.recovery_code_hashes.write().insert(
+                loginname.clone(),
+                recovery_code_hashes.into_iter().collect(),
+            );
+        }
+
+        Ok(confirmed)
+    }
+
+    async fn use_totp_step(&self, loginname: &LoginName, step: u64) -> Result<bool, BoxError> {
+        Ok(self
+            .totps
+            .write()
+            .get_mut(loginname)
+            .filter(|totp| {
+                totp.last_used_step
+                    .is_none_or(|last_used_step| last_used_step < step)
+            })
+            .map(|totp| totp.last_used_step = Some(step))
+            .is_some())
+    }
+
+    async fn delete_totp(&self, loginname: &LoginName) -> Result<bool, BoxError> {
+        self.recovery_code_hashes.write().remove(loginname);
+
+        Ok(self.totps.write().remove(loginname).is_some())
+    }
+
+    async fn use_recovery_code(
+        &self,
+        loginname: &LoginName,
+        code_hash: &str,
+    ) -> Result<bool, BoxError> {
+        Ok(self
+            .recovery_code_hashes
+            .write()
+            .get_mut(loginname)
+            .is_some_and(|code_hashes| code_hashes.remove(code_hash)))
+    }
+}
+
+#[async_trait]
+impl ExternalIdentityStore for InMemoryStore {
+    async fn get_external_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<StoredExternalIdentity>, BoxError> {
+        Ok(self
+            .external_identities
+            .read()
+            .get(&(issuer.to_string(), subject.to_string()))
+            .cloned())
+    }
+
+    async fn insert_external_identity(
+        &self,
+        external_identity: StoredExternalIdentity,
+    ) -> Result<bool, BoxError> {
+        let mut external_identities = self.external_identities.write();
+        let key = (
+            external_identity.issuer.clone(),
+            external_identity.subject.clone(),
+        );
+        if external_identities.contains_key(&key) {
+            return Ok(false);
+        }
+        external_identities.insert(key, external_identity);
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for InMemoryStore {
+    async fn insert_api_key(&self, api_key: StoredApiKey) -> Result<(), BoxError> {
+        self.api_keys
+            .write()
+            .insert(api_key.key_hash.clone(), api_key);
+
+        Ok(())
+    }
+
+    async fn get_api_key(&self, key_hash: &str) -> Result<Option<StoredApiKey>, BoxError> {
+        Ok(self.api_keys.read().get(key_hash).cloned())
+    }
+
+    async fn list_api_keys(&self, loginname: &LoginName) -> Result<Vec<StoredApiKey>, BoxError> {
+        let mut api_keys: Vec<_> = self
+            .api_keys
+            .read()
+            .values()
+            .filter(|api_key| api_key.loginname == loginname.0)
+            .cloned()
+            .collect();
+        api_keys.sort_by_key(|api_key| api_key.created_at);
+
+        Ok(api_keys)
+    }
+
+    async fn record_api_key_use(
+        &self,
+        id: &Uuid,
+        timestamp: UnixTimestamp,
+    ) -> Result<bool, BoxError> {
+        Ok(self
+            .api_keys
+            .write()
+            .values_mut()
+            .find(|api_key| api_key.id == *id)
+            .map(|api_key| api_key.last_used_at = Some(timestamp))
+            .is_some())
+    }
+
+    async fn delete_api_key(&self, loginname: &LoginName, id: &Uuid) -> Result<bool, BoxError> {
+        let mut api_keys = self.api_keys.write();
+        let count_before = api_keys.len();
+        api_keys.retain(|_, api_key| !(api_key.id == *id && api_key.loginname == loginname.0));
+
+        Ok(api_keys.len() < count_before)
+    }
+}
+
+#[async_trait]
+impl AuditSink for InMemoryStore {
+    async fn append(&self, events: Vec<AuditEvent>) -> Result<(), BoxError> {
+        self.audit_events.write().extend(events);
+
+        Ok(())
+    }
+
+    async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>, BoxError> {
+        Ok(self
+            .audit_events
+            .read()
+            .iter()
+            .rev()
+            .filter(|event| query.matches(event))
+            .skip(query.offset)
+            .take(query.limit)
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn totp(loginname: &str) -> StoredTotp {
+        StoredTotp {
+            loginname: loginname.to_string(),
+            secret: "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".to_string(),
+            confirmed: true,
+            last_used_step: None,
+            created_at: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn used_totp_steps_can_not_be_replayed() {
+        let store = InMemoryStore::new();
+        let loginname = LoginName("user".to_string());
+        store.upsert_totp(totp("user")).await.unwrap();
+
+        assert!(store.use_totp_step(&loginname, 100).await.unwrap());
+        // the same code again
+        assert!(!store.use_totp_step(&loginname, 100).await.unwrap());
+        // the code of the previous step, still within the drift window
+        assert!(!store.use_totp_step(&loginname, 99).await.unwrap());
+        assert!(store.use_totp_step(&loginname, 101).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn totp_steps_of_unknown_users_are_not_used() {
+        let store = InMemoryStore::new();
+
+        assert!(!store
+            .use_totp_step(&LoginName("nobody".to_string()), 100)
+            .await
+            .unwrap());
+    }
+}
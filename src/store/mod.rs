@@ -0,0 +1,201 @@
+mod in_memory;
+mod sqlite;
+
+pub use in_memory::InMemoryStore;
+pub use sqlite::SqliteStore;
+
+use axum::async_trait;
+
+use uuid::Uuid;
+
+use crate::{
+    error::BoxError,
+    model::{
+        api_key::StoredApiKey,
+        external_identity::StoredExternalIdentity,
+        invite::StoredInvite,
+        login_info::{LoginName, StoredLoginInfo},
+        refresh_token::StoredRefreshToken,
+        session::StoredSession,
+        totp::StoredTotp,
+    },
+    timestamp::UnixTimestamp,
+};
+
+/// Everything the application persists, implemented by every storage backend
+pub trait Store:
+    UserStore
+    + SessionStore
+    + RefreshTokenStore
+    + InviteStore
+    + TotpStore
+    + ExternalIdentityStore
+    + ApiKeyStore
+{
+}
+
+impl<
+        T: UserStore
+            + SessionStore
+            + RefreshTokenStore
+            + InviteStore
+            + TotpStore
+            + ExternalIdentityStore
+            + ApiKeyStore,
+    > Store for T
+{
+}
+
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn get_user(&self, loginname: &LoginName) -> Result<Option<StoredLoginInfo>, BoxError>;
+
+    /// Inserts the user, returns false if a user with the same loginname already exists
+    async fn insert_user(&self, login_info: StoredLoginInfo) -> Result<bool, BoxError>;
+
+    /// Stores every property of the user except the creation and the last login time, returns
+    /// false if the user does not exist
+    async fn update_user(&self, login_info: StoredLoginInfo) -> Result<bool, BoxError>;
+
+    /// Inserts the user or replaces it if a user with the same loginname already exists
+    async fn upsert_user(&self, login_info: StoredLoginInfo) -> Result<(), BoxError>;
+
+    /// Returns at most `limit` users ordered by loginname, skipping the first `offset` users
+    async fn list_users(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<StoredLoginInfo>, BoxError>;
+
+    /// Stores the time of the last login, returns false if the user does not exist
+    async fn record_login(
+        &self,
+        loginname: &LoginName,
+        timestamp: UnixTimestamp,
+    ) -> Result<bool, BoxError>;
+
+    /// Returns false if the user does not exist
+    async fn delete_user(&self, loginname: &LoginName) -> Result<bool, BoxError>;
+}
+
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn insert_session(&self, session: StoredSession) -> Result<(), BoxError>;
+
+    async fn get_session(&self, session_id: &Uuid) -> Result<Option<StoredSession>, BoxError>;
+
+    /// Returns the sessions of the user ordered by creation time, including the inactive ones
+    async fn list_sessions(&self, loginname: &LoginName) -> Result<Vec<StoredSession>, BoxError>;
+
+    /// Returns false if the session does not exist
+    async fn extend_session(
+        &self,
+        session_id: &Uuid,
+        last_refreshed_at: UnixTimestamp,
+        expires_at: UnixTimestamp,
+    ) -> Result<bool, BoxError>;
+
+    /// Returns false if the session does not exist
+    async fn revoke_session(&self, session_id: &Uuid) -> Result<bool, BoxError>;
+
+    /// Returns the number of deleted sessions
+    async fn delete_expired_sessions(&self, now: UnixTimestamp) -> Result<usize, BoxError>;
+}
+
+#[async_trait]
+pub trait RefreshTokenStore: Send + Sync {
+    async fn insert_refresh_token(&self, refresh_token: StoredRefreshToken)
+        -> Result<(), BoxError>;
+
+    async fn get_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<StoredRefreshToken>, BoxError>;
+
+    /// Atomically marks the token as used and returns it as it was before the update
+    async fn use_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<StoredRefreshToken>, BoxError>;
+
+    async fn delete_refresh_token_family(&self, family_id: &Uuid) -> Result<(), BoxError>;
+
+    /// Returns the number of deleted tokens
+    async fn delete_expired_refresh_tokens(&self, now: UnixTimestamp) -> Result<usize, BoxError>;
+}
+
+#[async_trait]
+pub trait InviteStore: Send + Sync {
+    async fn insert_invite(&self, invite: StoredInvite) -> Result<(), BoxError>;
+
+    /// Atomically marks the invite as used and returns it as it was before the update
+    async fn use_invite(&self, token_hash: &str) -> Result<Option<StoredInvite>, BoxError>;
+
+    /// Returns the number of deleted invites
+    async fn delete_expired_invites(&self, now: UnixTimestamp) -> Result<usize, BoxError>;
+}
+
+#[async_trait]
+pub trait TotpStore: Send + Sync {
+    async fn get_totp(&self, loginname: &LoginName) -> Result<Option<StoredTotp>, BoxError>;
+
+    /// Inserts the TOTP of the user or replaces the existing one
+    async fn upsert_totp(&self, totp: StoredTotp) -> Result<(), BoxError>;
+
+    /// Marks the TOTP as confirmed and replaces the recovery codes of the user. Returns false if
+    /// the user has no TOTP.
+    async fn confirm_totp(
+        &self,
+        loginname: &LoginName,
+        recovery_code_hashes: Vec<String>,
+    ) -> Result<bool, BoxError>;
+
+    /// Atomically stores the time step of a used code. Returns false if the user has no TOTP or
+    /// a code of the same or a later time step was already used.
+    async fn use_totp_step(&self, loginname: &LoginName, step: u64) -> Result<bool, BoxError>;
+
+    /// Deletes the TOTP and the recovery codes of the user, returns false if the user has no TOTP
+    async fn delete_totp(&self, loginname: &LoginName) -> Result<bool, BoxError>;
+
+    /// Atomically deletes the recovery code, returns false if the user has no such code
+    async fn use_recovery_code(
+        &self,
+        loginname: &LoginName,
+        code_hash: &str,
+    ) -> Result<bool, BoxError>;
+}
+
+#[async_trait]
+pub trait ExternalIdentityStore: Send + Sync {
+    async fn get_external_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<StoredExternalIdentity>, BoxError>;
+
+    /// Inserts the identity, returns false if the same identity is already linked to a user
+    async fn insert_external_identity(
+        &self,
+        external_identity: StoredExternalIdentity,
+    ) -> Result<bool, BoxError>;
+}
+
+#[async_trait]
+pub trait ApiKeyStore: Send + Sync {
+    async fn insert_api_key(&self, api_key: StoredApiKey) -> Result<(), BoxError>;
+
+    async fn get_api_key(&self, key_hash: &str) -> Result<Option<StoredApiKey>, BoxError>;
+
+    /// Returns the keys of the user ordered by creation time, including the expired ones
+    async fn list_api_keys(&self, loginname: &LoginName) -> Result<Vec<StoredApiKey>, BoxError>;
+
+    /// Stores the time the key was last used, returns false if the key does not exist
+    async fn record_api_key_use(
+        &self,
+        id: &Uuid,
+        timestamp: UnixTimestamp,
+    ) -> Result<bool, BoxError>;
+
+    /// Deletes the key if it belongs to the user, returns false otherwise
+    async fn delete_api_key(&self, loginname: &LoginName, id: &Uuid) -> Result<bool, BoxError>;
+}
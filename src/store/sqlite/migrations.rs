@@ -0,0 +1,132 @@
+use rusqlite::Connection;
+
+/// Schema migrations embedded into the binary, the n-th migration brings the schema to
+/// `user_version` n + 1. Append new migrations, never modify the existing ones.
+const MIGRATIONS: &[(&str, &str)] = &[
+    (
+        "0001_create_users",
+        include_str!("migrations/0001_create_users.sql"),
+    ),
+    (
+        "0002_create_refresh_tokens",
+        include_str!("migrations/0002_create_refresh_tokens.sql"),
+    ),
+    (
+        "0003_create_sessions",
+        include_str!("migrations/0003_create_sessions.sql"),
+    ),
+    (
+        "0004_replace_role_with_roles",
+        include_str!("migrations/0004_replace_role_with_roles.sql"),
+    ),
+    (
+        "0005_create_invites",
+        include_str!("migrations/0005_create_invites.sql"),
+    ),
+    (
+        "0006_create_totp",
+        include_str!("migrations/0006_create_totp.sql"),
+    ),
+    (
+        "0007_add_users_email",
+        include_str!("migrations/0007_add_users_email.sql"),
+    ),
+    (
+        "0008_create_external_identities",
+        include_str!("migrations/0008_create_external_identities.sql"),
+    ),
+    (
+        "0009_create_api_keys",
+        include_str!("migrations/0009_create_api_keys.sql"),
+    ),
+    (
+        "0010_add_sessions_impersonator",
+        include_str!("migrations/0010_add_sessions_impersonator.sql"),
+    ),
+    (
+        "0011_create_audit_events",
+        include_str!("migrations/0011_create_audit_events.sql"),
+    ),
+];
+
+pub fn apply(connection: &mut Connection) -> rusqlite::Result<()> {
+    let user_version: usize =
+        connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    for (index, (name, migration)) in MIGRATIONS.iter().enumerate().skip(user_version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+
+        log::info!("Applied database migration, name = '{name}'");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_version(connection: &Connection) -> usize {
+        connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    fn table_names(connection: &Connection) -> Vec<String> {
+        connection
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn every_migration_is_applied_to_an_empty_database() {
+        let mut connection = Connection::open_in_memory().unwrap();
+
+        apply(&mut connection).unwrap();
+
+        assert_eq!(user_version(&connection), MIGRATIONS.len());
+        assert_eq!(
+            table_names(&connection),
+            [
+                "api_keys",
+                "audit_events",
+                "external_identities",
+                "invites",
+                "recovery_codes",
+                "refresh_tokens",
+                "sessions",
+                "totp",
+                "users",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_database_at_the_current_version_is_not_migrated_again() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        apply(&mut connection).unwrap();
+        connection
+            .execute(
+                "INSERT INTO users (loginname, roles, password_hash, created_at)
+                VALUES ('user', '[]', '', 0)",
+                [],
+            )
+            .unwrap();
+
+        // as at every startup with an existing database
+        apply(&mut connection).unwrap();
+
+        assert_eq!(user_version(&connection), MIGRATIONS.len());
+        let user_count: usize = connection
+            .query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(user_count, 1);
+    }
+}
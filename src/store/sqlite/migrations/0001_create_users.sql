@@ -0,0 +1,8 @@
+CREATE TABLE users (
+    loginname TEXT NOT NULL PRIMARY KEY,
+    role TEXT NOT NULL,
+    password_hash TEXT NOT NULL,
+    logged_in INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL,
+    last_login_at INTEGER
+);
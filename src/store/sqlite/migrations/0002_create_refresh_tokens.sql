@@ -0,0 +1,9 @@
+CREATE TABLE refresh_tokens (
+    token_hash TEXT NOT NULL PRIMARY KEY,
+    family_id TEXT NOT NULL,
+    loginname TEXT NOT NULL REFERENCES users (loginname) ON DELETE CASCADE,
+    expires_at INTEGER NOT NULL,
+    used INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX refresh_tokens_family_id ON refresh_tokens (family_id);
@@ -0,0 +1,16 @@
+CREATE TABLE sessions (
+    id TEXT NOT NULL PRIMARY KEY,
+    loginname TEXT NOT NULL REFERENCES users (loginname) ON DELETE CASCADE,
+    user_agent TEXT,
+    created_at INTEGER NOT NULL,
+    last_refreshed_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    revoked INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX sessions_loginname ON sessions (loginname);
+
+-- the login state is tracked per session from now on, the refresh tokens issued before belong to
+-- no session
+ALTER TABLE users DROP COLUMN logged_in;
+DELETE FROM refresh_tokens;
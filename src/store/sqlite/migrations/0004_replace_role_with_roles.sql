@@ -0,0 +1,4 @@
+-- the roles are stored as a JSON array
+ALTER TABLE users ADD COLUMN roles TEXT NOT NULL DEFAULT '[]';
+UPDATE users SET roles = json_array(role);
+ALTER TABLE users DROP COLUMN role;
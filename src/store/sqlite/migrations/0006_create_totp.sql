@@ -0,0 +1,13 @@
+CREATE TABLE totp (
+    loginname TEXT NOT NULL PRIMARY KEY REFERENCES users (loginname) ON DELETE CASCADE,
+    secret TEXT NOT NULL,
+    confirmed INTEGER NOT NULL DEFAULT 0,
+    last_used_step INTEGER,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE recovery_codes (
+    loginname TEXT NOT NULL REFERENCES users (loginname) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL,
+    PRIMARY KEY (loginname, code_hash)
+);
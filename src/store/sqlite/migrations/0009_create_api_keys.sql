@@ -0,0 +1,12 @@
+CREATE TABLE api_keys (
+    id TEXT NOT NULL PRIMARY KEY,
+    key_hash TEXT NOT NULL UNIQUE,
+    loginname TEXT NOT NULL REFERENCES users (loginname) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    permissions TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER,
+    last_used_at INTEGER
+);
+
+CREATE INDEX api_keys_loginname ON api_keys (loginname);
@@ -0,0 +1,24 @@
+-- no foreign key to users, the events of deleted users are kept
+CREATE TABLE audit_events (
+    id TEXT NOT NULL PRIMARY KEY,
+    timestamp INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    loginname TEXT,
+    actor TEXT,
+    ip TEXT,
+    details TEXT
+);
+
+CREATE INDEX audit_events_timestamp ON audit_events (timestamp);
+CREATE INDEX audit_events_loginname ON audit_events (loginname);
+CREATE INDEX audit_events_actor ON audit_events (actor);
+
+CREATE TRIGGER audit_events_no_update BEFORE UPDATE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit events are append-only');
+END;
+
+CREATE TRIGGER audit_events_no_delete BEFORE DELETE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit events are append-only');
+END;
@@ -0,0 +1,1048 @@
+mod migrations;
+
+use std::path::Path;
+
+use axum::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditEvent, AuditEventKind, AuditQuery, AuditSink},
+    error::BoxError,
+    model::{
+        api_key::StoredApiKey,
+        external_identity::StoredExternalIdentity,
+        invite::StoredInvite,
+        login_info::{LoginName, StoredLoginInfo},
+        refresh_token::StoredRefreshToken,
+        session::StoredSession,
+        totp::StoredTotp,
+    },
+    syn::{arc_mutex_new, ArcMutex},
+    timestamp::UnixTimestamp,
+};
+
+use super::{
+    ApiKeyStore, ExternalIdentityStore, InviteStore, RefreshTokenStore, SessionStore, TotpStore,
+    UserStore,
+};
+
+const USER_COLUMNS: &str =
+    "loginname, roles, password_hash, created_at, last_login_at, disabled, email, email_verified";
+const SESSION_COLUMNS: &str =
+    "id, loginname, user_agent, created_at, last_refreshed_at, expires_at, revoked, impersonator";
+const REFRESH_TOKEN_COLUMNS: &str = "token_hash, family_id, loginname, expires_at, used";
+const INVITE_COLUMNS: &str = "token_hash, roles, created_by, created_at, expires_at, used";
+const TOTP_COLUMNS: &str = "loginname, secret, confirmed, last_used_step, created_at";
+const EXTERNAL_IDENTITY_COLUMNS: &str = "issuer, subject, loginname, created_at";
+const AUDIT_EVENT_COLUMNS: &str = "id, timestamp, kind, loginname, actor, ip, details";
+const API_KEY_COLUMNS: &str =
+    "id, key_hash, loginname, name, permissions, created_at, expires_at, last_used_at";
+
+#[derive(Clone)]
+pub struct SqliteStore {
+    connection: ArcMutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database at the given path and applies the pending migrations.
+    /// The path ":memory:" opens a new in-memory database.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, BoxError> {
+        let mut connection = Connection::open(path)?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        migrations::apply(&mut connection)?;
+
+        Ok(Self {
+            connection: arc_mutex_new(connection),
+        })
+    }
+
+    async fn with_connection<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T, BoxError> {
+        let connection = self.connection.clone();
+        let result = tokio::task::spawn_blocking(move || f(&mut connection.lock())).await??;
+        Ok(result)
+    }
+}
+
+fn read_login_info(row: &Row) -> rusqlite::Result<StoredLoginInfo> {
+    Ok(StoredLoginInfo {
+        loginname: row.get("loginname")?,
+        roles: read_json(row, "roles")?,
+        password_hash: row.get("password_hash")?,
+        created_at: row.get("created_at")?,
+        last_login_at: row.get("last_login_at")?,
+        disabled: row.get("disabled")?,
+        email: row.get("email")?,
+        email_verified: row.get("email_verified")?,
+    })
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(row: &Row, column: &str) -> rusqlite::Result<T> {
+    let json: String = row.get(column)?;
+    serde_json::from_str(&json).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(
+            row.as_ref().column_index(column).unwrap_or_default(),
+            rusqlite::types::Type::Text,
+            Box::new(e),
+        )
+    })
+}
+
+fn to_json(value: &impl serde::Serialize) -> rusqlite::Result<String> {
+    serde_json::to_string(value).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+fn read_uuid(row: &Row, column: &str) -> rusqlite::Result<Uuid> {
+    let uuid: String = row.get(column)?;
+    Uuid::parse_str(&uuid).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(
+            row.as_ref().column_index(column).unwrap_or_default(),
+            rusqlite::types::Type::Text,
+            Box::new(e),
+        )
+    })
+}
+
+fn read_session(row: &Row) -> rusqlite::Result<StoredSession> {
+    Ok(StoredSession {
+        id: read_uuid(row, "id")?,
+        loginname: row.get("loginname")?,
+        user_agent: row.get("user_agent")?,
+        created_at: row.get("created_at")?,
+        last_refreshed_at: row.get("last_refreshed_at")?,
+        expires_at: row.get("expires_at")?,
+        revoked: row.get("revoked")?,
+        impersonator: row.get("impersonator")?,
+    })
+}
+
+fn read_refresh_token(row: &Row) -> rusqlite::Result<StoredRefreshToken> {
+    Ok(StoredRefreshToken {
+        token_hash: row.get("token_hash")?,
+        family_id: read_uuid(row, "family_id")?,
+        loginname: row.get("loginname")?,
+        expires_at: row.get("expires_at")?,
+        used: row.get("used")?,
+    })
+}
+
+fn read_invite(row: &Row) -> rusqlite::Result<StoredInvite> {
+    Ok(StoredInvite {
+        token_hash: row.get("token_hash")?,
+        roles: read_json(row, "roles")?,
+        created_by: row.get("created_by")?,
+        created_at: row.get("created_at")?,
+        expires_at: row.get("expires_at")?,
+        used: row.get("used")?,
+    })
+}
+
+fn read_totp(row: &Row) -> rusqlite::Result<StoredTotp> {
+    Ok(StoredTotp {
+        loginname: row.get("loginname")?,
+        secret: row.get("secret")?,
+        confirmed: row.get("confirmed")?,
+        last_used_step: row.get("last_used_step")?,
+        created_at: row.get("created_at")?,
+    })
+}
+
+fn read_external_identity(row: &Row) -> rusqlite::Result<StoredExternalIdentity> {
+    Ok(StoredExternalIdentity {
+        issuer: row.get("issuer")?,
+        subject: row.get("subject")?,
+        loginname: row.get("loginname")?,
+        created_at: row.get("created_at")?,
+    })
+}
+
+fn read_api_key(row: &Row) -> rusqlite::Result<StoredApiKey> {
+    Ok(StoredApiKey {
+        id: read_uuid(row, "id")?,
+        key_hash: row.get("key_hash")?,
+        loginname: row.get("loginname")?,
+        name: row.get("name")?,
+        permissions: read_json(row, "permissions")?,
+        created_at: row.get("created_at")?,
+        expires_at: row.get("expires_at")?,
+        last_used_at: row.get("last_used_at")?,
+    })
+}
+
+fn read_audit_event(row: &Row) -> rusqlite::Result<AuditEvent> {
+    let kind: String = row.get("kind")?;
+    let ip: Option<String> = row.get("ip")?;
+    let details: Option<String> = row.get("details")?;
+
+    Ok(AuditEvent {
+        id: read_uuid(row, "id")?,
+        timestamp: row.get("timestamp")?,
+        kind: serde_json::from_value(serde_json::Value::String(kind))
+            .map_err(|e| conversion_error(row, "kind", e))?,
+        loginname: row.get("loginname")?,
+        actor: row.get("actor")?,
+        ip: ip
+            .map(|ip| ip.parse())
+            .transpose()
+            .map_err(|e| conversion_error(row, "ip", e))?,
+        details: details
+            .map(|details| serde_json::from_str(&details))
+            .transpose()
+            .map_err(|e| conversion_error(row, "details", e))?
+            .unwrap_or_default(),
+    })
+}
+
+fn audit_event_kind_name(kind: AuditEventKind) -> rusqlite::Result<String> {
+    match serde_json::to_value(kind) {
+        Ok(serde_json::Value::String(name)) => Ok(name),
+        Ok(_) => Err(rusqlite::Error::ToSqlConversionFailure(
+            "the audit event kind is not serialized as a string".into(),
+        )),
+        Err(e) => Err(rusqlite::Error::ToSqlConversionFailure(Box::new(e))),
+    }
+}
+
+fn conversion_error(
+    row: &Row,
+    column: &str,
+    e: impl std::error::Error + Send + Sync + 'static,
+) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(
+        row.as_ref().column_index(column).unwrap_or_default(),
+        rusqlite::types::Type::Text,
+        Box::new(e),
+    )
+}
+
+#[async_trait]
+impl UserStore for SqliteStore {
+    async fn get_user(&self, loginname: &LoginName) -> Result<Option<StoredLoginInfo>, BoxError> {
+        let loginname = loginname.0.clone();
+        self.with_connection(move |connection| {
+            connection
+                .query_row(
+                    &format!("SELECT {USER_COLUMNS} FROM users WHERE loginname = ?1"),
+                    params![loginname],
+                    read_login_info,
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn insert_user(&self, login_info: StoredLoginInfo) -> Result<bool, BoxError> {
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    &format!(
+                        "INSERT INTO users ({USER_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                        ON CONFLICT (loginname) DO NOTHING"
+                    ),
+                    params![
+                        login_info.loginname,
+                        to_json(&login_info.roles)?,
+                        login_info.password_hash,
+                        login_info.created_at,
+                        login_info.last_login_at,
+                        login_info.disabled,
+                        login_info.email,
+                        login_info.email_verified,
+                    ],
+                )
+                .map(|inserted_rows| inserted_rows > 0)
+        })
+        .await
+    }
+
+    async fn update_user(&self, login_info: StoredLoginInfo) -> Result<bool, BoxError> {
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    "UPDATE users SET
+                        roles = ?1, password_hash = ?2, disabled = ?3, email = ?4,
+                        email_verified = ?5
+                    WHERE loginname = ?6",
+                    params![
+                        to_json(&login_info.roles)?,
+                        login_info.password_hash,
+                        login_info.disabled,
+                        login_info.email,
+                        login_info.email_verified,
+                        login_info.loginname,
+                    ],
+                )
+                .map(|updated_rows| updated_rows > 0)
+        })
+        .await
+    }
+
+    async fn upsert_user(&self, login_info: StoredLoginInfo) -> Result<(), BoxError> {
+        self.with_connection(move |connection| {
+            // not "INSERT OR REPLACE", that would delete the rows referencing the user
+            connection.execute(
+                &format!(
+                    "INSERT INTO users ({USER_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                    ON CONFLICT (loginname) DO UPDATE SET
+                        roles = excluded.roles,
+                        password_hash = excluded.password_hash,
+                        created_at = excluded.created_at,
+                        last_login_at = excluded.last_login_at,
+                        disabled = excluded.disabled,
+                        email = excluded.email,
+                        email_verified = excluded.email_verified"
+                ),
+                params![
+                    login_info.loginname,
+                    to_json(&login_info.roles)?,
+                    login_info.password_hash,
+                    login_info.created_at,
+                    login_info.last_login_at,
+                    login_info.disabled,
+                    login_info.email,
+                    login_info.email_verified,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_users(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<StoredLoginInfo>, BoxError> {
+        self.with_connection(move |connection| {
+            connection
+                .prepare(&format!(
+                    "SELECT {USER_COLUMNS} FROM users ORDER BY loginname LIMIT ?1 OFFSET ?2"
+                ))?
+                .query_map(params![limit, offset], read_login_info)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn record_login(
+        &self,
+        loginname: &LoginName,
+        timestamp: UnixTimestamp,
+    ) -> Result<bool, BoxError> {
+        let loginname = loginname.0.clone();
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    "UPDATE users SET last_login_at = ?1 WHERE loginname = ?2",
+                    params![timestamp, loginname],
+                )
+                .map(|updated_rows| updated_rows > 0)
+        })
+        .await
+    }
+
+    async fn delete_user(&self, loginname: &LoginName) -> Result<bool, BoxError> {
+        let loginname = loginname.0.clone();
+        self.with_connection(move |connection| {
+            connection
+                .execute("DELETE FROM users WHERE loginname = ?1", params![loginname])
+                .map(|deleted_rows| deleted_rows > 0)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteStore {
+    async fn insert_session(&self, session: StoredSession) -> Result<(), BoxError> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                &format!(
+                    "INSERT INTO sessions ({SESSION_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+                ),
+                params![
+                    session.id.to_string(),
+                    session.loginname,
+                    session.user_agent,
+                    session.created_at,
+                    session.last_refreshed_at,
+                    session.expires_at,
+                    session.revoked,
+                    session.impersonator,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_session(&self, session_id: &Uuid) -> Result<Option<StoredSession>, BoxError> {
+        let session_id = session_id.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .query_row(
+                    &format!("SELECT {SESSION_COLUMNS} FROM sessions WHERE id = ?1"),
+                    params![session_id],
+                    read_session,
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn list_sessions(&self, loginname: &LoginName) -> Result<Vec<StoredSession>, BoxError> {
+        let loginname = loginname.0.clone();
+        self.with_connection(move |connection| {
+            connection
+                .prepare(&format!(
+                    "SELECT {SESSION_COLUMNS} FROM sessions WHERE loginname = ?1 ORDER BY created_at"
+                ))?
+                .query_map(params![loginname], read_session)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn extend_session(
+        &self,
+        session_id: &Uuid,
+        last_refreshed_at: UnixTimestamp,
+        expires_at: UnixTimestamp,
+    ) -> Result<bool, BoxError> {
+        let session_id = session_id.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    "UPDATE sessions SET last_refreshed_at = ?1, expires_at = ?2 WHERE id = ?3",
+                    params![last_refreshed_at, expires_at, session_id],
+                )
+                .map(|updated_rows| updated_rows > 0)
+        })
+        .await
+    }
+
+    async fn revoke_session(&self, session_id: &Uuid) -> Result<bool, BoxError> {
+        let session_id = session_id.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    "UPDATE sessions SET revoked = 1 WHERE id = ?1",
+                    params![session_id],
+                )
+                .map(|updated_rows| updated_rows > 0)
+        })
+        .await
+    }
+
+    async fn delete_expired_sessions(&self, now: UnixTimestamp) -> Result<usize, BoxError> {
+        self.with_connection(move |connection| {
+            connection.execute("DELETE FROM sessions WHERE expires_at <= ?1", params![now])
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl RefreshTokenStore for SqliteStore {
+    async fn insert_refresh_token(
+        &self,
+        refresh_token: StoredRefreshToken,
+    ) -> Result<(), BoxError> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                &format!(
+                    "INSERT INTO refresh_tokens ({REFRESH_TOKEN_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5)"
+                ),
+                params![
+                    refresh_token.token_hash,
+                    refresh_token.family_id.to_string(),
+                    refresh_token.loginname,
+                    refresh_token.expires_at,
+                    refresh_token.used,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<StoredRefreshToken>, BoxError> {
+        let token_hash = token_hash.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .query_row(
+                    &format!(
+                        "SELECT {REFRESH_TOKEN_COLUMNS} FROM refresh_tokens WHERE token_hash = ?1"
+                    ),
+                    params![token_hash],
+                    read_refresh_token,
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn use_refresh_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<StoredRefreshToken>, BoxError> {
+        let token_hash = token_hash.to_string();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+
+            let refresh_token = transaction
+                .query_row(
+                    &format!(
+                        "SELECT {REFRESH_TOKEN_COLUMNS} FROM refresh_tokens WHERE token_hash = ?1"
+                    ),
+                    params![token_hash],
+                    read_refresh_token,
+                )
+                .optional()?;
+
+            transaction.execute(
+                "UPDATE refresh_tokens SET used = 1 WHERE token_hash = ?1",
+                params![token_hash],
+            )?;
+            transaction.commit()?;
+
+            Ok(refresh_token)
+        })
+        .await
+    }
+
+    async fn delete_refresh_token_family(&self, family_id: &Uuid) -> Result<(), BoxError> {
+        let family_id = family_id.to_string();
+        self.with_connection(move |connection| {
+            connection.execute(
+                "DELETE FROM refresh_tokens WHERE family_id = ?1",
+                params![family_id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_expired_refresh_tokens(&self, now: UnixTimestamp) -> Result<usize, BoxError> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                "DELETE FROM refresh_tokens WHERE expires_at <= ?1",
+                params![now],
+            )
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl InviteStore for SqliteStore {
+    async fn insert_invite(&self, invite: StoredInvite) -> Result<(), BoxError> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                &format!("INSERT INTO invites ({INVITE_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)"),
+                params![
+                    invite.token_hash,
+                    to_json(&invite.roles)?,
+                    invite.created_by,
+                    invite.created_at,
+                    invite.expires_at,
+                    invite.used,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn use_invite(&self, token_hash: &str) -> Result<Option<StoredInvite>, BoxError> {
+        let token_hash = token_hash.to_string();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+
+            let invite = transaction
+                .query_row(
+                    &format!("SELECT {INVITE_COLUMNS} FROM invites WHERE token_hash = ?1"),
+                    params![token_hash],
+                    read_invite,
+                )
+                .optional()?;
+
+            transaction.execute(
+                "UPDATE invites SET used = 1 WHERE token_hash = ?1",
+                params![token_hash],
+            )?;
+            transaction.commit()?;
+
+            Ok(invite)
+        })
+        .await
+    }
+
+    async fn delete_expired_invites(&self, now: UnixTimestamp) -> Result<usize, BoxError> {
+        self.with_connection(move |connection| {
+            connection.execute("DELETE FROM invites WHERE expires_at <= ?1", params![now])
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl TotpStore for SqliteStore {
+    async fn get_totp(&self, loginname: &LoginName) -> Result<Option<StoredTotp>, BoxError> {
+        let loginname = loginname.0.clone();
+        self.with_connection(move |connection| {
+            connection
+                .query_row(
+                    &format!("SELECT {TOTP_COLUMNS} FROM totp WHERE loginname = ?1"),
+                    params![loginname],
+                    read_totp,
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn upsert_totp(&self, totp: StoredTotp) -> Result<(), BoxError> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                &format!(
+                    "INSERT INTO totp ({TOTP_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5)
+                    ON CONFLICT (loginname) DO UPDATE SET
+                        secret = excluded.secret,
+                        confirmed = excluded.confirmed,
+                        last_used_step = excluded.last_used_step,
+                        created_at = excluded.created_at"
+                ),
+                params![
+                    totp.loginname,
+                    totp.secret,
+                    totp.confirmed,
+                    totp.last_used_step,
+                    totp.created_at,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn confirm_totp(
+        &self,
+        loginname: &LoginName,
+        recovery_code_hashes: Vec<String>,
+    ) -> Result<bool, BoxError> {
+        let loginname = loginname.0.clone();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+
+            let updated_rows = transaction.execute(
+                "UPDATE totp SET confirmed = 1 WHERE loginname = ?1",
+                params![loginname],
+            )?;
+            if updated_rows == 0 {
+                return Ok(false);
+            }
+
+            transaction.execute(
+                "DELETE FROM recovery_codes WHERE loginname = ?1",
+                params![loginname],
+            )?;
+            for code_hash in recovery_code_hashes {
+                transaction.execute(
+                    "INSERT INTO recovery_codes (loginname, code_hash) VALUES (?1, ?2)",
+                    params![loginname, code_hash],
+                )?;
+            }
+            transaction.commit()?;
+
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn use_totp_step(&self, loginname: &LoginName, step: u64) -> Result<bool, BoxError> {
+        let loginname = loginname.0.clone();
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    "UPDATE totp SET last_used_step = ?1
+                    WHERE loginname = ?2 AND (last_used_step IS NULL OR last_used_step < ?1)",
+                    params![step, loginname],
+                )
+                .map(|updated_rows| updated_rows > 0)
+        })
+        .await
+    }
+
+    async fn delete_totp(&self, loginname: &LoginName) -> Result<bool, BoxError> {
+        let loginname = loginname.0.clone();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+
+            transaction.execute(
+                "DELETE FROM recovery_codes WHERE loginname = ?1",
+                params![loginname],
+            )?;
+            let deleted_rows =
+                transaction.execute("DELETE FROM totp WHERE loginname = ?1", params![loginname])?;
+            transaction.commit()?;
+
+            Ok(deleted_rows > 0)
+        })
+        .await
+    }
+
+    async fn use_recovery_code(
+        &self,
+        loginname: &LoginName,
+        code_hash: &str,
+    ) -> Result<bool, BoxError> {
+        let loginname = loginname.0.clone();
+        let code_hash = code_hash.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    "DELETE FROM recovery_codes WHERE loginname = ?1 AND code_hash = ?2",
+                    params![loginname, code_hash],
+                )
+                .map(|deleted_rows| deleted_rows > 0)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl ExternalIdentityStore for SqliteStore {
+    async fn get_external_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<StoredExternalIdentity>, BoxError> {
+        let issuer = issuer.to_string();
+        let subject = subject.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .query_row(
+                    &format!(
+                        "SELECT {EXTERNAL_IDENTITY_COLUMNS} FROM external_identities
+                        WHERE issuer = ?1 AND subject = ?2"
+                    ),
+                    params![issuer, subject],
+                    read_external_identity,
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn insert_external_identity(
+        &self,
+        external_identity: StoredExternalIdentity,
+    ) -> Result<bool, BoxError> {
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    &format!(
+                        "INSERT INTO external_identities ({EXTERNAL_IDENTITY_COLUMNS})
+                        VALUES (?1, ?2, ?3, ?4)
+                        ON CONFLICT (issuer, subject) DO NOTHING"
+                    ),
+                    params![
+                        external_identity.issuer,
+                        external_identity.subject,
+                        external_identity.loginname,
+                        external_identity.created_at,
+                    ],
+                )
+                .map(|inserted_rows| inserted_rows > 0)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for SqliteStore {
+    async fn insert_api_key(&self, api_key: StoredApiKey) -> Result<(), BoxError> {
+        self.with_connection(move |connection| {
+            connection.execute(
+                &format!(
+                    "INSERT INTO api_keys ({API_KEY_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"
+                ),
+                params![
+                    api_key.id.to_string(),
+                    api_key.key_hash,
+                    api_key.loginname,
+                    api_key.name,
+                    to_json(&api_key.permissions)?,
+                    api_key.created_at,
+                    api_key.expires_at,
+                    api_key.last_used_at,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_api_key(&self, key_hash: &str) -> Result<Option<StoredApiKey>, BoxError> {
+        let key_hash = key_hash.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .query_row(
+                    &format!("SELECT {API_KEY_COLUMNS} FROM api_keys WHERE key_hash = ?1"),
+                    params![key_hash],
+                    read_api_key,
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn list_api_keys(&self, loginname: &LoginName) -> Result<Vec<StoredApiKey>, BoxError> {
+        let loginname = loginname.0.clone();
+        self.with_connection(move |connection| {
+            connection
+                .prepare(&format!(
+                    "SELECT {API_KEY_COLUMNS} FROM api_keys WHERE loginname = ?1
+                    ORDER BY created_at"
+                ))?
+                .query_map(params![loginname], read_api_key)?
+                .collect()
+        })
+        .await
+    }
+
+    async fn record_api_key_use(
+        &self,
+        id: &Uuid,
+        timestamp: UnixTimestamp,
+    ) -> Result<bool, BoxError> {
+        let id = id.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    "UPDATE api_keys SET last_used_at = ?1 WHERE id = ?2",
+                    params![timestamp, id],
+                )
+                .map(|updated_rows| updated_rows > 0)
+        })
+        .await
+    }
+
+    async fn delete_api_key(&self, loginname: &LoginName, id: &Uuid) -> Result<bool, BoxError> {
+        let loginname = loginname.0.clone();
+        let id = id.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .execute(
+                    "DELETE FROM api_keys WHERE id = ?1 AND loginname = ?2",
+                    params![id, loginname],
+                )
+                .map(|deleted_rows| deleted_rows > 0)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl AuditSink for SqliteStore {
+    async fn append(&self, events: Vec<AuditEvent>) -> Result<(), BoxError> {
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            for event in events {
+                transaction.execute(
+                    &format!(
+                        "INSERT INTO audit_events ({AUDIT_EVENT_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+                    ),
+                    params![
+                        event.id.to_string(),
+                        event.timestamp,
+                        audit_event_kind_name(event.kind)?,
+                        event.loginname,
+                        event.actor,
+                        event.ip.map(|ip| ip.to_string()),
+                        (!event.details.is_null())
+                            .then(|| to_json(&event.details))
+                            .transpose()?,
+                    ],
+                )?;
+            }
+            transaction.commit()
+        })
+        .await
+    }
+
+    async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>, BoxError> {
+        let query = query.clone();
+        self.with_connection(move |connection| {
+            connection
+                .prepare(&format!(
+                    "SELECT {AUDIT_EVENT_COLUMNS} FROM audit_events
+                    WHERE (?1 IS NULL OR loginname = ?1 OR actor = ?1)
+                        AND (?2 IS NULL OR kind = ?2)
+                        AND (?3 IS NULL OR timestamp >= ?3)
+                        AND (?4 IS NULL OR timestamp < ?4)
+                    ORDER BY timestamp DESC, rowid DESC
+                    LIMIT ?5 OFFSET ?6"
+                ))?
+                .query_map(
+                    params![
+                        query.loginname,
+                        query.kind.map(audit_event_kind_name).transpose()?,
+                        query.since,
+                        query.until,
+                        query.limit,
+                        query.offset,
+                    ],
+                    read_audit_event,
+                )?
+                .collect()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    fn store() -> SqliteStore {
+        SqliteStore::open(":memory:").unwrap()
+    }
+
+    fn user(loginname: &str, password_hash: &str) -> StoredLoginInfo {
+        StoredLoginInfo {
+            loginname: loginname.to_string(),
+            roles: BTreeSet::from(["regular".to_string()]),
+            password_hash: password_hash.to_string(),
+            created_at: 100,
+            last_login_at: Some(200),
+            disabled: false,
+            email: Some(format!("{loginname}@example.com")),
+            email_verified: true,
+        }
+    }
+
+    fn refresh_token(token_hash: &str, family_id: Uuid) -> StoredRefreshToken {
+        StoredRefreshToken {
+            token_hash: token_hash.to_string(),
+            family_id,
+            loginname: "user".to_string(),
+            expires_at: 1000,
+            used: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn users_are_upserted_read_and_deleted() {
+        let store = store();
+        let loginname = LoginName("user".to_string());
+        assert!(store.get_user(&loginname).await.unwrap().is_none());
+
+        store.upsert_user(user("user", "hash")).await.unwrap();
+        let stored = store.get_user(&loginname).await.unwrap().unwrap();
+        assert_eq!(stored.loginname, "user");
+        assert_eq!(stored.roles, BTreeSet::from(["regular".to_string()]));
+        assert_eq!(stored.password_hash, "hash");
+        assert_eq!(stored.created_at, 100);
+        assert_eq!(stored.last_login_at, Some(200));
+        assert!(!stored.disabled);
+        assert_eq!(stored.email.as_deref(), Some("user@example.com"));
+        assert!(stored.email_verified);
+
+        store
+            .upsert_user(StoredLoginInfo {
+                roles: BTreeSet::from(["admin".to_string()]),
+                disabled: true,
+                ..user("user", "new hash")
+            })
+            .await
+            .unwrap();
+        let stored = store.get_user(&loginname).await.unwrap().unwrap();
+        assert_eq!(stored.roles, BTreeSet::from(["admin".to_string()]));
+        assert_eq!(stored.password_hash, "new hash");
+        assert!(stored.disabled);
+        assert_eq!(store.list_users(0, 10).await.unwrap().len(), 1);
+
+        assert!(store.delete_user(&loginname).await.unwrap());
+        assert!(store.get_user(&loginname).await.unwrap().is_none());
+        assert!(!store.delete_user(&loginname).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn upserting_a_user_keeps_the_rows_referencing_it() {
+        let store = store();
+        store.upsert_user(user("user", "hash")).await.unwrap();
+        store
+            .insert_refresh_token(refresh_token("token", Uuid::new_v4()))
+            .await
+            .unwrap();
+
+        store.upsert_user(user("user", "new hash")).await.unwrap();
+
+        assert!(store.get_refresh_token("token").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn deleting_a_refresh_token_family_keeps_the_other_families() {
+        let store = store();
+        store.upsert_user(user("user", "hash")).await.unwrap();
+        let family_id = Uuid::new_v4();
+        let other_family_id = Uuid::new_v4();
+        store
+            .insert_refresh_token(refresh_token("first", family_id))
+            .await
+            .unwrap();
+        store
+            .insert_refresh_token(refresh_token("rotated", family_id))
+            .await
+            .unwrap();
+        store
+            .insert_refresh_token(refresh_token("other", other_family_id))
+            .await
+            .unwrap();
+
+        let used = store.use_refresh_token("first").await.unwrap().unwrap();
+        assert!(!used.used);
+        assert_eq!(used.family_id, family_id);
+        assert!(
+            store
+                .get_refresh_token("first")
+                .await
+                .unwrap()
+                .unwrap()
+                .used
+        );
+
+        store.delete_refresh_token_family(&family_id).await.unwrap();
+
+        assert!(store.get_refresh_token("first").await.unwrap().is_none());
+        assert!(store.get_refresh_token("rotated").await.unwrap().is_none());
+        assert!(store.get_refresh_token("other").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn deleting_a_user_deletes_its_refresh_tokens() {
+        let store = store();
+        store.upsert_user(user("user", "hash")).await.unwrap();
+        store
+            .insert_refresh_token(refresh_token("token", Uuid::new_v4()))
+            .await
+            .unwrap();
+
+        store
+            .delete_user(&LoginName("user".to_string()))
+            .await
+            .unwrap();
+
+        assert!(store.get_refresh_token("token").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,11 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds elapsed since the unix epoch
+pub type UnixTimestamp = u64;
+
+pub fn now() -> UnixTimestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
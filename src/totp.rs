@@ -0,0 +1,212 @@
+//! Time-based one-time passwords (RFC 6238) with the parameters every authenticator app
+//! understands: HMAC-SHA1, 6 digits, 30 second time steps
+
+use ring::hmac;
+
+use crate::{opaque_token, timestamp::UnixTimestamp};
+
+pub const ISSUER: &str = "axum-app-template";
+pub const DIGITS: usize = 6;
+pub const TIME_STEP_SECS: u64 = 30;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Codes of the previous and the next time step are accepted too, to tolerate clock drift
+const ALLOWED_DRIFT_STEPS: u64 = 1;
+const SECRET_LENGTH: usize = 20;
+const RECOVERY_CODE_LENGTH: usize = 10;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Generates a random secret, encoded in base32 (without padding) as authenticator apps expect it
+pub fn generate_secret() -> Result<String, ()> {
+    let mut secret = [0; SECRET_LENGTH];
+    getrandom::getrandom(&mut secret)
+        .inspect_err(|e| log::error!("totp::generate_secret, error = {e}"))
+        .map_err(|_| ())?;
+
+    Ok(base32_encode(&secret))
+}
+
+/// The URI that authenticator apps import, usually shown as a QR code
+pub fn otpauth_uri(account: &str, secret: &str) -> String {
+    let issuer = percent_encode(ISSUER);
+    format!(
+        "otpauth://totp/{issuer}:{}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={TIME_STEP_SECS}",
+        percent_encode(account),
+    )
+}
+
+/// Returns the time step the code belongs to if the code is valid at the given time. Callers
+/// have to reject codes of already used time steps, otherwise a code can be replayed.
+pub fn verify(secret: &str, code: &str, now: UnixTimestamp) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let key = base32_decode(secret)?;
+    let current_step = now / TIME_STEP_SECS;
+
+    (current_step.saturating_sub(ALLOWED_DRIFT_STEPS)..=current_step + ALLOWED_DRIFT_STEPS)
+        .find(|step| opaque_token::constant_time_eq(hotp(&key, *step).as_bytes(), code.as_bytes()))
+}
+
+/// Generates single-use codes that can be used instead of a TOTP code, e.g., if the device of
+/// the user is lost
+pub fn generate_recovery_codes() -> Result<Vec<String>, ()> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0; RECOVERY_CODE_LENGTH];
+            getrandom::getrandom(&mut bytes)
+                .inspect_err(|e| log::error!("totp::generate_recovery_codes, error = {e}"))
+                .map_err(|_| ())?;
+
+            let code = bytes
+                .iter()
+                .map(|byte| BASE32_ALPHABET[(byte & 0x1f) as usize] as char)
+                .collect::<String>();
+            let (head, tail) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+
+            Ok(format!("{head}-{tail}"))
+        })
+        .collect()
+}
+
+/// Recovery codes are only stored hashed, the hash ignores the case and the separator
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect::<String>();
+
+    opaque_token::hash(&normalized)
+}
+
+/// HOTP (RFC 4226) code of the counter
+fn hotp(key: &[u8], counter: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let digest = tag.as_ref();
+
+    // dynamic truncation
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = (u32::from(digest[offset] & 0x7f) << 24)
+        | (u32::from(digest[offset + 1]) << 16)
+        | (u32::from(digest[offset + 2]) << 8)
+        | u32::from(digest[offset + 3]);
+
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS as u32),
+        width = DIGITS
+    )
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    encoded
+}
+
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in encoded.trim_end_matches('=').chars() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|alphabet_char| *alphabet_char as char == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The secret of the RFC 6238 test vectors, "12345678901234567890"
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    const NOW: UnixTimestamp = 1_111_111_109;
+
+    fn code_of_step(step: u64) -> String {
+        hotp(&base32_decode(SECRET).unwrap(), step)
+    }
+
+    #[test]
+    fn codes_match_the_rfc_test_vectors() {
+        // the 8 digit codes of the RFC truncated to 6 digits
+        assert_eq!(code_of_step(59 / TIME_STEP_SECS), "287082");
+        assert_eq!(code_of_step(NOW / TIME_STEP_SECS), "081804");
+    }
+
+    #[test]
+    fn code_of_the_current_step_is_accepted() {
+        let step = NOW / TIME_STEP_SECS;
+
+        assert_eq!(verify(SECRET, &code_of_step(step), NOW), Some(step));
+    }
+
+    #[test]
+    fn codes_of_the_neighbouring_steps_are_accepted() {
+        let step = NOW / TIME_STEP_SECS;
+
+        assert_eq!(verify(SECRET, &code_of_step(step - 1), NOW), Some(step - 1));
+        assert_eq!(verify(SECRET, &code_of_step(step + 1), NOW), Some(step + 1));
+    }
+
+    #[test]
+    fn codes_outside_the_drift_window_are_rejected() {
+        let step = NOW / TIME_STEP_SECS;
+
+        assert_eq!(verify(SECRET, &code_of_step(step - 2), NOW), None);
+        assert_eq!(verify(SECRET, &code_of_step(step + 2), NOW), None);
+    }
+
+    #[test]
+    fn wrong_codes_are_rejected() {
+        let step = NOW / TIME_STEP_SECS;
+        let wrong_code = (0..1_000_000)
+            .map(|code| format!("{code:06}"))
+            .find(|code| (step - 1..=step + 1).all(|neighbour| *code != code_of_step(neighbour)))
+            .unwrap();
+
+        assert_eq!(verify(SECRET, &wrong_code, NOW), None);
+        assert_eq!(verify(SECRET, "12345", NOW), None);
+        assert_eq!(verify(SECRET, "abcdef", NOW), None);
+    }
+}